@@ -1,157 +1,8397 @@
+// With the `no_std` feature only the lexer, parsers and evaluators are
+// built, on `core` and `alloc`; the REPL, timing and all IO need `std`.
+// build.sh checks that core alone as well as building the binary.
+#![cfg_attr(feature = "no_std", no_std)]
+// With no `main`, nothing in the core build is used from inside the crate.
+#![cfg_attr(feature = "no_std", allow(dead_code))]
+
+#[cfg(feature = "no_std")]
+#[macro_use]
+extern crate alloc;
+// The `std::fmt`, `std::cmp`, ... paths below then name the same items
+// in `core`.
+#[cfg(feature = "no_std")]
+extern crate core as std;
+
+#[cfg(feature = "no_std")]
+use alloc::boxed::Box;
+// `alloc` has no hash map; a `BTreeMap` covers everything `HashMap` is used
+// for here.
+#[cfg(feature = "no_std")]
+use alloc::collections::{BTreeMap, BTreeMap as HashMap, BTreeSet, VecDeque};
+#[cfg(feature = "no_std")]
+use alloc::string::{String, ToString};
+#[cfg(feature = "no_std")]
+use alloc::vec::Vec;
+#[cfg(not(feature = "no_std"))]
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+
+// Debug tracing of the lex, parse and eval phases, written to stderr when
+// `RUST_LOG` includes `debug` (or `trace`). Off by default, and compiled out
+// without `std`.
+#[cfg(not(feature = "no_std"))]
+macro_rules! debug_log {
+    ($($arg:tt)*) => {
+        if debug_logging_enabled() {
+            log_event(format_args!($($arg)*));
+        }
+    };
+}
+#[cfg(feature = "no_std")]
+macro_rules! debug_log {
+    ($($arg:tt)*) => {
+        let _ = format_args!($($arg)*);
+    };
+}
+
+// A `Number` remembers the radix it was written in, 10 unless it had a
+// `0x`, `0o` or `0b` prefix.
 #[derive(Debug, Clone, PartialEq)]
 enum Token {
-    Number(i32),
+    Number(i64, u32),
+    Float(f64),
+    Identifier(Symbol),
     Operator(char),
     LeftParen,
     RightParen,
+    Bar,
+    Comma,
+    Assign,
+    Arrow,
 }
 
-#[derive(Debug)]
+// The radix of a `Number` only matters for printing it back, in lowercase.
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 enum AST {
-    Number(i32),
+    Number(i64, u32),
+    Float(f64),
+    Variable(Symbol),
+    UnaryOp {
+        op: char,
+        operand: Box<AST>,
+    },
     BinaryOp {
         op: char,
         left: Box<AST>,
         right: Box<AST>,
+        span: Span,
+    },
+    Call {
+        name: Symbol,
+        args: Vec<AST>,
     },
+    // Parentheses the user wrote, kept only when parsing for the formatter.
+    Grouped(Box<AST>),
 }
 
-fn lex(expr: &str) -> Result<Vec<Token>, String> {
-    let mut tokens = Vec::new();
-    let mut chars = expr.chars().peekable();
+// An interned identifier: an index into a process-wide table of names, so
+// tokens and trees carry a `Copy` id instead of an owned `String`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct Symbol(u32);
 
-    while let Some(&ch) = chars.peek() {
-        match ch {
-            '0'..='9' => {
-                let mut num = 0;
-                while let Some(digit @ '0'..='9') = chars.peek() {
-                    num = num * 10 + digit.to_digit(10).unwrap() as i32;
-                    chars.next();
-                }
-                tokens.push(Token::Number(num));
-            }
-            '+' | '-' | '*' | '/' => {
-                tokens.push(Token::Operator(ch));
-                chars.next();
+// Owns each distinct name once, in `names` at its symbol's index.
+#[derive(Default)]
+struct Interner {
+    symbols: HashMap<String, u32>,
+    names: Vec<String>,
+}
+
+// One table for every thread, so a `Symbol` names the same identifier
+// wherever it is resolved.
+#[cfg(not(feature = "no_std"))]
+static INTERNER: std::sync::LazyLock<std::sync::Mutex<Interner>> =
+    std::sync::LazyLock::new(Default::default);
+
+// Without `std` there is no `Mutex`, so a spin lock guards the table.
+#[cfg(feature = "no_std")]
+static INTERNER: SpinLock<Interner> = SpinLock::new(Interner {
+    symbols: HashMap::new(),
+    names: Vec::new(),
+});
+
+// A minimal lock for `no_std` builds, which may have no OS to block on.
+#[cfg(feature = "no_std")]
+struct SpinLock<T> {
+    locked: std::sync::atomic::AtomicBool,
+    value: std::cell::UnsafeCell<T>,
+}
+
+// Sound because `with` only hands out the value while holding the lock.
+#[cfg(feature = "no_std")]
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+#[cfg(feature = "no_std")]
+impl<T> SpinLock<T> {
+    const fn new(value: T) -> SpinLock<T> {
+        SpinLock {
+            locked: std::sync::atomic::AtomicBool::new(false),
+            value: std::cell::UnsafeCell::new(value),
+        }
+    }
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+        // Unlocks on drop, so a panic in `f` doesn't leave the lock held.
+        struct Unlock<'a>(&'a std::sync::atomic::AtomicBool);
+        impl Drop for Unlock<'_> {
+            fn drop(&mut self) {
+                self.0.store(false, Release);
             }
-            '(' => {
-                tokens.push(Token::LeftParen);
-                chars.next();
+        }
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Acquire, Relaxed)
+            .is_err()
+        {
+            std::hint::spin_loop();
+        }
+        let _unlock = Unlock(&self.locked);
+        f(unsafe { &mut *self.value.get() })
+    }
+}
+
+// Character offsets into the source line; `Span::default()` marks nodes that
+// weren't parsed from source.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+struct Span {
+    start: usize,
+    end: usize,
+}
+
+impl Span {
+    fn at(position: usize) -> Span {
+        Span {
+            start: position,
+            end: position + 1,
+        }
+    }
+}
+
+impl Symbol {
+    fn intern(name: &str) -> Symbol {
+        with_interner(|interner| {
+            if let Some(&index) = interner.symbols.get(name) {
+                return Symbol(index);
             }
-            ')' => {
-                tokens.push(Token::RightParen);
-                chars.next();
+            let index = interner.names.len() as u32;
+            interner.names.push(name.to_string());
+            interner.symbols.insert(name.to_string(), index);
+            Symbol(index)
+        })
+    }
+    // Calls `f` with the name, without copying it; `f` mustn't intern.
+    fn with_str<R>(self, f: impl FnOnce(&str) -> R) -> R {
+        with_interner(|interner| f(&interner.names[self.0 as usize]))
+    }
+    fn name(self) -> String {
+        self.with_str(str::to_string)
+    }
+    fn is(self, text: &str) -> bool {
+        self.with_str(|name| name == text)
+    }
+}
+// A panic while the table was locked can't leave it half-updated, so a
+// poisoned lock is still safe to use.
+#[cfg(not(feature = "no_std"))]
+fn with_interner<R>(f: impl FnOnce(&mut Interner) -> R) -> R {
+    let mut interner = INTERNER.lock().unwrap_or_else(|e| e.into_inner());
+    f(&mut interner)
+}
+#[cfg(feature = "no_std")]
+fn with_interner<R>(f: impl FnOnce(&mut Interner) -> R) -> R {
+    INTERNER.with(f)
+}
+
+impl Rng {
+    fn seeded(seed: u64) -> Rng {
+        Rng {
+            state: std::cell::Cell::new(seed ^ 0x9e37_79b9_7f4a_7c15),
+        }
+    }
+    #[cfg(not(feature = "no_std"))]
+    fn from_time() -> Rng {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        Rng::seeded(nanos)
+    }
+    // Without `std` there is no clock, so every evaluation that isn't given
+    // an `Rng` draws the same sequence.
+    #[cfg(feature = "no_std")]
+    fn from_time() -> Rng {
+        Rng::seeded(0)
+    }
+    fn next_u64(&self) -> u64 {
+        let mut x = self.state.get();
+        if x == 0 {
+            x = 0x9e37_79b9_7f4a_7c15;
+        }
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state.set(x);
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+    // A uniformly drawn integer in `lo..=hi`.
+    fn range(&self, lo: i64, hi: i64) -> Result<i64, CalcError> {
+        if lo > hi {
+            return Err(CalcError::EmptyRange { lo, hi });
+        }
+        let width = (hi as i128 - lo as i128 + 1) as u128;
+        Ok((lo as i128 + (self.next_u64() as u128 % width) as i128) as i64)
+    }
+}
+
+impl Value {
+    fn as_f64(&self) -> f64 {
+        match *self {
+            Value::Int(n) => n as f64,
+            Value::Float(x) => x,
+            Value::Bool(b) => b as i64 as f64,
+            Value::Rational(numerator, denominator) => numerator as f64 / denominator as f64,
+            Value::Big(ref n) => n.to_f64(),
+        }
+    }
+    // Whole floats in range convert, so `2.5 * 2` can be used as an integer;
+    // booleans are 1 or 0.
+    fn as_int(&self) -> Result<i64, CalcError> {
+        match *self {
+            Value::Int(n) => Ok(n),
+            Value::Float(x)
+                if x.fract() == 0.0 && (i64::MIN as f64..i64::MAX as f64).contains(&x) =>
+            {
+                Ok(x as i64)
             }
-            ' ' => {
-                chars.next();
+            Value::Float(x) => Err(CalcError::NotAnInteger(x)),
+            Value::Bool(b) => Ok(b as i64),
+            Value::Rational(..) => Err(CalcError::NotAnInteger(self.as_f64())),
+            Value::Big(_) => Err(CalcError::Overflow),
+        }
+    }
+    // Integers and fractions as `(numerator, denominator)`, widened so a
+    // product of two parts can't overflow.
+    fn as_ratio(&self) -> Option<(i128, i128)> {
+        match *self {
+            Value::Int(n) => Some((n as i128, 1)),
+            Value::Rational(numerator, denominator) => {
+                Some((numerator as i128, denominator as i128))
             }
-            _ => return Err(format!("unknown character: {}", ch)),
+            Value::Float(_) | Value::Bool(_) | Value::Big(_) => None,
+        }
+    }
+    fn as_integer(&self) -> Option<Integer> {
+        match self {
+            Value::Int(n) => Some(Integer::Small(*n)),
+            Value::Big(n) => Some(Integer::Big(n.clone())),
+            _ => None,
+        }
+    }
+    fn from_integer(n: Integer) -> Value {
+        match n {
+            Integer::Small(n) => Value::Int(n),
+            Integer::Big(n) => Value::Big(n),
         }
     }
+}
 
-    Ok(tokens)
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Float(x) => write!(f, "{:?}", x),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Rational(numerator, denominator) => write!(f, "{}/{}", numerator, denominator),
+            Value::Big(n) => write!(f, "{}", n),
+        }
+    }
 }
-fn parse(tokens: &[Token]) -> AST {
-    let mut output_stack: Vec<AST> = Vec::new();
-    let mut operator_stack: Vec<Token> = Vec::new();
 
-    let precedence = |op: char| match op {
-        '+' | '-' => 1,
-        '*' | '/' => 2,
-        _ => 0,
-    };
+impl std::fmt::Display for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.with_str(|name| f.write_str(name))
+    }
+}
 
-    for token in tokens {
-        match token {
-            Token::Number(n) => output_stack.push(AST::Number(*n)),
-            Token::Operator(op) => {
-                while let Some(Token::Operator(top_op)) = operator_stack.last() {
-                    if precedence(*top_op) >= precedence(*op) {
-                        let right = output_stack.pop().unwrap();
-                        let left = output_stack.pop().unwrap();
-                        output_stack.push(AST::BinaryOp {
-                            op: *top_op,
-                            left: Box::new(left),
-                            right: Box::new(right),
-                        });
-                        operator_stack.pop();
-                    } else {
-                        break;
-                    }
-                }
-                operator_stack.push(Token::Operator(*op));
+impl std::fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.with_str(|name| write!(f, "{:?}", name))
+    }
+}
+
+impl AST {
+    fn num(n: i64) -> AST {
+        AST::Number(n, 10)
+    }
+    // A `Big` has no literal to become, so it's reported as the overflow
+    // it would have been in `i64`.
+    fn value(value: Value) -> Result<AST, CalcError> {
+        Ok(match value {
+            Value::Int(n) => AST::num(n),
+            Value::Float(x) => AST::Float(x),
+            Value::Bool(b) => AST::num(b as i64),
+            Value::Rational(numerator, denominator) => AST::BinaryOp {
+                op: '/',
+                left: Box::new(AST::num(numerator)),
+                right: Box::new(AST::num(denominator)),
+                span: Span::default(),
+            },
+            Value::Big(_) => return Err(CalcError::Overflow),
+        })
+    }
+    fn is_literal(&self) -> bool {
+        matches!(self, AST::Number(..) | AST::Float(_))
+    }
+    #[allow(dead_code)]
+    fn var(name: &str) -> AST {
+        AST::Variable(Symbol::intern(name))
+    }
+    fn unary(op: char, operand: AST) -> AST {
+        AST::UnaryOp {
+            op,
+            operand: Box::new(operand),
+        }
+    }
+    #[allow(dead_code)]
+    fn neg(operand: AST) -> AST {
+        AST::unary('-', operand)
+    }
+    #[allow(dead_code)]
+    fn abs(operand: AST) -> AST {
+        AST::unary('|', operand)
+    }
+    #[allow(dead_code)]
+    fn binary(op: char, left: AST, right: AST) -> AST {
+        AST::BinaryOp {
+            op,
+            left: Box::new(left),
+            right: Box::new(right),
+            span: Span::default(),
+        }
+    }
+    #[allow(dead_code)]
+    fn call(name: &str, args: Vec<AST>) -> AST {
+        AST::Call {
+            name: Symbol::intern(name),
+            args,
+        }
+    }
+    #[allow(dead_code)]
+    fn add(left: AST, right: AST) -> AST {
+        AST::binary('+', left, right)
+    }
+    #[allow(dead_code)]
+    fn sub(left: AST, right: AST) -> AST {
+        AST::binary('-', left, right)
+    }
+    #[allow(dead_code)]
+    fn mul(left: AST, right: AST) -> AST {
+        AST::binary('*', left, right)
+    }
+    #[allow(dead_code)]
+    fn div(left: AST, right: AST) -> AST {
+        AST::binary('/', left, right)
+    }
+    #[allow(dead_code)]
+    fn pow(left: AST, right: AST) -> AST {
+        AST::binary('^', left, right)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum CalcError {
+    UnknownCharacter(char, Span),
+    MismatchedBracket {
+        expected: char,
+        found: char,
+        span: Span,
+    },
+    UnknownOperator(char),
+    BudgetExceeded(u64),
+    NegativeExponent(Integer),
+    UndefinedVariable(String),
+    UnexpectedToken(Token),
+    UnexpectedEnd,
+    UnbalancedParens,
+    UnbalancedBars,
+    DivisionByZero(Span),
+    UnknownCommand(String),
+    ReservedName(String),
+    InvalidArgument {
+        command: String,
+        arg: String,
+    },
+    UnknownFunction(String),
+    WrongArgCount {
+        name: String,
+        expected: Arity,
+        found: usize,
+    },
+    Overflow,
+    EmptyRange {
+        lo: i64,
+        hi: i64,
+    },
+    InputTooLong {
+        length: usize,
+        limit: usize,
+    },
+    NotAnInteger(f64),
+    Io(String),
+    NoInverse {
+        value: i64,
+        modulus: i64,
+    },
+    ExceedsWidth {
+        value: i64,
+        width: u32,
+    },
+    TrailingOperator(char),
+    LiteralOverflow(Span),
+    NotFinite(f64),
+    BooleanOperand(String),
+    IncompatibleUnits {
+        left: String,
+        right: String,
+    },
+    UnitsNotAllowed(String),
+    UnterminatedIdentifier(Span),
+    AmbiguousMix {
+        comparison: char,
+        arithmetic: char,
+        span: Span,
+    },
+    InvalidJson(String),
+    InvalidPath(usize),
+    LeadingZero(Span),
+    NothingToUndo,
+    NotAnEquation,
+    NotLinear {
+        var: String,
+        term: String,
+    },
+    NoUniqueSolution(String),
+    InvalidNumeral {
+        numeral: String,
+        span: Span,
+    },
+    UnboundWildcard(String),
+    InvalidEncoding {
+        encoding: Encoding,
+        offset: usize,
+    },
+}
+
+impl CalcError {
+    // Where in the source the error was found, for errors that know.
+    fn span(&self) -> Option<Span> {
+        match self {
+            CalcError::UnknownCharacter(_, span)
+            | CalcError::MismatchedBracket { span, .. }
+            | CalcError::DivisionByZero(span)
+            | CalcError::LiteralOverflow(span)
+            | CalcError::UnterminatedIdentifier(span)
+            | CalcError::AmbiguousMix { span, .. }
+            | CalcError::LeadingZero(span)
+            | CalcError::InvalidNumeral { span, .. } => Some(*span),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Number(n, radix) => write!(f, "{}", format_literal(*n, *radix)),
+            Token::Float(x) => write!(f, "{:?}", x),
+            Token::Identifier(name) => write!(f, "{}", identifier_text(*name)),
+            Token::Operator(op) => write!(f, "{}", operator_text(*op)),
+            Token::LeftParen => write!(f, "("),
+            Token::RightParen => write!(f, ")"),
+            Token::Bar => write!(f, "|"),
+            Token::Comma => write!(f, ","),
+            Token::Assign => write!(f, "="),
+            Token::Arrow => write!(f, "=>"),
+        }
+    }
+}
+
+impl std::fmt::Display for CalcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CalcError::UnknownCharacter(ch, span) => {
+                write!(f, "unknown character: {} at position {}", ch, span.start)
             }
-            Token::LeftParen => operator_stack.push(Token::LeftParen),
-            Token::RightParen => {
-                while let Some(top) = operator_stack.pop() {
-                    if let Token::LeftParen = top {
-                        break;
-                    }
-                    let right = output_stack.pop().unwrap();
-                    let left = output_stack.pop().unwrap();
-                    if let Token::Operator(op) = top {
-                        output_stack.push(AST::BinaryOp {
-                            op,
-                            left: Box::new(left),
-                            right: Box::new(right),
-                        });
-                    }
-                }
+            CalcError::MismatchedBracket {
+                expected,
+                found,
+                span,
+            } => write!(
+                f,
+                "mismatched bracket: expected {}, found {} at position {}",
+                expected, found, span.start
+            ),
+            CalcError::UnknownOperator(op) => write!(f, "unknown operator: {}", op),
+            CalcError::BudgetExceeded(budget) => {
+                write!(f, "evaluation budget of {} operations exceeded", budget)
             }
+            CalcError::NegativeExponent(exponent) => {
+                write!(f, "negative exponent: {}", exponent)
+            }
+            CalcError::UndefinedVariable(name) => write!(f, "undefined variable: {}", name),
+            CalcError::UnexpectedToken(token) => write!(f, "unexpected token: {}", token),
+            CalcError::UnexpectedEnd => write!(f, "unexpected end of expression"),
+            CalcError::UnbalancedParens => write!(f, "unbalanced parentheses"),
+            CalcError::UnbalancedBars => write!(f, "unbalanced absolute value bars"),
+            CalcError::DivisionByZero(span) if *span == Span::default() => {
+                write!(f, "division by zero")
+            }
+            CalcError::DivisionByZero(span) => {
+                write!(f, "division by zero at position {}", span.start)
+            }
+            CalcError::UnknownCommand(command) => write!(f, "unknown command: :{}", command),
+            CalcError::ReservedName(name) => write!(f, "cannot assign to reserved name: {}", name),
+            CalcError::InvalidArgument { command, arg } => {
+                write!(f, "invalid argument for :{}: {}", command, arg)
+            }
+            CalcError::UnknownFunction(name) => write!(f, "unknown function: {}", name),
+            CalcError::WrongArgCount {
+                name,
+                expected,
+                found,
+            } => write!(f, "{} expects {}, got {}", name, expected, found),
+            CalcError::Overflow => write!(f, "integer overflow"),
+            CalcError::EmptyRange { lo, hi } => write!(f, "empty range: {} > {}", lo, hi),
+            CalcError::InputTooLong { length, limit } => {
+                write!(f, "input is {} bytes, limit is {}", length, limit)
+            }
+            CalcError::NotAnInteger(x) => write!(f, "expected an integer, found {:?}", x),
+            CalcError::Io(message) => write!(f, "i/o error: {}", message),
+            CalcError::NotFinite(x) => write!(f, "cannot convert {} to an integer", x),
+            CalcError::LiteralOverflow(span) => {
+                write!(f, "number too large at position {}", span.start)
+            }
+            CalcError::TrailingOperator(op) => write!(
+                f,
+                "incomplete expression: ends with operator '{}'",
+                operator_text(*op)
+            ),
+            CalcError::ExceedsWidth { value, width } => {
+                write!(
+                    f,
+                    "integer overflow: {} does not fit in {} bits",
+                    value, width
+                )
+            }
+            CalcError::NoInverse { value, modulus } => {
+                write!(f, "{} has no inverse modulo {}", value, modulus)
+            }
+            CalcError::BooleanOperand(op) => {
+                write!(f, "boolean operand for {}; convert it with int() first", op)
+            }
+            CalcError::IncompatibleUnits { left, right } => {
+                write!(f, "incompatible units: {} and {}", left, right)
+            }
+            CalcError::UnterminatedIdentifier(span) => {
+                write!(
+                    f,
+                    "unterminated quoted identifier at position {}",
+                    span.start
+                )
+            }
+            CalcError::AmbiguousMix {
+                comparison,
+                arithmetic,
+                span,
+            } => write!(
+                f,
+                "ambiguous mix of '{}' and '{}' at position {}; add parentheses",
+                operator_text(*comparison),
+                operator_text(*arithmetic),
+                span.start
+            ),
+            CalcError::LeadingZero(span) => write!(
+                f,
+                "leading zero at position {}; write 0o for octal",
+                span.start
+            ),
+            CalcError::NothingToUndo => write!(f, "nothing to undo"),
+            CalcError::NotAnEquation => write!(f, "expected an equation: lhs = rhs"),
+            CalcError::NotLinear { var, term } => write!(f, "{} is not linear in {}", term, var),
+            CalcError::NoUniqueSolution(var) => write!(f, "no unique solution for {}", var),
+            CalcError::InvalidNumeral { numeral, .. } => {
+                write!(f, "invalid roman numeral: {}", numeral)
+            }
+            CalcError::InvalidEncoding { encoding, offset } => {
+                write!(f, "byte {} is not valid {}", offset, encoding.name())
+            }
+            CalcError::UnboundWildcard(name) => {
+                write!(f, "wildcard {} is not in the pattern", name)
+            }
+            CalcError::InvalidPath(depth) => write!(f, "path step {} does not exist", depth),
+            CalcError::InvalidJson(message) => write!(f, "invalid JSON: {}", message),
+            CalcError::UnitsNotAllowed(place) => write!(f, "units are not allowed in {}", place),
         }
     }
+}
 
-    while let Some(top) = operator_stack.pop() {
-        let right = output_stack.pop().unwrap();
-        let left = output_stack.pop().unwrap();
-        if let Token::Operator(op) = top {
-            output_stack.push(AST::BinaryOp {
-                op,
-                left: Box::new(left),
-                right: Box::new(right),
-            });
+impl std::fmt::Display for Integer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Integer::Small(n) => write!(f, "{}", n),
+            Integer::Big(n) => write!(f, "{}", n),
         }
     }
+}
 
-    output_stack.pop().unwrap()
+// Peels off nine decimal digits at a time, lowest first.
+impl std::fmt::Display for BigInt {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut digits = self.digits.clone();
+        let mut chunks = Vec::new();
+        while !digits.is_empty() {
+            let (quotient, remainder) = div_small(&digits, 1_000_000_000);
+            chunks.push(remainder);
+            digits = quotient;
+        }
+        if self.negative {
+            f.write_str("-")?;
+        }
+        match chunks.split_last() {
+            None => f.write_str("0"),
+            Some((top, rest)) => {
+                write!(f, "{}", top)?;
+                rest.iter()
+                    .rev()
+                    .try_for_each(|chunk| write!(f, "{:09}", chunk))
+            }
+        }
+    }
 }
-fn evaluate(ast: &AST) -> Result<i32, String> {
-    match ast {
-        AST::Number(n) => Ok(*n),
-        AST::BinaryOp { op, left, right } => {
-            let left_val = evaluate(left).unwrap_or_else(|e| {
-                println!("err> {e}");
-                std::process::exit(1)
-            });
-            let right_val = evaluate(right).unwrap_or_else(|e| {
-                println!("err> {e}");
-                std::process::exit(1)
-            });
-            match op {
-                '+' => Ok(left_val + right_val),
-                '-' => Ok(left_val - right_val),
-                '*' => Ok(left_val * right_val),
-                '/' => Ok(left_val / right_val),
-                _ => Err(String::from("unknown operator")),
+
+impl std::fmt::Display for Quantity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.units.is_empty() {
+            write!(f, "{}", self.value)
+        } else {
+            write!(f, "{} {}", self.value, format_units(&self.units))
+        }
+    }
+}
+
+#[cfg(not(feature = "no_std"))]
+impl std::fmt::Display for Timing {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let per_eval =
+            |total: std::time::Duration| total.as_nanos() / self.iterations.max(1) as u128;
+        write!(
+            f,
+            "tree {} ns, vm {} ns per evaluation ({} runs)",
+            per_eval(self.tree),
+            per_eval(self.vm),
+            self.iterations
+        )
+    }
+}
+
+impl std::fmt::Display for Arity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let plural = |n: usize| if n == 1 { "argument" } else { "arguments" };
+        match *self {
+            Arity::Exact(n) => write!(f, "{} {}", n, plural(n)),
+            Arity::AtLeast(n) => write!(f, "at least {} {}", n, plural(n)),
+            Arity::Range(lo, hi) => write!(f, "{} to {} {}", lo, hi, plural(hi)),
+        }
+    }
+}
+
+// Prints with the minimum parentheses needed to preserve the tree's shape.
+impl std::fmt::Display for AST {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AST::Number(n, radix) => write!(f, "{}", format_literal(*n, *radix)),
+            AST::Float(x) => write!(f, "{:?}", x),
+            AST::Variable(name) => write!(f, "{}", identifier_text(*name)),
+            AST::UnaryOp { op: '|', operand } => write!(f, "|{}|", operand),
+            AST::UnaryOp { op, operand } => {
+                write!(f, "{}", op)?;
+                write_operand(f, operand, UNARY_PRECEDENCE, false)
+            }
+            AST::BinaryOp {
+                op, left, right, ..
+            } => {
+                write_operand(f, left, precedence(*op), is_right_assoc(*op))?;
+                write!(f, " {} ", operator_text(*op))?;
+                write_operand(f, right, precedence(*op), !is_right_assoc(*op))
+            }
+            AST::Call { name, args } => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
             }
+            AST::Grouped(inner) => write!(f, "({})", inner),
         }
     }
 }
-fn main() {
-    let mut expression = String::new();
-    print!("in> ");
-    use std::io::Write;
-    std::io::stdout().flush().unwrap();
-    std::io::stdin().read_line(&mut expression).unwrap();
 
-    let tokens = lex(&expression[..expression.len() - 1]).unwrap_or_else(|e| {
-        println!("err> {e}");
-        std::process::exit(1)
-    });
-    let ast = parse(&tokens);
-    let result = evaluate(&ast).unwrap_or_else(|e| {
-        println!("err> {e}");
-        std::process::exit(1)
-    });
-    println!("out> {}", result);
+// `budget` caps the number of arithmetic operations a single evaluation may
+// perform, for callers evaluating untrusted input. With `strict_bools`,
+// comparisons produce `Value::Bool`, which arithmetic rejects until it is
+// converted with `int(b)`; otherwise they produce 1 or 0. A `modulus`, which
+// must be prime, reduces every integer into `0..modulus`: literals, variable
+// reads and function results as well as operations, but not exponents or the
+// operands of `%`. `width` emulates narrower machine integers, 8 to 32 bits;
+// without one, a checked result past `i64` becomes a `Value::Big` instead of
+// an overflow. `float_warnings` flags float operations that lose precision,
+// or produce NaN or infinity, without failing them.
+// With `rationals`, an integer division that would truncate gives an exact
+// fraction instead: `1 + 1/3` is `4/3`, while `4/2` is still 2. With
+// `undefined_as_zero`, an unbound variable reads as 0 with a warning, for
+// filling in templates only partly.
+#[derive(Debug, Clone, Copy, Default)]
+struct EvalOptions {
+    budget: Option<u64>,
+    strict_bools: bool,
+    modulus: Option<i64>,
+    width: Option<u32>,
+    overflow: OverflowPolicy,
+    float_warnings: bool,
+    rationals: bool,
+    undefined_as_zero: bool,
+}
+
+// What an integer operation does when its result doesn't fit: report
+// `CalcError::Overflow`, wrap around in two's complement, or clamp to the
+// nearest representable value.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+enum OverflowPolicy {
+    #[default]
+    Checked,
+    Wrapping,
+    Saturating,
+}
+
+// Integer arithmetic stays in `i64` until a checked result doesn't fit,
+// and then moves to a `Big`, which never holds a value an `Int` could; an
+// operation with a float operand is carried out in `f64`. A `Rational` is a
+// fraction in lowest terms with a denominator above 1, produced only under
+// `EvalOptions::rationals`.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Rational(i64, i64),
+    Big(BigInt),
+}
+
+type Env = HashMap<Symbol, Value>;
+
+// Which kind of `Value` an expression produces, as `result_kind` predicts it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ResultKind {
+    Int,
+    Float,
+    Bool,
+}
+
+// One instruction for the stack machine in `run_vm`. `compile` emits them in
+// postfix order; the jumps take absolute indexes into the code.
+#[derive(Debug, Clone, PartialEq)]
+enum Op {
+    Push(Value),
+    Load(Symbol),
+    Unary(char),
+    Binary(char, Span),
+    Call(Symbol, usize),
+    // Pops a value and jumps if it's zero.
+    JumpIfZero(usize),
+    Jump(usize),
+}
+
+// One step from a node to one of its children, for addressing a subtree:
+// in `1 + 2 * 3`, `[Right, Left]` is the `2`.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PathStep {
+    Left,
+    Right,
+    Operand,
+    Arg(usize),
+    Inner,
+}
+
+// A product of base units and their (non-zero) powers: `m/s^2` is
+// `{m: 1, s: -2}`. Empty means dimensionless.
+type Units = BTreeMap<Symbol, i64>;
+
+// A number tagged with units, as computed by `evaluate_units`.
+#[derive(Debug, Clone, PartialEq)]
+struct Quantity {
+    value: f64,
+    units: Units,
+}
+
+// With `decimal_comma`, `3,5` is three and a half and function arguments are
+// separated by `;` instead. With `reject_leading_zero`, a decimal literal
+// such as `010` is an error rather than ten, since C would read it as octal;
+// `0o10` says that explicitly. `unknown` picks what a character the lexer
+// doesn't recognize turns into, and `roman` whether names like `XII` are
+// numbers.
+#[derive(Debug, Clone, Copy, Default)]
+struct LexOptions {
+    decimal_comma: bool,
+    reject_leading_zero: bool,
+    unknown: UnknownChars,
+    roman: RomanNumerals,
+}
+
+// Under `Lenient`, any run of `IVXLCDM` the subtractive rule can read is a
+// number, so `IIII` is 4 but `VX` is an error; under `Strict`, only the
+// standard spelling of a number from 1 to 3999 is accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+enum RomanNumerals {
+    #[default]
+    Off,
+    Lenient,
+    Strict,
+}
+
+// `Fail` reports an unknown character as `CalcError::UnknownCharacter`,
+// `Skip` drops it, and `Replace(c)` lexes it as if `c` had been written, so
+// with `Replace('*')`, `2 @ 3` is `2 * 3`. A replacement that is itself
+// unknown is an error.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+enum UnknownChars {
+    #[default]
+    Fail,
+    Skip,
+    Replace(char),
+}
+
+// With `keep_groups`, every explicit `( )` becomes an `AST::Grouped` node.
+// With `right_assoc_sub`, chains of `-` and `/` group to the right like `^`
+// does, so `10 - 3 - 2` is `10 - (3 - 2)`. With `reject_mixed`, a comparison
+// whose operand is unparenthesized arithmetic is an error: `1 + 2 < 3` must
+// be written `(1 + 2) < 3`.
+#[derive(Debug, Clone, Copy, Default)]
+struct ParseOptions {
+    keep_groups: bool,
+    right_assoc_sub: bool,
+    reject_mixed: bool,
+}
+
+// Entries waiting on the shunting-yard operator stack. A call remembers how
+// many operands were already on the output stack when it opened.
+#[derive(Debug, Clone, Copy)]
+enum Pending {
+    Binary(char, Span),
+    Unary(char),
+    Paren,
+    Abs,
+    Call(Symbol, usize),
+}
+
+// Narration of the shunting-yard parser's decisions, collected by
+// `explain_parse`. Off for ordinary parsing, where `step` does nothing.
+struct ParseTrace(Option<Vec<String>>);
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+// Every operator and punctuation spelling the lexer knows, each at most two
+// chars; it always takes the longest spelling that matches, so `<=` is one
+// token and `< =` two. When an operator has several spellings, the first is
+// the one printed. Two-char comparisons are stored as a single char: `==` is
+// `=`, and `<=`, `>=` and `!=` are `≤`, `≥` and `≠`.
+const SYMBOLS: &[(&str, Token)] = &[
+    ("+", Token::Operator('+')),
+    ("-", Token::Operator('-')),
+    ("*", Token::Operator('*')),
+    ("/", Token::Operator('/')),
+    ("%", Token::Operator('%')),
+    ("^", Token::Operator('^')),
+    ("**", Token::Operator('^')),
+    ("×", Token::Operator('*')),
+    ("÷", Token::Operator('/')),
+    ("<", Token::Operator('<')),
+    ("<=", Token::Operator('≤')),
+    (">", Token::Operator('>')),
+    (">=", Token::Operator('≥')),
+    ("==", Token::Operator('=')),
+    ("!=", Token::Operator('≠')),
+    ("=", Token::Assign),
+    ("=>", Token::Arrow),
+    ("|", Token::Bar),
+];
+
+// How many arguments a built-in function takes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Arity {
+    Exact(usize),
+    AtLeast(usize),
+    Range(usize, usize),
+}
+
+// Every built-in function and its arity, checked before a call is dispatched.
+const BUILTINS: &[(&str, Arity)] = &[
+    ("abs", Arity::Exact(1)),
+    ("float", Arity::Exact(1)),
+    ("int", Arity::Exact(1)),
+    ("mod", Arity::Exact(2)),
+    ("gcd", Arity::Exact(2)),
+    ("lcm", Arity::Exact(2)),
+    ("max", Arity::AtLeast(1)),
+    ("min", Arity::AtLeast(1)),
+    ("sum", Arity::AtLeast(0)),
+    ("prod", Arity::AtLeast(0)),
+    ("rand", Arity::Range(1, 2)),
+    ("pct", Arity::Exact(2)),
+    ("change", Arity::Exact(2)),
+    ("if", Arity::Exact(3)),
+];
+
+// How many times `:bench` evaluates its expression.
+const BENCH_ITERATIONS: u32 = 100_000;
+
+// How many assignments `:undo` can step back through.
+const UNDO_LIMIT: usize = 100;
+
+// Inputs longer than this many bytes are rejected before lexing.
+// `BASIC_ASTS_MAX_INPUT` overrides it for the REPL.
+const DEFAULT_MAX_INPUT_LEN: usize = 64 * 1024;
+
+#[cfg(not(feature = "no_std"))]
+#[derive(Debug)]
+struct Repl {
+    env: Env,
+    results: usize,
+    step_mode: bool,
+    explicit_sign: bool,
+    base: u32,
+    negative_format: NegativeFormat,
+    rng: Rng,
+    // The latest `:seed`, or `None` while `rand` is seeded from the clock.
+    seed: Option<u64>,
+    max_input_len: usize,
+    lex_options: LexOptions,
+    parse_options: ParseOptions,
+    recording: bool,
+    transcript: Vec<(String, String)>,
+    eval_options: EvalOptions,
+    bool_words: bool,
+    // Spell integer results out in English: `forty-two`.
+    words: bool,
+    // Integers at least this large in magnitude print in scientific
+    // notation, set with `:intsci`.
+    int_sci: Option<u64>,
+    // Decimal places for floats, or `None` for the shortest exact form.
+    precision: Option<usize>,
+    trim_zeros: bool,
+    prompts: Prompts,
+    // One entry per assignment or `:swap`, holding each changed variable's
+    // previous value (`None` if it was new), most recent last.
+    undo: Vec<Vec<(Symbol, Option<Value>)>>,
+    // Warnings from the latest input, for the caller to print.
+    warnings: Vec<String>,
+    // The latest input's reductions under `:step`, taken by `submit`.
+    steps: Vec<String>,
+    // Lines of an incomplete input, joined with spaces, waiting for the rest.
+    pending: String,
+}
+
+// What one line fed to the REPL produced. `Incomplete` means the line was
+// kept and the next one continues it; `Empty` is a blank line. Under
+// `:step`, `Steps` holds each intermediate expression and the result.
+#[cfg(not(feature = "no_std"))]
+#[derive(Debug, Clone, PartialEq)]
+enum ReplOutput {
+    Result(String),
+    Steps(Vec<String>, String),
+    Error(CalcError),
+    Command(String),
+    Incomplete,
+    Empty,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NegativeFormat {
+    Signed,
+    Bits,
+}
+
+#[cfg(not(feature = "no_std"))]
+impl Default for Repl {
+    fn default() -> Repl {
+        Repl {
+            env: Env::new(),
+            results: 0,
+            step_mode: false,
+            explicit_sign: false,
+            base: 10,
+            negative_format: NegativeFormat::Signed,
+            rng: Rng::from_time(),
+            seed: None,
+            max_input_len: DEFAULT_MAX_INPUT_LEN,
+            lex_options: LexOptions::default(),
+            parse_options: ParseOptions::default(),
+            recording: false,
+            transcript: Vec::new(),
+            eval_options: EvalOptions::default(),
+            bool_words: false,
+            words: false,
+            int_sci: None,
+            precision: None,
+            trim_zeros: false,
+            prompts: Prompts::from_lookup(|_| None),
+            undo: Vec::new(),
+            warnings: Vec::new(),
+            steps: Vec::new(),
+            pending: String::new(),
+        }
+    }
+}
+
+// Prefixes the REPL prints before input, continuation lines, results,
+// errors, warnings and `:step` reductions. Each can be overridden (or
+// blanked, for scripting) with `BASIC_ASTS_PROMPT`, `BASIC_ASTS_CONT_PROMPT`,
+// `BASIC_ASTS_OUT_PROMPT`, `BASIC_ASTS_ERR_PROMPT`, `BASIC_ASTS_WARN_PROMPT`
+// and `BASIC_ASTS_STEP_PROMPT`.
+#[cfg(not(feature = "no_std"))]
+#[derive(Debug, Clone, PartialEq)]
+struct Prompts {
+    input: String,
+    continuation: String,
+    output: String,
+    error: String,
+    warning: String,
+    step: String,
+}
+
+// How `run_bytes` turns bytes into text. Every byte is a valid Latin-1
+// character, so only `Utf8` and `Ascii` can reject input.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Utf8,
+    Latin1,
+    Ascii,
+}
+
+// Where `main` takes its input from. `BASIC_ASTS_EXPR` wins over
+// command-line arguments, which win over the interactive REPL on stdin.
+// Each argument that isn't a `--` flag is an expression of its own.
+// Arguments that fit none of the forms in `USAGE` are a `Usage` error,
+// never a silent fall back to the REPL.
+#[cfg(not(feature = "no_std"))]
+#[derive(Debug, PartialEq)]
+enum InputSource {
+    Expressions(Vec<String>),
+    Explain(String),
+    JsonAst(String),
+    Csv,
+    Repl,
+    Usage(String),
+}
+
+#[cfg(not(feature = "no_std"))]
+const USAGE: &str = "usage: ast_rs [EXPR...] | --explain EXPR | --json-ast EXPR | --csv";
+
+// How long `iterations` evaluations of one expression took on the tree
+// walker and on the bytecode VM.
+#[cfg(not(feature = "no_std"))]
+#[derive(Debug, Clone, Copy)]
+struct Timing {
+    iterations: u32,
+    tree: std::time::Duration,
+    vm: std::time::Duration,
+}
+
+struct Evaluator<'a> {
+    env: &'a Env,
+    options: &'a EvalOptions,
+    rng: &'a Rng,
+    // Integer functions to call through; `None` calls the built-ins directly.
+    functions: Option<&'a FunctionRegistry>,
+    // `options.modulus`, cleared while evaluating an operand that must stay
+    // a plain integer.
+    modulus: Option<i64>,
+    steps: u64,
+    warnings: Vec<String>,
+}
+
+type Function = Box<dyn Fn(&[i64]) -> Result<i64, CalcError>>;
+
+// Integer functions callable from expressions, so embedders can expose their
+// own. The default one holds the integer built-ins, each checked against its
+// arity; registered functions check their own argument counts.
+struct FunctionRegistry {
+    functions: HashMap<Symbol, Function>,
+}
+
+// A bottom-up pass over an `AST`: `fold` calls one method per node, handing
+// it the already-folded results of the node's children.
+trait Fold {
+    type Output;
+    fn number(&mut self, n: i64, radix: u32) -> Self::Output;
+    fn float(&mut self, x: f64) -> Self::Output;
+    fn variable(&mut self, name: Symbol) -> Self::Output;
+    fn unary(&mut self, op: char, operand: Self::Output) -> Self::Output;
+    fn binary(
+        &mut self,
+        op: char,
+        left: Self::Output,
+        right: Self::Output,
+        span: Span,
+    ) -> Self::Output;
+    fn call(&mut self, name: Symbol, args: Vec<Self::Output>) -> Self::Output;
+}
+
+struct ConstantFolder<'a> {
+    env: &'a Env,
+}
+
+// The normalization pass behind `simplify`.
+struct Simplifier;
+
+// A rewrite for `apply_rules`: a tree matching `pattern` becomes
+// `replacement`. In the pattern, `?a` matches any subtree and `?#a` only a
+// number; a wildcard used twice must match equal subtrees both times.
+#[derive(Debug, Clone)]
+struct Rule {
+    pattern: AST,
+    replacement: AST,
+}
+
+// 64-bit FNV-1a, whose output depends only on the bytes written, unlike
+// the per-process keys of `RandomState`.
+struct Fnv1a(u64);
+
+// An exact integer, as `integer_binary` computes it: an `i64` while the
+// value fits, and a `BigInt` only once it doesn't.
+#[derive(Debug, Clone, PartialEq)]
+enum Integer {
+    Small(i64),
+    Big(BigInt),
+}
+
+// Sign and magnitude, the magnitude in base 2^32 with the least significant
+// digit first and no leading zero digits; zero has no digits and is never
+// negative.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BigInt {
+    negative: bool,
+    digits: Vec<u32>,
+}
+
+// xorshift64* generator behind `rand(lo, hi)`; the same seed always gives
+// the same sequence. The state is a `Cell` so evaluation can draw from a
+// shared reference.
+#[derive(Debug, Clone)]
+struct Rng {
+    state: std::cell::Cell<u64>,
+}
+
+// Lookahead over the lexer's input: `peek` and `peek2` look one and two
+// chars ahead, `bump` consumes one, and `eat` and `eat_if` consume one only
+// if it matches. `pos` is the index of the next char, or the input's length
+// once it runs out.
+struct CharCursor<I: Iterator<Item = char>> {
+    chars: I,
+    // Chars pulled from `chars` but not consumed yet; at most two.
+    ahead: VecDeque<char>,
+    pos: usize,
+}
+
+// `[ ]` and `{ }` group like `( )` and lex to the same tokens, but each
+// closer must match the kind of its opener, so `[1+2)` is rejected.
+fn lex(expr: &str) -> Result<Vec<Token>, CalcError> {
+    Ok(lex_spanned(expr)?
+        .into_iter()
+        .map(|(token, _)| token)
+        .collect())
+}
+fn lex_spanned(expr: &str) -> Result<Vec<(Token, Span)>, CalcError> {
+    lex_spanned_with(expr, &LexOptions::default())
+}
+fn lex_spanned_with(expr: &str, options: &LexOptions) -> Result<Vec<(Token, Span)>, CalcError> {
+    let mut errors = Vec::new();
+    let tokens = scan(expr.chars(), options, &mut errors);
+    match errors.into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(tokens),
+    }
+}
+// Lexes past bad characters, skipping them, so every problem in the input can
+// be reported at once. `errors` is empty for valid input, and otherwise sorted
+// by position with at most one error of each kind per position.
+fn lex_all(expr: &str) -> (Vec<Token>, Vec<CalcError>) {
+    let mut errors = Vec::new();
+    let tokens = scan(expr.chars(), &LexOptions::default(), &mut errors)
+        .into_iter()
+        .map(|(token, _)| token)
+        .collect();
+    errors.sort_by_key(|error| error.span().map(|span| span.start));
+    errors.dedup_by(|a, b| {
+        a.span() == b.span() && std::mem::discriminant(a) == std::mem::discriminant(b)
+    });
+    (tokens, errors)
+}
+// Lexes a stream of chars as it arrives, without collecting it into a string
+// first.
+#[allow(dead_code)]
+fn lex_chars<I: Iterator<Item = char>>(chars: I) -> Result<Vec<Token>, CalcError> {
+    let mut errors = Vec::new();
+    let tokens = scan(chars, &LexOptions::default(), &mut errors);
+    match errors.into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(tokens.into_iter().map(|(token, _)| token).collect()),
+    }
+}
+fn scan<I: Iterator<Item = char>>(
+    input: I,
+    options: &LexOptions,
+    errors: &mut Vec<CalcError>,
+) -> Vec<(Token, Span)> {
+    let mut tokens: Vec<(Token, Span)> = Vec::new();
+    let mut chars = CharCursor::new(input);
+    let mut open_brackets = Vec::new();
+    let (decimal_point, separator) = if options.decimal_comma {
+        (',', ';')
+    } else {
+        ('.', ',')
+    };
+
+    while let Some(ch) = chars.peek() {
+        let start = chars.pos();
+        let token = match ch {
+            '0'..='9' => {
+                let mut num = Some(0i64);
+                let mut literal = String::new();
+                while let Some(digit) = chars.eat_if(|c| c.is_ascii_digit()) {
+                    let digit_value = digit.to_digit(10).unwrap() as i64;
+                    num = num.and_then(|n| n.checked_mul(10)?.checked_add(digit_value));
+                    literal.push(digit);
+                }
+                if options.reject_leading_zero && literal.len() > 1 && literal.starts_with('0') {
+                    errors.push(CalcError::LeadingZero(Span {
+                        start,
+                        end: chars.pos(),
+                    }));
+                }
+                let mut value = match chars.peek() {
+                    Some(prefix @ ('x' | 'o' | 'b')) if literal == "0" => {
+                        let at = chars.pos();
+                        chars.bump();
+                        let radix = match prefix {
+                            'x' => 16,
+                            'o' => 8,
+                            _ => 2,
+                        };
+                        let mut digits = Some(0u64);
+                        let mut any = false;
+                        while let Some(c) = chars.eat_if(|c| c.is_digit(radix)) {
+                            let digit = c.to_digit(radix).unwrap();
+                            digits = digits.and_then(|n| {
+                                n.checked_mul(radix as u64)?.checked_add(digit as u64)
+                            });
+                            any = true;
+                        }
+                        if !any {
+                            errors.push(CalcError::UnknownCharacter(prefix, Span::at(at)));
+                            continue;
+                        }
+                        // Up to 64 bits are accepted, so `0xffffffffffffffff`
+                        // is the bit pattern of -1.
+                        digits.map(|n| Token::Number(n as i64, radix))
+                    }
+                    // A separator with no digits after it is a stray
+                    // character, left to be reported on its own.
+                    Some(c) if c == decimal_point && matches!(chars.peek2(), Some('0'..='9')) => {
+                        chars.bump();
+                        literal.push('.');
+                        while let Some(digit) = chars.eat_if(|c| c.is_ascii_digit()) {
+                            literal.push(digit);
+                        }
+                        Some(Token::Float(literal.parse().unwrap()))
+                    }
+                    _ => num.map(|n| Token::Number(n, 10)),
+                };
+                let name_follows = chars
+                    .peek2()
+                    .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_');
+                if let Some(suffix) =
+                    chars.eat_if(|c| magnitude_suffix(c).is_some() && !name_follows)
+                {
+                    let multiplier = magnitude_suffix(suffix).unwrap();
+                    value = match value {
+                        Some(Token::Number(n, radix)) => {
+                            n.checked_mul(multiplier).map(|n| Token::Number(n, radix))
+                        }
+                        Some(Token::Float(x)) => Some(Token::Float(x * multiplier as f64)),
+                        other => other,
+                    };
+                }
+                match value {
+                    Some(token) => token,
+                    None => {
+                        errors.push(CalcError::LiteralOverflow(Span {
+                            start,
+                            end: chars.pos(),
+                        }));
+                        continue;
+                    }
+                }
+            }
+            'a'..='z' | 'A'..='Z' | '_' => {
+                let mut name = String::new();
+                while let Some(c) = chars.eat_if(|c| c.is_ascii_alphanumeric() || c == '_') {
+                    name.push(c);
+                }
+                let span = Span {
+                    start,
+                    end: chars.pos(),
+                };
+                match options.roman {
+                    RomanNumerals::Off => Token::Identifier(Symbol::intern(&name)),
+                    _ if !name.chars().all(|c| roman_digit(c).is_some()) => {
+                        Token::Identifier(Symbol::intern(&name))
+                    }
+                    roman => match parse_roman(&name) {
+                        Some(n)
+                            if roman == RomanNumerals::Lenient
+                                || to_roman(n).as_deref() == Some(name.as_str()) =>
+                        {
+                            Token::Number(n, 10)
+                        }
+                        _ => {
+                            errors.push(CalcError::InvalidNumeral {
+                                numeral: name,
+                                span,
+                            });
+                            continue;
+                        }
+                    },
+                }
+            }
+            // Superscript digits straight after a number are an exponent:
+            // `2¹⁰` lexes as `2 ^ 10`.
+            _ if superscript_digit(ch).is_some() => {
+                let after_number = matches!(
+                    tokens.last(),
+                    Some((Token::Number(..) | Token::Float(_), span)) if span.end == start
+                );
+                if !after_number {
+                    chars.bump();
+                    errors.push(CalcError::UnknownCharacter(ch, Span::at(start)));
+                    continue;
+                }
+                let mut exponent = Some(0i64);
+                while let Some(c) = chars.eat_if(|c| superscript_digit(c).is_some()) {
+                    let digit = superscript_digit(c).unwrap() as i64;
+                    exponent = exponent.and_then(|n| n.checked_mul(10)?.checked_add(digit));
+                }
+                let Some(exponent) = exponent else {
+                    errors.push(CalcError::LiteralOverflow(Span {
+                        start,
+                        end: chars.pos(),
+                    }));
+                    continue;
+                };
+                tokens.push((Token::Operator('^'), Span::at(start)));
+                Token::Number(exponent, 10)
+            }
+            // A backtick-quoted name may hold anything but a backtick:
+            // `` `my var` `` is the variable `my var`.
+            '`' => {
+                chars.bump();
+                let mut name = String::new();
+                while let Some(c) = chars.eat_if(|c| c != '`') {
+                    name.push(c);
+                }
+                if !chars.eat('`') {
+                    errors.push(CalcError::UnterminatedIdentifier(Span {
+                        start,
+                        end: chars.pos(),
+                    }));
+                    continue;
+                }
+                Token::Identifier(Symbol::intern(&name))
+            }
+            _ if SYMBOLS.iter().any(|(text, _)| text.starts_with(ch)) => {
+                chars.bump();
+                let mut spelling = ch.to_string();
+                if let Some(next) = chars.peek() {
+                    spelling.push(next);
+                    if lookup_symbol(&spelling).is_some() {
+                        chars.bump();
+                    } else {
+                        spelling.pop();
+                    }
+                }
+                match lookup_symbol(&spelling).or_else(|| recover(ch, start, options, errors)) {
+                    Some(token) => token,
+                    None => continue,
+                }
+            }
+            _ if ch == separator => {
+                chars.bump();
+                Token::Comma
+            }
+            '(' | '[' | '{' => {
+                open_brackets.push(ch);
+                chars.bump();
+                Token::LeftParen
+            }
+            ')' | ']' | '}' => {
+                chars.bump();
+                let opener = open_brackets.pop();
+                let expected = match opener {
+                    Some('[') => ']',
+                    Some('{') => '}',
+                    _ => ')',
+                };
+                if ch != expected {
+                    open_brackets.extend(opener);
+                    errors.push(CalcError::MismatchedBracket {
+                        expected,
+                        found: ch,
+                        span: Span::at(start),
+                    });
+                    continue;
+                }
+                Token::RightParen
+            }
+            ' ' => {
+                chars.bump();
+                continue;
+            }
+            _ => {
+                chars.bump();
+                match recover(ch, start, options, errors) {
+                    Some(token) => token,
+                    None => continue,
+                }
+            }
+        };
+        debug_log!("lexed {:?}", token);
+        tokens.push((
+            token,
+            Span {
+                start,
+                end: chars.pos(),
+            },
+        ));
+    }
+
+    tokens
+}
+// The token an unknown `ch` stands for under `options.unknown`, if any.
+fn recover(
+    ch: char,
+    start: usize,
+    options: &LexOptions,
+    errors: &mut Vec<CalcError>,
+) -> Option<Token> {
+    match options.unknown {
+        UnknownChars::Fail => {}
+        UnknownChars::Skip => return None,
+        UnknownChars::Replace(replacement) => {
+            if let Some(token) = lookup_symbol(&replacement.to_string()) {
+                return Some(token);
+            }
+        }
+    }
+    errors.push(CalcError::UnknownCharacter(ch, Span::at(start)));
+    None
+}
+impl<I: Iterator<Item = char>> CharCursor<I> {
+    fn new(chars: I) -> Self {
+        CharCursor {
+            chars,
+            ahead: VecDeque::new(),
+            pos: 0,
+        }
+    }
+    fn peek(&mut self) -> Option<char> {
+        self.look(0)
+    }
+    fn peek2(&mut self) -> Option<char> {
+        self.look(1)
+    }
+    fn look(&mut self, offset: usize) -> Option<char> {
+        while self.ahead.len() <= offset {
+            self.ahead.push_back(self.chars.next()?);
+        }
+        Some(self.ahead[offset])
+    }
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.ahead.pop_front();
+        self.pos += 1;
+        Some(c)
+    }
+    fn eat(&mut self, expected: char) -> bool {
+        self.eat_if(|c| c == expected).is_some()
+    }
+    fn eat_if(&mut self, accept: impl FnOnce(char) -> bool) -> Option<char> {
+        match self.peek() {
+            Some(c) if accept(c) => self.bump(),
+            _ => None,
+        }
+    }
+    fn pos(&self) -> usize {
+        self.pos
+    }
+}
+fn roman_digit(c: char) -> Option<i64> {
+    match c {
+        'I' => Some(1),
+        'V' => Some(5),
+        'X' => Some(10),
+        'L' => Some(50),
+        'C' => Some(100),
+        'D' => Some(500),
+        'M' => Some(1000),
+        _ => None,
+    }
+}
+// A digit smaller than the one after it is subtracted: `IX` is 9. Only
+// `I`, `X` and `C` are ever subtracted, and only from the next two larger
+// digits, so `None` means a numeral like `VX` or `IC`, or (after
+// quadrillions of `M`s) a total that overflows.
+fn parse_roman(numeral: &str) -> Option<i64> {
+    let digits: Vec<i64> = numeral.chars().filter_map(roman_digit).collect();
+    digits
+        .iter()
+        .enumerate()
+        .try_fold(0i64, |total, (i, &digit)| match digits.get(i + 1) {
+            Some(&next) if next > digit => match digit {
+                1 | 10 | 100 if next <= digit * 10 => total.checked_sub(digit),
+                _ => None,
+            },
+            _ => total.checked_add(digit),
+        })
+}
+// The standard spelling, for 1 to 3999 only.
+fn to_roman(n: i64) -> Option<String> {
+    const NUMERALS: [(i64, &str); 13] = [
+        (1000, "M"),
+        (900, "CM"),
+        (500, "D"),
+        (400, "CD"),
+        (100, "C"),
+        (90, "XC"),
+        (50, "L"),
+        (40, "XL"),
+        (10, "X"),
+        (9, "IX"),
+        (5, "V"),
+        (4, "IV"),
+        (1, "I"),
+    ];
+    if !(1..=3999).contains(&n) {
+        return None;
+    }
+    let mut rest = n;
+    let mut numeral = String::new();
+    for &(value, text) in &NUMERALS {
+        while rest >= value {
+            numeral.push_str(text);
+            rest -= value;
+        }
+    }
+    Some(numeral)
+}
+// `3k` is 3000, `2M` 2000000 and `1G` 1000000000. A suffix only counts
+// straight after a number's digits, and not when more of a name follows it:
+// `5kg` is 5 and then `kg`. `k` and `K` are both kilo, but mega and giga
+// are upper case only.
+fn magnitude_suffix(c: char) -> Option<i64> {
+    match c {
+        'k' | 'K' => Some(1_000),
+        'M' => Some(1_000_000),
+        'G' => Some(1_000_000_000),
+        _ => None,
+    }
+}
+fn superscript_digit(c: char) -> Option<u32> {
+    match c {
+        '⁰' => Some(0),
+        '¹' => Some(1),
+        '²' => Some(2),
+        '³' => Some(3),
+        '⁴'..='⁹' => Some(c as u32 - '⁰' as u32),
+        _ => None,
+    }
+}
+// Decimal literals print as they are; others keep their prefix, with a sign
+// rather than a bit pattern when negative, so they lex back to the same value.
+// Only the radix is kept, not the spelling: hex digits come out lowercase and
+// leading zeros are dropped, so `0xFF` and `0x00ff` both print as `0xff`.
+fn format_literal(n: i64, radix: u32) -> String {
+    format_radix(n, radix, NegativeFormat::Signed)
+}
+// The spelling of a variable name that lexes back to it, quoting names that
+// aren't plain identifiers in backticks.
+fn identifier_text(name: Symbol) -> String {
+    let name = name.name();
+    let mut chars = name.chars();
+    let plain = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if plain {
+        name.to_string()
+    } else {
+        format!("`{}`", name)
+    }
+}
+fn lookup_symbol(spelling: &str) -> Option<Token> {
+    SYMBOLS
+        .iter()
+        .find(|(text, _)| *text == spelling)
+        .map(|(_, token)| token.clone())
+}
+fn precedence(op: char) -> u8 {
+    match op {
+        '=' | '≠' | '<' | '≤' | '>' | '≥' => 1,
+        '+' | '-' => 2,
+        '*' | '/' | '%' => 3,
+        '^' => 5,
+        _ => 0,
+    }
+}
+fn is_comparison(op: char) -> bool {
+    precedence(op) == 1
+}
+fn operator_text(op: char) -> String {
+    SYMBOLS
+        .iter()
+        .find(|(_, token)| *token == Token::Operator(op))
+        .map_or_else(|| op.to_string(), |(text, _)| text.to_string())
+}
+// Prefix `-` binds tighter than `*` but looser than `^`, so `-2^2` is `-4`.
+const UNARY_PRECEDENCE: u8 = 4;
+fn is_right_assoc(op: char) -> bool {
+    op == '^'
+}
+fn binds_before(top_precedence: u8, op: char, options: &ParseOptions) -> bool {
+    let right_assoc = is_right_assoc(op) || (options.right_assoc_sub && matches!(op, '-' | '/'));
+    top_precedence > precedence(op) || (top_precedence == precedence(op) && !right_assoc)
+}
+fn apply_pending(
+    output_stack: &mut Vec<AST>,
+    pending: Pending,
+    trace: &mut ParseTrace,
+) -> Result<(), CalcError> {
+    let right = output_stack.pop().ok_or(CalcError::UnexpectedEnd)?;
+    let node = match pending {
+        Pending::Binary(op, span) => {
+            let left = output_stack.pop().ok_or(CalcError::UnexpectedEnd)?;
+            debug_log!("built BinaryOp({})", op);
+            AST::BinaryOp {
+                op,
+                left: Box::new(left),
+                right: Box::new(right),
+                span,
+            }
+        }
+        Pending::Unary(op) => {
+            debug_log!("built UnaryOp({})", op);
+            AST::unary(op, right)
+        }
+        Pending::Abs => {
+            debug_log!("built UnaryOp(|)");
+            AST::unary('|', right)
+        }
+        Pending::Paren | Pending::Call(..) => return Err(CalcError::UnbalancedParens),
+    };
+    trace.step(|| format!("pop {}: built {}", pending_text(pending), node));
+    output_stack.push(node);
+    Ok(())
+}
+fn pending_text(pending: Pending) -> String {
+    match pending {
+        Pending::Binary(op, _) => operator_text(op),
+        Pending::Unary(op) => format!("unary {}", op),
+        Pending::Paren => "(".to_string(),
+        Pending::Abs => "|".to_string(),
+        Pending::Call(name, _) => format!("{}(", identifier_text(name)),
+    }
+}
+// Pops pending operators until the group opened by `opener` is reached. A
+// closing paren also ends a function call, collecting its arguments.
+fn close_group(
+    output_stack: &mut Vec<AST>,
+    operator_stack: &mut Vec<Pending>,
+    opener: Pending,
+    keep_groups: bool,
+    trace: &mut ParseTrace,
+) -> Result<(), CalcError> {
+    let closes_paren = matches!(opener, Pending::Paren);
+    loop {
+        match operator_stack.pop() {
+            Some(Pending::Paren) if closes_paren && keep_groups => {
+                let inner = output_stack.pop().ok_or(CalcError::UnexpectedEnd)?;
+                trace.step(|| "matched ( with )".to_string());
+                output_stack.push(AST::Grouped(Box::new(inner)));
+                return Ok(());
+            }
+            Some(Pending::Paren) if closes_paren => {
+                trace.step(|| "matched ( with )".to_string());
+                return Ok(());
+            }
+            Some(Pending::Call(name, base)) if closes_paren => {
+                let args = output_stack.split_off(base);
+                debug_log!("built Call({})", name);
+                let call = AST::Call { name, args };
+                trace.step(|| format!("matched {}( with ): built {}", identifier_text(name), call));
+                output_stack.push(call);
+                return Ok(());
+            }
+            Some(Pending::Abs) if !closes_paren => {
+                trace.step(|| "matched | with |".to_string());
+                return apply_pending(output_stack, Pending::Abs, trace);
+            }
+            Some(Pending::Paren) | Some(Pending::Call(..)) => {
+                return Err(CalcError::UnbalancedParens)
+            }
+            Some(Pending::Abs) => return Err(CalcError::UnbalancedBars),
+            Some(pending) => apply_pending(output_stack, pending, trace)?,
+            None if closes_paren => return Err(CalcError::UnbalancedParens),
+            None => return Err(CalcError::UnbalancedBars),
+        }
+    }
+}
+fn parse(tokens: &[Token]) -> Result<AST, CalcError> {
+    let spanned: Vec<(Token, Span)> = tokens
+        .iter()
+        .map(|token| (token.clone(), Span::default()))
+        .collect();
+    parse_spanned(&spanned)
+}
+// `|` opens an absolute value where an operand is expected and closes one
+// after an operand, which is enough to read nested bars like `||x| - 1|`.
+fn parse_spanned(tokens: &[(Token, Span)]) -> Result<AST, CalcError> {
+    parse_spanned_with(tokens, &ParseOptions::default())
+}
+fn parse_spanned_with(tokens: &[(Token, Span)], options: &ParseOptions) -> Result<AST, CalcError> {
+    if options.reject_mixed {
+        let grouped = parse_spanned_with(
+            tokens,
+            &ParseOptions {
+                keep_groups: true,
+                reject_mixed: false,
+                ..*options
+            },
+        )?;
+        check_unmixed(&grouped)?;
+        return Ok(if options.keep_groups {
+            grouped
+        } else {
+            without_groups(grouped)
+        });
+    }
+    parse_traced(tokens, options, &mut ParseTrace(None))
+}
+fn parse_traced(
+    tokens: &[(Token, Span)],
+    options: &ParseOptions,
+    trace: &mut ParseTrace,
+) -> Result<AST, CalcError> {
+    let mut output_stack: Vec<AST> = Vec::new();
+    let mut operator_stack: Vec<Pending> = Vec::new();
+    let mut expect_operand = true;
+    let mut call_opened = false;
+    let mut tokens = tokens.iter().peekable();
+    let mut last_token = None;
+
+    while let Some((token, span)) = tokens.next() {
+        last_token = Some(token);
+        let just_opened = call_opened;
+        call_opened = false;
+        match (token, expect_operand) {
+            (Token::Number(n, radix), true) => {
+                debug_log!("built Number({})", n);
+                trace.step(|| format!("operand {}", format_literal(*n, *radix)));
+                output_stack.push(AST::Number(*n, *radix));
+                expect_operand = false;
+            }
+            (Token::Float(x), true) => {
+                debug_log!("built Float({:?})", x);
+                trace.step(|| format!("operand {}", AST::Float(*x)));
+                output_stack.push(AST::Float(*x));
+                expect_operand = false;
+            }
+            (Token::Identifier(name), true) => {
+                if let Some((Token::LeftParen, _)) = tokens.peek() {
+                    tokens.next();
+                    let call = Pending::Call(*name, output_stack.len());
+                    trace.step(|| format!("push {}", pending_text(call)));
+                    operator_stack.push(call);
+                    call_opened = true;
+                } else {
+                    debug_log!("built Variable({})", name);
+                    trace.step(|| format!("operand {}", identifier_text(*name)));
+                    output_stack.push(AST::Variable(*name));
+                    expect_operand = false;
+                }
+            }
+            (Token::Operator('-'), true) => {
+                trace.step(|| "push unary -".to_string());
+                operator_stack.push(Pending::Unary('-'))
+            }
+            // Unary plus is a no-op and builds no node: `+5` parses as `5`.
+            (Token::Operator('+'), true) => trace.step(|| "skip unary +".to_string()),
+            (Token::LeftParen, true) => {
+                trace.step(|| "push (".to_string());
+                operator_stack.push(Pending::Paren)
+            }
+            (Token::Bar, true) => {
+                trace.step(|| "push |".to_string());
+                operator_stack.push(Pending::Abs)
+            }
+            (Token::Operator(op), false) => {
+                while let Some(&top) = operator_stack.last() {
+                    let top_precedence = match top {
+                        Pending::Binary(top_op, _) => precedence(top_op),
+                        Pending::Unary(_) => UNARY_PRECEDENCE,
+                        Pending::Paren | Pending::Abs | Pending::Call(..) => break,
+                    };
+                    if !binds_before(top_precedence, *op, options) {
+                        trace.step(|| {
+                            format!(
+                                "keep {}: {} binds tighter",
+                                pending_text(top),
+                                operator_text(*op)
+                            )
+                        });
+                        break;
+                    }
+                    trace.step(|| {
+                        format!("{} binds before {}", pending_text(top), operator_text(*op))
+                    });
+                    operator_stack.pop();
+                    apply_pending(&mut output_stack, top, trace)?;
+                }
+                trace.step(|| format!("push {}", operator_text(*op)));
+                operator_stack.push(Pending::Binary(*op, *span));
+                expect_operand = true;
+            }
+            (Token::Comma, false) => {
+                while let Some(top) = operator_stack.last() {
+                    match top {
+                        Pending::Call(..) => break,
+                        Pending::Paren | Pending::Abs => {
+                            return Err(CalcError::UnexpectedToken(Token::Comma))
+                        }
+                        _ => {
+                            let top = operator_stack.pop().unwrap();
+                            apply_pending(&mut output_stack, top, trace)?;
+                        }
+                    }
+                }
+                if operator_stack.is_empty() {
+                    return Err(CalcError::UnexpectedToken(Token::Comma));
+                }
+                trace.step(|| "next argument".to_string());
+                expect_operand = true;
+            }
+            (Token::RightParen, true) if just_opened => {
+                close_group(
+                    &mut output_stack,
+                    &mut operator_stack,
+                    Pending::Paren,
+                    options.keep_groups,
+                    trace,
+                )?;
+                expect_operand = false;
+            }
+            (Token::RightParen, false) => close_group(
+                &mut output_stack,
+                &mut operator_stack,
+                Pending::Paren,
+                options.keep_groups,
+                trace,
+            )?,
+            (Token::Bar, false) => close_group(
+                &mut output_stack,
+                &mut operator_stack,
+                Pending::Abs,
+                options.keep_groups,
+                trace,
+            )?,
+            (token, _) => return Err(CalcError::UnexpectedToken(token.clone())),
+        }
+    }
+
+    if expect_operand {
+        return Err(ended_early(last_token));
+    }
+    while let Some(pending) = operator_stack.pop() {
+        match pending {
+            Pending::Paren | Pending::Call(..) => return Err(CalcError::UnbalancedParens),
+            Pending::Abs => return Err(CalcError::UnbalancedBars),
+            _ => apply_pending(&mut output_stack, pending, trace)?,
+        }
+    }
+
+    output_stack.pop().ok_or(CalcError::UnexpectedEnd)
+}
+// The shunting-yard parser's decisions for `input`, one per line, in the
+// order `parse` makes them: `1 + 2 * 3` keeps `+` on the stack when `*`
+// arrives, since `*` binds tighter.
+#[allow(dead_code)]
+fn explain_parse(input: &str) -> Result<Vec<String>, CalcError> {
+    explain_parse_with(input, &LexOptions::default(), &ParseOptions::default())
+}
+// `explain_parse` under a session's options, so `:assoc sub right` shows up
+// in the decisions.
+fn explain_parse_with(
+    input: &str,
+    lex_options: &LexOptions,
+    parse_options: &ParseOptions,
+) -> Result<Vec<String>, CalcError> {
+    let tokens = lex_spanned_with(input, lex_options)?;
+    let mut trace = ParseTrace(Some(Vec::new()));
+    parse_traced(&tokens, parse_options, &mut trace)?;
+    Ok(trace.0.unwrap_or_default())
+}
+impl ParseTrace {
+    fn step(&mut self, describe: impl FnOnce() -> String) {
+        if let Some(steps) = &mut self.0 {
+            steps.push(describe());
+        }
+    }
+}
+// Finds a comparison with an arithmetic operand the user didn't parenthesize;
+// `ast` must keep its groups so the parenthesized ones can be told apart.
+fn check_unmixed(ast: &AST) -> Result<(), CalcError> {
+    match ast {
+        AST::Number(..) | AST::Float(_) | AST::Variable(_) => Ok(()),
+        AST::UnaryOp { operand, .. } => check_unmixed(operand),
+        AST::BinaryOp {
+            op,
+            left,
+            right,
+            span,
+        } => {
+            if is_comparison(*op) {
+                for operand in [left, right] {
+                    if let AST::BinaryOp { op: inner, .. } = operand.as_ref() {
+                        if !is_comparison(*inner) {
+                            return Err(CalcError::AmbiguousMix {
+                                comparison: *op,
+                                arithmetic: *inner,
+                                span: *span,
+                            });
+                        }
+                    }
+                }
+            }
+            check_unmixed(left)?;
+            check_unmixed(right)
+        }
+        AST::Call { args, .. } => args.iter().try_for_each(check_unmixed),
+        AST::Grouped(inner) => check_unmixed(inner),
+    }
+}
+fn without_groups(ast: AST) -> AST {
+    match ast {
+        AST::UnaryOp { op, operand } => AST::unary(op, without_groups(*operand)),
+        AST::BinaryOp {
+            op,
+            left,
+            right,
+            span,
+        } => AST::BinaryOp {
+            op,
+            left: Box::new(without_groups(*left)),
+            right: Box::new(without_groups(*right)),
+            span,
+        },
+        AST::Call { name, args } => AST::Call {
+            name,
+            args: args.into_iter().map(without_groups).collect(),
+        },
+        AST::Grouped(inner) => without_groups(*inner),
+        leaf => leaf,
+    }
+}
+// Precedence-climbing alternative to the shunting-yard `parse`; both build
+// identical trees and report the same errors.
+#[allow(dead_code)]
+fn parse_pratt(tokens: &[Token]) -> Result<AST, CalcError> {
+    let mut parser = Parser { tokens, pos: 0 };
+    let ast = parser.expression(0)?;
+    match parser.peek() {
+        None => Ok(ast),
+        Some(Token::RightParen) => Err(CalcError::UnbalancedParens),
+        Some(Token::Bar) => Err(CalcError::UnbalancedBars),
+        Some(token) => Err(CalcError::UnexpectedToken(token.clone())),
+    }
+}
+// Parses one complete expression from the front of `tokens` and hands back
+// whatever follows it, for callers embedding arithmetic in a larger grammar.
+#[allow(dead_code)]
+fn parse_prefix(tokens: &[Token]) -> Result<(AST, &[Token]), CalcError> {
+    let mut parser = Parser { tokens, pos: 0 };
+    let ast = parser.expression(0)?;
+    Ok((ast, &tokens[parser.pos..]))
+}
+// The error for input that stops where an operand was expected, naming the
+// operator left dangling at the end of `3 +`.
+fn ended_early(last_token: Option<&Token>) -> CalcError {
+    match last_token {
+        Some(Token::Operator(op)) => CalcError::TrailingOperator(*op),
+        _ => CalcError::UnexpectedEnd,
+    }
+}
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+    fn next(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        token
+    }
+    fn expression(&mut self, min_precedence: u8) -> Result<AST, CalcError> {
+        let mut left = self.primary()?;
+        while let Some(&Token::Operator(op)) = self.peek() {
+            if precedence(op) < min_precedence {
+                break;
+            }
+            self.pos += 1;
+            let next_min = if is_right_assoc(op) {
+                precedence(op)
+            } else {
+                precedence(op) + 1
+            };
+            let right = self.expression(next_min)?;
+            left = AST::binary(op, left, right);
+        }
+        Ok(left)
+    }
+    fn primary(&mut self) -> Result<AST, CalcError> {
+        match self.next().cloned() {
+            Some(Token::Number(n, radix)) => Ok(AST::Number(n, radix)),
+            Some(Token::Float(x)) => Ok(AST::Float(x)),
+            Some(Token::Identifier(name)) => {
+                if self.peek() != Some(&Token::LeftParen) {
+                    return Ok(AST::Variable(name));
+                }
+                self.pos += 1;
+                let mut args = Vec::new();
+                if self.peek() == Some(&Token::RightParen) {
+                    self.pos += 1;
+                    return Ok(AST::Call { name, args });
+                }
+                loop {
+                    args.push(self.expression(0)?);
+                    match self.next() {
+                        Some(Token::Comma) => continue,
+                        Some(Token::RightParen) => return Ok(AST::Call { name, args }),
+                        None | Some(Token::Bar) => return Err(CalcError::UnbalancedParens),
+                        Some(token) => return Err(CalcError::UnexpectedToken(token.clone())),
+                    }
+                }
+            }
+            Some(Token::Operator('-')) => {
+                let operand = self.expression(UNARY_PRECEDENCE)?;
+                Ok(AST::unary('-', operand))
+            }
+            Some(Token::Operator('+')) => self.expression(UNARY_PRECEDENCE),
+            Some(Token::LeftParen) => {
+                let inner = self.expression(0)?;
+                match self.next() {
+                    Some(Token::RightParen) => Ok(inner),
+                    None | Some(Token::Bar) => Err(CalcError::UnbalancedParens),
+                    Some(token) => Err(CalcError::UnexpectedToken(token.clone())),
+                }
+            }
+            Some(Token::Bar) => {
+                let inner = self.expression(0)?;
+                match self.next() {
+                    Some(Token::Bar) => Ok(AST::unary('|', inner)),
+                    None | Some(Token::RightParen) => Err(CalcError::UnbalancedBars),
+                    Some(token) => Err(CalcError::UnexpectedToken(token.clone())),
+                }
+            }
+            Some(token) => Err(CalcError::UnexpectedToken(token)),
+            None => Err(ended_early(self.tokens.last())),
+        }
+    }
+}
+#[allow(dead_code)]
+fn run(input: &str) -> Result<i64, CalcError> {
+    run_limited(input, DEFAULT_MAX_INPUT_LEN)
+}
+// `run` rejecting input longer than `limit` bytes, as `main` does with
+// `BASIC_ASTS_MAX_INPUT`.
+fn run_limited(input: &str, limit: usize) -> Result<i64, CalcError> {
+    check_input_len(input, limit)?;
+    evaluate(&parse_spanned(&lex_spanned(input)?)?)
+}
+// Evaluates a top-level comma list like `1+1, 2*3, 4` to one value per
+// element: `[2, 6, 4]`. Input without top-level commas is a 1-tuple.
+#[allow(dead_code)]
+fn evaluate_tuple(input: &str) -> Result<Vec<i64>, CalcError> {
+    check_input_len(input, DEFAULT_MAX_INPUT_LEN)?;
+    let tokens = lex_spanned(input)?;
+    split_tuple(&tokens)
+        .into_iter()
+        .map(|element| evaluate(&parse_spanned(element)?))
+        .collect()
+}
+// Splits at commas outside any parentheses, so call arguments stay whole.
+fn split_tuple(tokens: &[(Token, Span)]) -> Vec<&[(Token, Span)]> {
+    let mut elements = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, (token, _)) in tokens.iter().enumerate() {
+        match token {
+            Token::LeftParen => depth += 1,
+            Token::RightParen => depth -= 1,
+            Token::Comma if depth == 0 => {
+                elements.push(&tokens[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    elements.push(&tokens[start..]);
+    elements
+}
+// Every stage of the pipeline for one input, as printed by `--explain`: the
+// tokens, the tree, its postfix form and the result.
+fn explain(input: &str) -> Result<String, CalcError> {
+    let tokens = lex_spanned(input)?;
+    let ast = parse_spanned(&tokens)?;
+    let token_list: Vec<String> = tokens.iter().map(|(token, _)| token.to_string()).collect();
+    let mut text = format!("tokens: {}\ntree:\n", token_list.join(" "));
+    write_tree(&ast, 1, &mut text);
+    text.push_str(&format!("rpn: {}\n", to_rpn(&ast)));
+    match evaluate(&ast) {
+        Ok(value) => text.push_str(&format!("result: {}\n", value)),
+        Err(e) => text.push_str(&format!("error: {}\n", e)),
+    }
+    Ok(text)
+}
+// One node per line, children indented two spaces under their parent.
+fn write_tree(ast: &AST, depth: usize, out: &mut String) {
+    let label = match ast {
+        AST::Number(n, _) => format!("Number {}", n),
+        AST::Float(x) => format!("Float {:?}", x),
+        AST::Variable(name) => format!("Variable {}", name),
+        AST::UnaryOp { op, .. } => format!("Unary {}", op),
+        AST::BinaryOp { op, .. } => format!("Binary {}", operator_text(*op)),
+        AST::Call { name, .. } => format!("Call {}", name),
+        AST::Grouped(_) => "Grouped".to_string(),
+    };
+    out.push_str(&format!("{}{}\n", "  ".repeat(depth), label));
+    match ast {
+        AST::UnaryOp { operand, .. } | AST::Grouped(operand) => write_tree(operand, depth + 1, out),
+        AST::BinaryOp { left, right, .. } => {
+            write_tree(left, depth + 1, out);
+            write_tree(right, depth + 1, out);
+        }
+        AST::Call { args, .. } => {
+            for arg in args {
+                write_tree(arg, depth + 1, out);
+            }
+        }
+        _ => {}
+    }
+}
+// Serializes a tree as JSON, one object per node tagged by `"type"`. The
+// crate has no dependencies, so this is written out by hand.
+fn to_json(ast: &AST) -> String {
+    let mut out = String::new();
+    write_json(ast, false, &mut out);
+    out
+}
+// Like `to_json`, but each `BinaryOp` also carries its operator's
+// `"precedence"` and `"associativity"` from the precedence table.
+#[allow(dead_code)]
+fn to_json_rich(ast: &AST) -> String {
+    let mut out = String::new();
+    write_json(ast, true, &mut out);
+    out
+}
+fn write_json(ast: &AST, rich: bool, out: &mut String) {
+    match ast {
+        AST::Number(n, _) => out.push_str(&format!(r#"{{"type":"Number","value":{}}}"#, n)),
+        AST::Float(x) => out.push_str(&format!(r#"{{"type":"Float","value":{:?}}}"#, x)),
+        AST::Variable(name) => out.push_str(&format!(
+            r#"{{"type":"Variable","name":{}}}"#,
+            json_string(&name.name())
+        )),
+        AST::UnaryOp { op, operand } => {
+            out.push_str(&format!(
+                r#"{{"type":"UnaryOp","op":{},"operand":"#,
+                json_string(&op.to_string())
+            ));
+            write_json(operand, rich, out);
+            out.push('}');
+        }
+        AST::BinaryOp {
+            op, left, right, ..
+        } => {
+            out.push_str(&format!(
+                r#"{{"type":"BinaryOp","op":{},"#,
+                json_string(&operator_text(*op))
+            ));
+            if rich {
+                let associativity = if is_right_assoc(*op) { "right" } else { "left" };
+                out.push_str(&format!(
+                    r#""precedence":{},"associativity":"{}","#,
+                    precedence(*op),
+                    associativity
+                ));
+            }
+            out.push_str(r#""left":"#);
+            write_json(left, rich, out);
+            out.push_str(r#","right":"#);
+            write_json(right, rich, out);
+            out.push('}');
+        }
+        AST::Call { name, args } => {
+            out.push_str(&format!(
+                r#"{{"type":"Call","name":{},"args":["#,
+                json_string(&name.name())
+            ));
+            for (i, arg) in args.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json(arg, rich, out);
+            }
+            out.push_str("]}");
+        }
+        AST::Grouped(inner) => {
+            out.push_str(r#"{"type":"Grouped","inner":"#);
+            write_json(inner, rich, out);
+            out.push('}');
+        }
+    }
+}
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+// Evaluates `expr` with its variables bound from a flat JSON object such as
+// `{"x": 3, "y": 1.5, "on": true}`.
+#[allow(dead_code)]
+fn eval_with_json(expr: &str, vars_json: &str) -> Result<i64, CalcError> {
+    let env = parse_json_env(vars_json)?;
+    let ast = parse(&lex(expr)?)?;
+    evaluate_with(&ast, &env, &EvalOptions::default())
+}
+type JsonChars<'a> = std::iter::Peekable<std::str::CharIndices<'a>>;
+// Only numbers and booleans can be bound, so nested objects, arrays,
+// strings and `null` as values are errors. A repeated key keeps its last
+// value.
+fn parse_json_env(json: &str) -> Result<Env, CalcError> {
+    let mut chars = json.char_indices().peekable();
+    let mut env = Env::new();
+    expect_json(&mut chars, '{', json.len())?;
+    skip_json_space(&mut chars);
+    if chars.next_if(|&(_, c)| c == '}').is_none() {
+        loop {
+            skip_json_space(&mut chars);
+            let name = json_key(&mut chars, json.len())?;
+            expect_json(&mut chars, ':', json.len())?;
+            skip_json_space(&mut chars);
+            env.insert(Symbol::intern(&name), json_value(&mut chars, json)?);
+            skip_json_space(&mut chars);
+            match chars.next() {
+                Some((_, ',')) => continue,
+                Some((_, '}')) => break,
+                other => return Err(json_error(other, json.len(), "',' or '}'")),
+            }
+        }
+    }
+    skip_json_space(&mut chars);
+    match chars.next() {
+        None => Ok(env),
+        other => Err(json_error(other, json.len(), "end of input")),
+    }
+}
+fn skip_json_space(chars: &mut JsonChars) {
+    while chars
+        .next_if(|&(_, c)| matches!(c, ' ' | '\t' | '\n' | '\r'))
+        .is_some()
+    {}
+}
+fn expect_json(chars: &mut JsonChars, expected: char, len: usize) -> Result<(), CalcError> {
+    skip_json_space(chars);
+    match chars.next() {
+        Some((_, c)) if c == expected => Ok(()),
+        other => Err(json_error(other, len, &format!("'{}'", expected))),
+    }
+}
+fn json_error(found: Option<(usize, char)>, len: usize, expected: &str) -> CalcError {
+    let position = found.map_or(len, |(i, _)| i);
+    CalcError::InvalidJson(format!("expected {} at byte {}", expected, position))
+}
+fn json_key(chars: &mut JsonChars, len: usize) -> Result<String, CalcError> {
+    expect_json(chars, '"', len)?;
+    let mut key = String::new();
+    loop {
+        let c = match chars.next() {
+            Some((_, '"')) => return Ok(key),
+            Some((_, '\\')) => match chars.next() {
+                Some((_, '"')) => '"',
+                Some((_, '\\')) => '\\',
+                Some((_, '/')) => '/',
+                Some((_, 'b')) => '\u{8}',
+                Some((_, 'f')) => '\u{c}',
+                Some((_, 'n')) => '\n',
+                Some((_, 'r')) => '\r',
+                Some((_, 't')) => '\t',
+                Some((at, 'u')) => {
+                    let hex: String = (0..4)
+                        .filter_map(|_| chars.next().map(|(_, c)| c))
+                        .collect();
+                    u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or_else(|| json_error(Some((at, 'u')), len, "a \\u escape"))?
+                }
+                other => return Err(json_error(other, len, "an escape")),
+            },
+            Some((_, c)) if (c as u32) >= 0x20 => c,
+            other => return Err(json_error(other, len, "'\"'")),
+        };
+        key.push(c);
+    }
+}
+fn json_value(chars: &mut JsonChars, json: &str) -> Result<Value, CalcError> {
+    let Some(&(start, first)) = chars.peek() else {
+        return Err(json_error(None, json.len(), "a number or boolean"));
+    };
+    let mut end = start;
+    while let Some((i, c)) = chars.next_if(|&(_, c)| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    {
+        end = i + c.len_utf8();
+    }
+    let text = &json[start..end];
+    let value = match text {
+        "true" => Some(Value::Bool(true)),
+        "false" => Some(Value::Bool(false)),
+        _ if !(first == '-' || first.is_ascii_digit()) => None,
+        _ if text.contains(['.', 'e', 'E']) => text.parse().ok().map(Value::Float),
+        _ => text.parse().ok().map(Value::Int),
+    };
+    value.ok_or_else(|| json_error(Some((start, first)), json.len(), "a number or boolean"))
+}
+// Postfix notation: `neg` and `abs` are the unary operators, and a call is
+// written `name/arity` after its arguments.
+fn to_rpn(ast: &AST) -> String {
+    match ast {
+        AST::UnaryOp { op: '|', operand } => format!("{} abs", to_rpn(operand)),
+        AST::UnaryOp { operand, .. } => format!("{} neg", to_rpn(operand)),
+        AST::BinaryOp {
+            op, left, right, ..
+        } => format!("{} {} {}", to_rpn(left), to_rpn(right), operator_text(*op)),
+        AST::Call { name, args } => {
+            let mut parts: Vec<String> = args.iter().map(to_rpn).collect();
+            parts.push(format!("{}/{}", name, args.len()));
+            parts.join(" ")
+        }
+        AST::Grouped(inner) => to_rpn(inner),
+        _ => ast.to_string(),
+    }
+}
+// `run` for input read from a file in a legacy encoding, such as the
+// Latin-1 bytes `6 \xd7 7` for `6 × 7`.
+#[allow(dead_code)]
+fn run_bytes(bytes: &[u8], encoding: Encoding) -> Result<i64, CalcError> {
+    run(&decode(bytes, encoding)?)
+}
+// Errors give the offset of the first byte that isn't valid.
+fn decode(bytes: &[u8], encoding: Encoding) -> Result<String, CalcError> {
+    let invalid = |offset| CalcError::InvalidEncoding { encoding, offset };
+    match encoding {
+        Encoding::Utf8 => std::str::from_utf8(bytes)
+            .map(str::to_string)
+            .map_err(|e| invalid(e.valid_up_to())),
+        Encoding::Latin1 => Ok(bytes.iter().map(|&byte| byte as char).collect()),
+        Encoding::Ascii => match bytes.iter().position(|byte| !byte.is_ascii()) {
+            Some(offset) => Err(invalid(offset)),
+            None => Ok(bytes.iter().map(|&byte| byte as char).collect()),
+        },
+    }
+}
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Latin1 => "Latin-1",
+            Encoding::Ascii => "ASCII",
+        }
+    }
+}
+// Runs each input independently, pairing it with its outcome.
+fn run_many(inputs: &[&str], limit: usize) -> Vec<(String, Result<i64, CalcError>)> {
+    inputs
+        .iter()
+        .map(|input| (input.to_string(), run_limited(input, limit)))
+        .collect()
+}
+// Evaluates lines as they are pulled, so a long stream is never buffered.
+// Blank lines are skipped, as `--csv` does, rather than reported.
+#[allow(dead_code)]
+fn eval_lines<I: Iterator<Item = String>>(
+    lines: I,
+) -> impl Iterator<Item = Result<i64, CalcError>> {
+    lines
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| run(normalize_input(&line)))
+}
+// `run_many` results as CSV with an `input,result,error` header. Exactly one
+// of `result` and `error` is filled in on each row.
+fn to_csv(results: &[(String, Result<i64, CalcError>)]) -> String {
+    let mut csv = String::from("input,result,error\n");
+    for (input, result) in results {
+        let (value, error) = match result {
+            Ok(n) => (n.to_string(), String::new()),
+            Err(e) => (String::new(), e.to_string()),
+        };
+        csv.push_str(&format!(
+            "{},{},{}\n",
+            csv_field(input),
+            value,
+            csv_field(&error)
+        ));
+    }
+    csv
+}
+// Quotes a field holding a comma, quote or line break, doubling its quotes.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+// Re-emits an expression with canonical spacing, keeping the parentheses
+// the user wrote and adding none: `(1+2)*3` becomes `(1 + 2) * 3`.
+#[allow(dead_code)]
+fn format_expr(input: &str) -> Result<String, CalcError> {
+    format_expr_with(input, &LexOptions::default(), &ParseOptions::default())
+}
+// `format_expr` reading `input` the way a session with these options would.
+fn format_expr_with(
+    input: &str,
+    lex_options: &LexOptions,
+    parse_options: &ParseOptions,
+) -> Result<String, CalcError> {
+    Ok(parse_spanned_with(
+        &lex_spanned_with(input, lex_options)?,
+        &ParseOptions {
+            keep_groups: true,
+            ..*parse_options
+        },
+    )?
+    .to_string())
+}
+// Spells out the grouping that precedence and associativity imply, for
+// teaching: `1 + 2 * 3` becomes `1 + (2 * 3)` and `-2^2` becomes `-(2 ^ 2)`.
+#[allow(dead_code)]
+fn explain_precedence(input: &str) -> Result<String, CalcError> {
+    Ok(fully_grouped(&parse_spanned(&lex_spanned(input)?)?, false))
+}
+fn fully_grouped(ast: &AST, nested: bool) -> String {
+    let text = match ast {
+        AST::BinaryOp {
+            op, left, right, ..
+        } => format!(
+            "{} {} {}",
+            fully_grouped(left, true),
+            operator_text(*op),
+            fully_grouped(right, true)
+        ),
+        AST::UnaryOp { op: '|', operand } => return format!("|{}|", fully_grouped(operand, false)),
+        AST::UnaryOp { op, operand } => format!("{}{}", op, fully_grouped(operand, true)),
+        AST::Call { name, args } => {
+            let args: Vec<String> = args.iter().map(|arg| fully_grouped(arg, false)).collect();
+            return format!("{}({})", name, args.join(", "));
+        }
+        AST::Grouped(inner) => return fully_grouped(inner, nested),
+        _ => return ast.to_string(),
+    };
+    if nested {
+        format!("({})", text)
+    } else {
+        text
+    }
+}
+fn check_input_len(input: &str, limit: usize) -> Result<(), CalcError> {
+    if input.len() > limit {
+        return Err(CalcError::InputTooLong {
+            length: input.len(),
+            limit,
+        });
+    }
+    Ok(())
+}
+// `^` runs a loop of multiplications, so it counts as this many operations.
+const POWER_COST: u64 = 8;
+// A rough operation count for `ast`, computed without evaluating it, so a
+// caller can turn away expensive input before running it or pick a budget.
+// Each operation counts 1 except `^`; an `if` counts its costlier branch.
+#[allow(dead_code)]
+fn estimate_cost(ast: &AST) -> u64 {
+    match ast {
+        AST::Number(..) | AST::Float(_) | AST::Variable(_) => 0,
+        AST::UnaryOp { operand, .. } => estimate_cost(operand).saturating_add(1),
+        AST::BinaryOp {
+            op, left, right, ..
+        } => {
+            let cost = if *op == '^' { POWER_COST } else { 1 };
+            cost.saturating_add(estimate_cost(left))
+                .saturating_add(estimate_cost(right))
+        }
+        AST::Call { name, args } if name.is("if") && args.len() == 3 => {
+            let branch = estimate_cost(&args[1]).max(estimate_cost(&args[2]));
+            estimate_cost(&args[0])
+                .saturating_add(branch)
+                .saturating_add(1)
+        }
+        AST::Call { args, .. } => args
+            .iter()
+            .fold(1u64, |total, arg| total.saturating_add(estimate_cost(arg))),
+        AST::Grouped(inner) => estimate_cost(inner),
+    }
+}
+fn evaluate(ast: &AST) -> Result<i64, CalcError> {
+    evaluate_with(ast, &Env::new(), &EvalOptions::default())
+}
+fn evaluate_with(ast: &AST, env: &Env, options: &EvalOptions) -> Result<i64, CalcError> {
+    evaluate_with_rng(ast, env, options, &Rng::from_time())
+}
+// Evaluates with `functions` in place of the integer built-ins, so an
+// integer call to a name the registry lacks, even `abs` or `gcd`, is an
+// unknown function. The evaluator's own `float`, `rand`, `pct`, `change`,
+// `sum`, `prod` and `if`, and `abs`, `int`, `max` and `min` given a float
+// or a fraction, don't go through the registry and always work.
+#[allow(dead_code)]
+fn evaluate_with_functions(
+    ast: &AST,
+    env: &Env,
+    functions: &FunctionRegistry,
+) -> Result<i64, CalcError> {
+    let options = EvalOptions::default();
+    let rng = Rng::from_time();
+    let mut evaluator = Evaluator {
+        env,
+        options: &options,
+        rng: &rng,
+        functions: Some(functions),
+        modulus: None,
+        steps: 0,
+        warnings: Vec::new(),
+    };
+    evaluator.eval(ast)?.as_int()
+}
+fn evaluate_with_rng(
+    ast: &AST,
+    env: &Env,
+    options: &EvalOptions,
+    rng: &Rng,
+) -> Result<i64, CalcError> {
+    evaluate_value(ast, env, options, rng)?.as_int()
+}
+fn evaluate_value(
+    ast: &AST,
+    env: &Env,
+    options: &EvalOptions,
+    rng: &Rng,
+) -> Result<Value, CalcError> {
+    evaluate_with_warnings(ast, env, options, rng).map(|(value, _)| value)
+}
+// Also returns the warnings raised under `float_warnings` and
+// `undefined_as_zero`.
+fn evaluate_with_warnings(
+    ast: &AST,
+    env: &Env,
+    options: &EvalOptions,
+    rng: &Rng,
+) -> Result<(Value, Vec<String>), CalcError> {
+    let mut evaluator = Evaluator {
+        env,
+        options,
+        rng,
+        functions: None,
+        modulus: options.modulus,
+        steps: 0,
+        warnings: Vec::new(),
+    };
+    let value = evaluator.eval(ast)?;
+    Ok((value, evaluator.warnings))
+}
+// Flattens `ast` into bytecode for `run_vm`, so an expression evaluated many
+// times skips walking the boxed tree. `if` compiles to jumps and keeps its
+// short-circuiting.
+fn compile(ast: &AST) -> Vec<Op> {
+    let mut code = Vec::new();
+    compile_into(ast, &mut code);
+    code
+}
+fn compile_into(ast: &AST, code: &mut Vec<Op>) {
+    match ast {
+        AST::Number(n, _) => code.push(Op::Push(Value::Int(*n))),
+        AST::Float(x) => code.push(Op::Push(Value::Float(*x))),
+        AST::Variable(name) => code.push(Op::Load(*name)),
+        AST::UnaryOp { op, operand } => {
+            compile_into(operand, code);
+            code.push(Op::Unary(*op));
+        }
+        AST::BinaryOp {
+            op,
+            left,
+            right,
+            span,
+        } => {
+            compile_into(left, code);
+            compile_into(right, code);
+            code.push(Op::Binary(*op, *span));
+        }
+        AST::Call { name, args } if name.is("if") && args.len() == 3 => {
+            compile_into(&args[0], code);
+            let to_otherwise = code.len();
+            code.push(Op::JumpIfZero(0));
+            compile_into(&args[1], code);
+            let to_end = code.len();
+            code.push(Op::Jump(0));
+            code[to_otherwise] = Op::JumpIfZero(code.len());
+            compile_into(&args[2], code);
+            code[to_end] = Op::Jump(code.len());
+        }
+        AST::Call { name, args } => {
+            for arg in args {
+                compile_into(arg, code);
+            }
+            code.push(Op::Call(*name, args.len()));
+        }
+        AST::Grouped(inner) => compile_into(inner, code),
+    }
+}
+// Runs bytecode from `compile`, giving the same results and errors as
+// `evaluate_with` on the tree it came from.
+fn run_vm(code: &[Op], env: &Env) -> Result<i64, CalcError> {
+    let options = EvalOptions::default();
+    let rng = Rng::from_time();
+    let mut evaluator = Evaluator {
+        env,
+        options: &options,
+        rng: &rng,
+        functions: None,
+        modulus: None,
+        steps: 0,
+        warnings: Vec::new(),
+    };
+    let mut stack = Vec::new();
+    let mut pc = 0;
+    while let Some(op) = code.get(pc) {
+        pc += 1;
+        let value = match *op {
+            Op::Push(ref value) => value.clone(),
+            Op::Load(name) => evaluator.lookup(name)?,
+            Op::Unary(op) => {
+                let operand = pop(&mut stack);
+                evaluator.unary(op, operand)?
+            }
+            Op::Binary(op, span) => {
+                let right = pop(&mut stack);
+                let left = pop(&mut stack);
+                evaluator.binary(op, left, right, span)?
+            }
+            Op::Call(name, argc) => {
+                evaluator.check_call(name, argc)?;
+                let args = stack.split_off(stack.len() - argc);
+                let value = evaluator.apply(name, &args)?;
+                evaluator.fit_width(value)?
+            }
+            Op::JumpIfZero(target) => {
+                evaluator.step()?;
+                if pop(&mut stack).as_f64() == 0.0 {
+                    pc = target;
+                }
+                continue;
+            }
+            Op::Jump(target) => {
+                pc = target;
+                continue;
+            }
+        };
+        stack.push(value);
+    }
+    pop(&mut stack).as_int()
+}
+// Compiles `ast` once and returns a function evaluating it against any
+// environment, for running the same expression over many values of `x`.
+#[allow(dead_code)]
+fn into_evaluator(ast: AST) -> impl Fn(&Env) -> Result<i64, CalcError> {
+    let code = compile(&ast);
+    move |env| run_vm(&code, env)
+}
+// `ast` evaluated once per value of `var`, in order, for tabulating or
+// plotting `y = f(x)`. It is compiled once and every value runs the same
+// bytecode.
+#[allow(dead_code)]
+fn evaluate_series(ast: &AST, var: &str, values: &[i64]) -> Vec<Result<i64, CalcError>> {
+    let code = compile(ast);
+    let var = Symbol::intern(var);
+    let mut env = Env::new();
+    values
+        .iter()
+        .map(|&value| {
+            env.insert(var, Value::Int(value));
+            run_vm(&code, &env)
+        })
+        .collect()
+}
+// Times `iterations` runs of `ast` with each evaluator. One untimed run
+// first reports any error instead of timing it.
+#[cfg(not(feature = "no_std"))]
+fn time_evaluations(ast: &AST, env: &Env, iterations: u32) -> Result<Timing, CalcError> {
+    let options = EvalOptions::default();
+    evaluate_with(ast, env, &options)?;
+    let start = std::time::Instant::now();
+    for _ in 0..iterations {
+        let _ = std::hint::black_box(evaluate_with(std::hint::black_box(ast), env, &options));
+    }
+    let tree = start.elapsed();
+    let code = compile(ast);
+    let start = std::time::Instant::now();
+    for _ in 0..iterations {
+        let _ = std::hint::black_box(run_vm(std::hint::black_box(&code), env));
+    }
+    Ok(Timing {
+        iterations,
+        tree,
+        vm: start.elapsed(),
+    })
+}
+fn pop(stack: &mut Vec<Value>) -> Value {
+    stack
+        .pop()
+        .expect("compiled code never underflows the stack")
+}
+// Integer-only evaluation in `i128`, so intermediate results may leave the
+// `i64` range as long as no `i128` operation overflows:
+// `(10000000000 * 10000000000) / 10000000000` is 10000000000. `narrow`
+// range-checks the final result back into an `i64`.
+#[allow(dead_code)]
+fn evaluate_wide(ast: &AST) -> Result<i128, CalcError> {
+    match ast {
+        AST::Number(n, _) => Ok(*n as i128),
+        AST::Float(x) => Err(CalcError::NotAnInteger(*x)),
+        AST::Variable(name) => Err(CalcError::UndefinedVariable(name.to_string())),
+        AST::UnaryOp { op, operand } => {
+            let value = evaluate_wide(operand)?;
+            match op {
+                '-' => value.checked_neg().ok_or(CalcError::Overflow),
+                '|' => value.checked_abs().ok_or(CalcError::Overflow),
+                _ => Err(CalcError::UnknownOperator(*op)),
+            }
+        }
+        AST::BinaryOp {
+            op,
+            left,
+            right,
+            span,
+        } => {
+            let left = evaluate_wide(left)?;
+            let right = evaluate_wide(right)?;
+            let result = match op {
+                '+' => left.checked_add(right),
+                '-' => left.checked_sub(right),
+                '*' => left.checked_mul(right),
+                '/' | '%' if right == 0 => return Err(CalcError::DivisionByZero(*span)),
+                '/' => left.checked_div(right),
+                '%' => left.checked_rem(right),
+                '^' if right < 0 => {
+                    let exponent = Integer::from_big(BigInt::from_i128(right));
+                    return Err(CalcError::NegativeExponent(exponent));
+                }
+                '^' if right > u32::MAX as i128 => None,
+                '^' => left.checked_pow(right as u32),
+                _ if is_comparison(*op) => Some(comparison_holds(*op, left.cmp(&right)) as i128),
+                _ => return Err(CalcError::UnknownOperator(*op)),
+            };
+            result.ok_or(CalcError::Overflow)
+        }
+        AST::Call { name, args } if name.is("if") && args.len() == 3 => {
+            if evaluate_wide(&args[0])? != 0 {
+                evaluate_wide(&args[1])
+            } else {
+                evaluate_wide(&args[2])
+            }
+        }
+        AST::Call { name, args } => {
+            let args = args
+                .iter()
+                .map(|arg| Ok(AST::num(narrow(evaluate_wide(arg)?)?)))
+                .collect::<Result<Vec<AST>, CalcError>>()?;
+            Ok(evaluate(&AST::Call { name: *name, args })? as i128)
+        }
+        AST::Grouped(inner) => evaluate_wide(inner),
+    }
+}
+fn narrow(value: i128) -> Result<i64, CalcError> {
+    if value < i64::MIN as i128 || value > i64::MAX as i128 {
+        return Err(CalcError::Overflow);
+    }
+    Ok(value as i64)
+}
+// `BigInt` results are capped at this many bits (about 9900 decimal
+// digits), past which the operation reports `CalcError::Overflow`.
+const MAX_BIG_BITS: usize = 32_768;
+// Integer-only evaluation that never overflows, since the evaluator moves
+// a result that doesn't fit an `i64` to a `BigInt`: `2 ^ 100` is
+// 1267650600228229401496703205376. A float or fraction is `NotAnInteger`.
+#[allow(dead_code)]
+fn evaluate_exact(ast: &AST) -> Result<Integer, CalcError> {
+    let value = evaluate_value(ast, &Env::new(), &EvalOptions::default(), &Rng::from_time())?;
+    value
+        .as_integer()
+        .ok_or_else(|| CalcError::NotAnInteger(value.as_f64()))
+}
+// Tries the operation in `i64` first, redoing it on `BigInt`s only when
+// that overflows.
+fn integer_binary(
+    op: char,
+    left: Integer,
+    right: Integer,
+    span: Span,
+) -> Result<Integer, CalcError> {
+    if let (&Integer::Small(a), &Integer::Small(b)) = (&left, &right) {
+        let small = match op {
+            '+' => a.checked_add(b),
+            '-' => a.checked_sub(b),
+            '*' => a.checked_mul(b),
+            '/' | '%' if b == 0 => return Err(CalcError::DivisionByZero(span)),
+            '/' => a.checked_div(b),
+            '%' => a.checked_rem(b),
+            '^' if b < 0 => return Err(CalcError::NegativeExponent(Integer::Small(b))),
+            '^' => checked_power(a, b as u64),
+            _ if is_comparison(op) => Some(comparison_holds(op, a.cmp(&b)) as i64),
+            _ => return Err(CalcError::UnknownOperator(op)),
+        };
+        if let Some(n) = small {
+            return Ok(Integer::Small(n));
+        }
+    }
+    let (a, b) = (left.to_big(), right.to_big());
+    let result = match op {
+        '+' => a.add(&b),
+        '-' => a.add(&b.negated()),
+        '*' => a.mul(&b),
+        '/' | '%' if b.digits.is_empty() => return Err(CalcError::DivisionByZero(span)),
+        '/' => a.div_rem(&b).0,
+        '%' => a.div_rem(&b).1,
+        '^' if b.negative => return Err(CalcError::NegativeExponent(Integer::Big(b))),
+        '^' => a.pow(&b)?,
+        _ if is_comparison(op) => BigInt::from_i64(comparison_holds(op, a.cmp(&b)) as i64),
+        _ => return Err(CalcError::UnknownOperator(op)),
+    };
+    if result.bits() > MAX_BIG_BITS {
+        return Err(CalcError::Overflow);
+    }
+    Ok(Integer::from_big(result))
+}
+impl Integer {
+    fn to_big(&self) -> BigInt {
+        match self {
+            Integer::Small(n) => BigInt::from_i64(*n),
+            Integer::Big(n) => n.clone(),
+        }
+    }
+    fn from_big(n: BigInt) -> Integer {
+        match n.to_i64() {
+            Some(small) => Integer::Small(small),
+            None => Integer::Big(n),
+        }
+    }
+}
+impl BigInt {
+    fn new(negative: bool, mut digits: Vec<u32>) -> BigInt {
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+        BigInt {
+            negative: negative && !digits.is_empty(),
+            digits,
+        }
+    }
+    fn from_i64(n: i64) -> BigInt {
+        BigInt::from_i128(n as i128)
+    }
+    fn from_i128(n: i128) -> BigInt {
+        let magnitude = n.unsigned_abs();
+        let digits = (0..4).map(|i| (magnitude >> (32 * i)) as u32).collect();
+        BigInt::new(n < 0, digits)
+    }
+    fn to_i64(&self) -> Option<i64> {
+        if self.digits.len() > 2 {
+            return None;
+        }
+        let magnitude = self
+            .digits
+            .iter()
+            .rev()
+            .fold(0u64, |total, &digit| total << 32 | digit as u64);
+        match self.negative {
+            false if magnitude <= i64::MAX as u64 => Some(magnitude as i64),
+            true if magnitude <= i64::MIN.unsigned_abs() => Some((magnitude as i64).wrapping_neg()),
+            _ => None,
+        }
+    }
+    fn to_f64(&self) -> f64 {
+        let magnitude = self
+            .digits
+            .iter()
+            .rev()
+            .fold(0.0, |total, &digit| total * 4_294_967_296.0 + digit as f64);
+        if self.negative {
+            -magnitude
+        } else {
+            magnitude
+        }
+    }
+    fn bits(&self) -> usize {
+        match self.digits.last() {
+            Some(top) => self.digits.len() * 32 - top.leading_zeros() as usize,
+            None => 0,
+        }
+    }
+    fn negated(&self) -> BigInt {
+        BigInt::new(!self.negative, self.digits.clone())
+    }
+    fn add(&self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::new(self.negative, add_magnitudes(&self.digits, &other.digits));
+        }
+        match compare_magnitudes(&self.digits, &other.digits) {
+            std::cmp::Ordering::Less => {
+                BigInt::new(other.negative, sub_magnitudes(&other.digits, &self.digits))
+            }
+            _ => BigInt::new(self.negative, sub_magnitudes(&self.digits, &other.digits)),
+        }
+    }
+    fn mul(&self, other: &BigInt) -> BigInt {
+        BigInt::new(
+            self.negative != other.negative,
+            mul_magnitudes(&self.digits, &other.digits),
+        )
+    }
+    // Truncating division, like `i64`: the remainder takes the dividend's
+    // sign. `other` must be nonzero.
+    fn div_rem(&self, other: &BigInt) -> (BigInt, BigInt) {
+        let (quotient, remainder) = div_rem_magnitudes(&self.digits, &other.digits);
+        (
+            BigInt::new(self.negative != other.negative, quotient),
+            BigInt::new(self.negative, remainder),
+        )
+    }
+    // Square-and-multiply, giving up once the result would pass
+    // `MAX_BIG_BITS`. Bases 0, 1 and -1 take any nonnegative exponent.
+    fn pow(&self, exponent: &BigInt) -> Result<BigInt, CalcError> {
+        if self.bits() <= 1 {
+            let odd = exponent.digits.first().is_some_and(|digit| digit & 1 == 1);
+            return Ok(match (exponent.digits.is_empty(), self.negative && !odd) {
+                (true, _) => BigInt::from_i64(1),
+                (false, true) => self.negated(),
+                (false, false) => self.clone(),
+            });
+        }
+        let exponent = match exponent.to_i64() {
+            Some(n) if n as u64 <= MAX_BIG_BITS as u64 => n as u32,
+            _ => return Err(CalcError::Overflow),
+        };
+        let mut result = BigInt::from_i64(1);
+        let mut base = self.clone();
+        let mut rest = exponent;
+        while rest > 0 {
+            if rest & 1 == 1 {
+                result = result.mul(&base);
+            }
+            rest >>= 1;
+            if rest > 0 {
+                base = base.mul(&base);
+            }
+            if result.bits() > MAX_BIG_BITS || base.bits() > MAX_BIG_BITS {
+                return Err(CalcError::Overflow);
+            }
+        }
+        Ok(result)
+    }
+    fn cmp(&self, other: &BigInt) -> std::cmp::Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => std::cmp::Ordering::Greater,
+            (true, false) => std::cmp::Ordering::Less,
+            (false, false) => compare_magnitudes(&self.digits, &other.digits),
+            (true, true) => compare_magnitudes(&other.digits, &self.digits),
+        }
+    }
+}
+fn compare_magnitudes(a: &[u32], b: &[u32]) -> std::cmp::Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+fn add_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut sum = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0u64;
+    for i in 0..a.len().max(b.len()) {
+        let total = *a.get(i).unwrap_or(&0) as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        sum.push(total as u32);
+        carry = total >> 32;
+    }
+    sum.push(carry as u32);
+    sum
+}
+// `a - b`, where `a` is at least `b`.
+fn sub_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut difference = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for (i, &digit) in a.iter().enumerate() {
+        let mut total = digit as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = (total < 0) as i64;
+        total += borrow << 32;
+        difference.push(total as u32);
+    }
+    difference
+}
+fn mul_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut product = vec![0u32; a.len() + b.len()];
+    for (i, &x) in a.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, &y) in b.iter().enumerate() {
+            let total = product[i + j] as u64 + x as u64 * y as u64 + carry;
+            product[i + j] = total as u32;
+            carry = total >> 32;
+        }
+        product[i + b.len()] = carry as u32;
+    }
+    product
+}
+// Divides by a single digit, which is all `Display` needs.
+fn div_small(a: &[u32], divisor: u32) -> (Vec<u32>, u32) {
+    let mut quotient = vec![0u32; a.len()];
+    let mut remainder = 0u64;
+    for (i, &digit) in a.iter().enumerate().rev() {
+        let current = remainder << 32 | digit as u64;
+        quotient[i] = (current / divisor as u64) as u32;
+        remainder = current % divisor as u64;
+    }
+    while quotient.last() == Some(&0) {
+        quotient.pop();
+    }
+    (quotient, remainder as u32)
+}
+// Binary long division, one bit of `a` at a time; slow for huge operands,
+// but `MAX_BIG_BITS` keeps them small.
+fn div_rem_magnitudes(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if let [divisor] = b {
+        let (quotient, remainder) = div_small(a, *divisor);
+        return (quotient, vec![remainder]);
+    }
+    let mut quotient = vec![0u32; a.len()];
+    let mut remainder: Vec<u32> = Vec::new();
+    for bit in (0..a.len() * 32).rev() {
+        let mut carry = (a[bit / 32] >> (bit % 32)) & 1;
+        for digit in remainder.iter_mut() {
+            let shifted = *digit >> 31;
+            *digit = *digit << 1 | carry;
+            carry = shifted;
+        }
+        if carry != 0 {
+            remainder.push(carry);
+        }
+        if compare_magnitudes(&remainder, b) != std::cmp::Ordering::Less {
+            remainder = sub_magnitudes(&remainder, b);
+            while remainder.last() == Some(&0) {
+                remainder.pop();
+            }
+            quotient[bit / 32] |= 1 << (bit % 32);
+        }
+    }
+    (quotient, remainder)
+}
+// Evaluates a subtree of literals down to a single literal. A `rand` call
+// has no constant value, so a tree holding one is returned as it is.
+fn fold_constant(ast: &AST) -> Result<AST, CalcError> {
+    if calls_rand(ast) {
+        return Ok(ast.clone());
+    }
+    fold_constant_with(ast, &EvalOptions::default(), &Rng::seeded(0))
+}
+fn calls_rand(ast: &AST) -> bool {
+    match ast {
+        AST::Number(..) | AST::Float(_) | AST::Variable(_) => false,
+        AST::UnaryOp { operand, .. } => calls_rand(operand),
+        AST::BinaryOp { left, right, .. } => calls_rand(left) || calls_rand(right),
+        AST::Call { name, args } => name.is("rand") || args.iter().any(calls_rand),
+        AST::Grouped(inner) => calls_rand(inner),
+    }
+}
+fn fold_constant_with(ast: &AST, options: &EvalOptions, rng: &Rng) -> Result<AST, CalcError> {
+    evaluate_value(ast, &Env::new(), options, rng).and_then(AST::value)
+}
+// Folds every subtree whose variables are all bound in `env`, leaving the
+// rest symbolic: with `x` unbound, `2 + 3 + x` becomes `5 + x`.
+#[allow(dead_code)]
+fn partial_eval(ast: &AST, env: &Env) -> AST {
+    fold(ast, &mut ConstantFolder { env })
+}
+// Folds negation into literals, so `-5` becomes `Number(-5)` rather than a
+// `UnaryOp` around `Number(5)`. Double negation cancels: `--5` is `5` and
+// `--x` is `x`.
+#[allow(dead_code)]
+fn simplify(ast: &AST) -> AST {
+    fold(ast, &mut Simplifier)
+}
+fn fold<F: Fold>(ast: &AST, folder: &mut F) -> F::Output {
+    match ast {
+        AST::Number(n, radix) => folder.number(*n, *radix),
+        AST::Float(x) => folder.float(*x),
+        AST::Variable(name) => folder.variable(*name),
+        AST::UnaryOp { op, operand } => {
+            let operand = fold(operand, folder);
+            folder.unary(*op, operand)
+        }
+        AST::BinaryOp {
+            op,
+            left,
+            right,
+            span,
+        } => {
+            let left = fold(left, folder);
+            let right = fold(right, folder);
+            folder.binary(*op, left, right, *span)
+        }
+        AST::Call { name, args } => {
+            let args = args.iter().map(|arg| fold(arg, folder)).collect();
+            folder.call(*name, args)
+        }
+        AST::Grouped(inner) => fold(inner, folder),
+    }
+}
+impl Fold for ConstantFolder<'_> {
+    type Output = AST;
+    fn number(&mut self, n: i64, radix: u32) -> AST {
+        AST::Number(n, radix)
+    }
+    fn float(&mut self, x: f64) -> AST {
+        AST::Float(x)
+    }
+    fn variable(&mut self, name: Symbol) -> AST {
+        match self.env.get(&name).cloned().map(AST::value) {
+            Some(Ok(value)) => value,
+            _ => AST::Variable(name),
+        }
+    }
+    fn unary(&mut self, op: char, operand: AST) -> AST {
+        let constant = operand.is_literal();
+        fold_if(constant, AST::unary(op, operand))
+    }
+    fn binary(&mut self, op: char, left: AST, right: AST, span: Span) -> AST {
+        let constant = left.is_literal() && right.is_literal();
+        let folded = AST::BinaryOp {
+            op,
+            left: Box::new(left),
+            right: Box::new(right),
+            span,
+        };
+        fold_if(constant, folded)
+    }
+    // A constant `if` condition picks its branch even when the other branch
+    // is still symbolic.
+    fn call(&mut self, name: Symbol, mut args: Vec<AST>) -> AST {
+        if name.is("if") && args.len() == 3 && args[0].is_literal() {
+            return if is_zero(&args[0]) {
+                args.swap_remove(2)
+            } else {
+                args.swap_remove(1)
+            };
+        }
+        let constant = !name.is("rand") && args.iter().all(AST::is_literal);
+        fold_if(constant, AST::Call { name, args })
+    }
+}
+// Only a node whose children are all literals is evaluated; an error, like
+// `1/0`, leaves it for evaluation to report.
+fn fold_if(constant: bool, node: AST) -> AST {
+    if !constant {
+        return node;
+    }
+    fold_constant(&node).unwrap_or(node)
+}
+impl Fold for Simplifier {
+    type Output = AST;
+    fn number(&mut self, n: i64, radix: u32) -> AST {
+        AST::Number(n, radix)
+    }
+    fn float(&mut self, x: f64) -> AST {
+        AST::Float(x)
+    }
+    fn variable(&mut self, name: Symbol) -> AST {
+        AST::Variable(name)
+    }
+    fn unary(&mut self, op: char, operand: AST) -> AST {
+        match (op, operand) {
+            ('-', AST::Number(n, radix)) if n != i64::MIN => AST::Number(-n, radix),
+            ('-', AST::Float(x)) => AST::Float(-x),
+            ('-', AST::UnaryOp { op: '-', operand }) => *operand,
+            (op, operand) => AST::unary(op, operand),
+        }
+    }
+    fn binary(&mut self, op: char, left: AST, right: AST, span: Span) -> AST {
+        AST::BinaryOp {
+            op,
+            left: Box::new(left),
+            right: Box::new(right),
+            span,
+        }
+    }
+    fn call(&mut self, name: Symbol, args: Vec<AST>) -> AST {
+        AST::Call { name, args }
+    }
+}
+// Passes over the whole tree before `apply_rules` gives up on reaching a
+// fixpoint, for rule sets such as `?a + ?b => ?b + ?a` that never settle.
+const MAX_REWRITE_PASSES: usize = 100;
+// Rewrites `ast` with `rules` until none applies anywhere, or until
+// `MAX_REWRITE_PASSES` passes have run. Each pass works bottom-up, trying the
+// rules in order at every node and taking the first that matches, so with
+// `?a + 0 => ?a`, `(x + 0) + 0` is `x` after one pass.
+#[allow(dead_code)]
+fn apply_rules(ast: AST, rules: &[Rule]) -> AST {
+    let mut ast = ast;
+    for _ in 0..MAX_REWRITE_PASSES {
+        let (rewritten, changed) = rewrite_pass(&ast, rules);
+        ast = rewritten;
+        if !changed {
+            break;
+        }
+    }
+    ast
+}
+// A rewrite that only rebuilds an equal tree doesn't count as a change.
+fn rewrite_pass(ast: &AST, rules: &[Rule]) -> (AST, bool) {
+    let mut changed = false;
+    let node = match ast {
+        AST::UnaryOp { op, operand } => {
+            let (operand, operand_changed) = rewrite_pass(operand, rules);
+            changed |= operand_changed;
+            AST::unary(*op, operand)
+        }
+        AST::BinaryOp {
+            op,
+            left,
+            right,
+            span,
+        } => {
+            let (left, left_changed) = rewrite_pass(left, rules);
+            let (right, right_changed) = rewrite_pass(right, rules);
+            changed |= left_changed || right_changed;
+            AST::BinaryOp {
+                op: *op,
+                left: Box::new(left),
+                right: Box::new(right),
+                span: *span,
+            }
+        }
+        AST::Call { name, args } => {
+            let args = args
+                .iter()
+                .map(|arg| {
+                    let (arg, arg_changed) = rewrite_pass(arg, rules);
+                    changed |= arg_changed;
+                    arg
+                })
+                .collect();
+            AST::Call { name: *name, args }
+        }
+        AST::Grouped(inner) => {
+            let (inner, inner_changed) = rewrite_pass(inner, rules);
+            changed |= inner_changed;
+            AST::Grouped(Box::new(inner))
+        }
+        leaf => leaf.clone(),
+    };
+    for rule in rules {
+        let mut bindings = HashMap::new();
+        if match_pattern(&rule.pattern, &node, &mut bindings) {
+            let rewritten = substitute(&rule.replacement, &bindings);
+            let rewrote = !eq_ignore_spans(&rewritten, &node);
+            return (rewritten, changed || rewrote);
+        }
+    }
+    (node, changed)
+}
+// Groups in `ast` are looked through, so `(x + 0)` matches `?a + 0`.
+fn match_pattern(pattern: &AST, ast: &AST, bindings: &mut HashMap<Symbol, AST>) -> bool {
+    if let AST::Grouped(inner) = ast {
+        return match_pattern(pattern, inner, bindings);
+    }
+    match (pattern, ast) {
+        (AST::Variable(name), _) if name.with_str(|name| name.starts_with('?')) => {
+            if name.with_str(|name| name.starts_with("?#")) && !ast.is_literal() {
+                return false;
+            }
+            match bindings.get(name) {
+                Some(bound) => eq_ignore_spans(bound, ast),
+                None => {
+                    bindings.insert(*name, ast.clone());
+                    true
+                }
+            }
+        }
+        (
+            AST::UnaryOp { op, operand },
+            AST::UnaryOp {
+                op: other_op,
+                operand: other_operand,
+            },
+        ) => op == other_op && match_pattern(operand, other_operand, bindings),
+        (
+            AST::BinaryOp {
+                op, left, right, ..
+            },
+            AST::BinaryOp {
+                op: other_op,
+                left: other_left,
+                right: other_right,
+                ..
+            },
+        ) => {
+            op == other_op
+                && match_pattern(left, other_left, bindings)
+                && match_pattern(right, other_right, bindings)
+        }
+        (
+            AST::Call { name, args },
+            AST::Call {
+                name: other_name,
+                args: other_args,
+            },
+        ) => {
+            name == other_name
+                && args.len() == other_args.len()
+                && args
+                    .iter()
+                    .zip(other_args)
+                    .all(|(arg, other)| match_pattern(arg, other, bindings))
+        }
+        (AST::Grouped(inner), _) => match_pattern(inner, ast, bindings),
+        _ => eq_ignore_spans(pattern, ast),
+    }
+}
+fn substitute(replacement: &AST, bindings: &HashMap<Symbol, AST>) -> AST {
+    match replacement {
+        AST::Variable(name) => bindings
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| replacement.clone()),
+        AST::UnaryOp { op, operand } => AST::unary(*op, substitute(operand, bindings)),
+        AST::BinaryOp {
+            op, left, right, ..
+        } => AST::binary(*op, substitute(left, bindings), substitute(right, bindings)),
+        AST::Call { name, args } => AST::Call {
+            name: *name,
+            args: args.iter().map(|arg| substitute(arg, bindings)).collect(),
+        },
+        AST::Grouped(inner) => AST::Grouped(Box::new(substitute(inner, bindings))),
+        literal => literal.clone(),
+    }
+}
+#[allow(dead_code)]
+impl Rule {
+    // `Rule::parse("?a + 0", "?a")`. Every wildcard in the replacement must
+    // also be in the pattern.
+    fn parse(pattern: &str, replacement: &str) -> Result<Rule, CalcError> {
+        let pattern = parse_rule_side(pattern)?;
+        let replacement = parse_rule_side(replacement)?;
+        let bound = free_variables(&pattern);
+        if let Some(name) = free_variables(&replacement)
+            .into_iter()
+            .find(|name| name.starts_with('?') && !bound.contains(name))
+        {
+            return Err(CalcError::UnboundWildcard(name));
+        }
+        Ok(Rule {
+            pattern,
+            replacement,
+        })
+    }
+}
+// Wildcards are variables named with a leading `?`; the lexer only takes
+// such names in backticks, so `?a` is quoted to `` `?a` `` first.
+fn parse_rule_side(side: &str) -> Result<AST, CalcError> {
+    let mut quoted = String::new();
+    let mut chars = side.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '?' {
+            quoted.push(c);
+            continue;
+        }
+        quoted.push_str("`?");
+        if let Some(hash) = chars.next_if_eq(&'#') {
+            quoted.push(hash);
+        }
+        while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+            quoted.push(c);
+        }
+        quoted.push('`');
+    }
+    parse_spanned(&lex_spanned(&quoted)?)
+}
+// Reduces the leftmost-innermost variable lookup or operation whose operands
+// are all numbers, returning `None` once `ast` is a single number.
+#[allow(dead_code)]
+fn small_step(ast: &AST, env: &Env) -> Result<Option<AST>, CalcError> {
+    small_step_with(ast, env, &EvalOptions::default(), &Rng::from_time())
+}
+// `small_step` with the arithmetic `options` and `rng` of an evaluation.
+fn small_step_with(
+    ast: &AST,
+    env: &Env,
+    options: &EvalOptions,
+    rng: &Rng,
+) -> Result<Option<AST>, CalcError> {
+    let step = |ast: &AST| small_step_with(ast, env, options, rng);
+    let fold = |ast: &AST| fold_constant_with(ast, options, rng).map(Some);
+    match ast {
+        AST::Number(..) | AST::Float(_) => Ok(None),
+        AST::Variable(name) => match env.get(name) {
+            Some(value) => AST::value(value.clone()).map(Some),
+            None => Err(CalcError::UndefinedVariable(name.to_string())),
+        },
+        AST::UnaryOp { op, operand } => match step(operand)? {
+            Some(stepped) => Ok(Some(AST::unary(*op, stepped))),
+            None => fold(ast),
+        },
+        AST::BinaryOp {
+            op,
+            left,
+            right,
+            span,
+        } => {
+            if left.is_literal() && right.is_literal() {
+                return fold(ast);
+            }
+            let (left, right) = match step(left)? {
+                Some(stepped) => (stepped, right.as_ref().clone()),
+                None => (left.as_ref().clone(), step(right)?.unwrap()),
+            };
+            Ok(Some(AST::BinaryOp {
+                op: *op,
+                left: Box::new(left),
+                right: Box::new(right),
+                span: *span,
+            }))
+        }
+        AST::Call { name, args } if name.is("if") && args.len() == 3 => match step(&args[0])? {
+            Some(cond) => {
+                let mut args = args.clone();
+                args[0] = cond;
+                Ok(Some(AST::Call { name: *name, args }))
+            }
+            None if !is_zero(&args[0]) => Ok(Some(args[1].clone())),
+            None => Ok(Some(args[2].clone())),
+        },
+        AST::Call { name, args } => {
+            let mut args = args.clone();
+            for arg in args.iter_mut() {
+                if let Some(stepped) = step(arg)? {
+                    *arg = stepped;
+                    return Ok(Some(AST::Call { name: *name, args }));
+                }
+            }
+            fold(ast)
+        }
+        AST::Grouped(inner) => match step(inner)? {
+            Some(stepped) => Ok(Some(AST::Grouped(Box::new(stepped)))),
+            None => Ok(Some(inner.as_ref().clone())),
+        },
+    }
+}
+// A test oracle for symbolic rewrites: evaluates both trees at `samples`
+// random values of `var` and checks they agree. Samples where either side
+// fails to evaluate, such as a division by zero, are skipped. The values
+// come from a fixed seed, so the answer is reproducible.
+#[allow(dead_code)]
+fn probably_equivalent(a: &AST, b: &AST, var: &str, samples: usize) -> bool {
+    let rng = Rng::seeded(samples as u64);
+    let options = EvalOptions::default();
+    let var = Symbol::intern(var);
+    (0..samples).all(|_| {
+        let mut env = Env::new();
+        env.insert(var, Value::Int(rng.range(-1000, 1000).unwrap()));
+        match (
+            evaluate_value(a, &env, &options, &rng),
+            evaluate_value(b, &env, &options, &rng),
+        ) {
+            (Ok(left), Ok(right)) => left == right,
+            _ => true,
+        }
+    })
+}
+// Solves `lhs = rhs` for `var` when both sides are linear in it:
+// `solve_equation("2*x + 3 = 11", "x")` is 4. Whole-number solutions come
+// back as integers.
+#[allow(dead_code)]
+fn solve_equation(equation: &str, var: &str) -> Result<Value, CalcError> {
+    let tokens = lex_spanned(equation)?;
+    let (lhs, rhs) = parse_equation(&tokens, &ParseOptions::default())?;
+    solve(&lhs, &rhs, Symbol::intern(var), &Env::new())
+}
+fn parse_equation(
+    tokens: &[(Token, Span)],
+    options: &ParseOptions,
+) -> Result<(AST, AST), CalcError> {
+    let equals = tokens
+        .iter()
+        .position(|(token, _)| *token == Token::Assign)
+        .ok_or(CalcError::NotAnEquation)?;
+    let lhs = parse_spanned_with(&tokens[..equals], options)?;
+    let rhs = parse_spanned_with(&tokens[equals + 1..], options)?;
+    Ok((lhs, rhs))
+}
+// Other variables are looked up in `env`.
+fn solve(lhs: &AST, rhs: &AST, var: Symbol, env: &Env) -> Result<Value, CalcError> {
+    let (lhs_slope, lhs_offset) = linear(lhs, var, env)?;
+    let (rhs_slope, rhs_offset) = linear(rhs, var, env)?;
+    let slope = lhs_slope - rhs_slope;
+    if slope == 0.0 {
+        return Err(CalcError::NoUniqueSolution(var.to_string()));
+    }
+    let x = (rhs_offset - lhs_offset) / slope;
+    if x.fract() == 0.0 && (i64::MIN as f64..i64::MAX as f64).contains(&x) {
+        Ok(Value::Int(x as i64))
+    } else {
+        Ok(Value::Float(x))
+    }
+}
+// `ast` as `(slope, offset)`, meaning `slope * var + offset`. Subtrees
+// without `var` are evaluated to constants; `var` may only be negated,
+// added, subtracted, scaled by a constant or divided by one.
+fn linear(ast: &AST, var: Symbol, env: &Env) -> Result<(f64, f64), CalcError> {
+    if !mentions(ast, var) {
+        let value = evaluate_value(ast, env, &EvalOptions::default(), &Rng::from_time())?;
+        return Ok((0.0, value.as_f64()));
+    }
+    let not_linear = || CalcError::NotLinear {
+        var: var.to_string(),
+        term: ast.to_string(),
+    };
+    match ast {
+        AST::Variable(_) => Ok((1.0, 0.0)),
+        AST::UnaryOp { op: '-', operand } => {
+            let (slope, offset) = linear(operand, var, env)?;
+            Ok((-slope, -offset))
+        }
+        AST::BinaryOp {
+            op,
+            left,
+            right,
+            span,
+        } => {
+            let (a, b) = linear(left, var, env)?;
+            let (c, d) = linear(right, var, env)?;
+            match op {
+                '+' => Ok((a + c, b + d)),
+                '-' => Ok((a - c, b - d)),
+                '*' if a == 0.0 => Ok((b * c, b * d)),
+                '*' if c == 0.0 => Ok((a * d, b * d)),
+                '/' if c == 0.0 && d == 0.0 => Err(CalcError::DivisionByZero(*span)),
+                '/' if c == 0.0 => Ok((a / d, b / d)),
+                _ => Err(not_linear()),
+            }
+        }
+        AST::Grouped(inner) => linear(inner, var, env),
+        _ => Err(not_linear()),
+    }
+}
+// Every variable name `ast` reads, so a caller knows what to bind before
+// evaluating it. Function names aren't variables.
+#[allow(dead_code)]
+fn free_variables(ast: &AST) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+    collect_variables(ast, &mut names);
+    names
+}
+// Checks every variable up front, reporting all the unbound ones rather
+// than only the first that evaluation would trip over.
+#[allow(dead_code)]
+fn check_defined(ast: &AST, env: &Env) -> Result<(), Vec<String>> {
+    let missing: Vec<String> = free_variables(ast)
+        .into_iter()
+        .filter(|name| !env.contains_key(&Symbol::intern(name)))
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(missing)
+    }
+}
+fn collect_variables(ast: &AST, names: &mut BTreeSet<String>) {
+    match ast {
+        AST::Number(..) | AST::Float(_) => {}
+        AST::Variable(name) => {
+            names.insert(name.to_string());
+        }
+        AST::UnaryOp { operand, .. } => collect_variables(operand, names),
+        AST::BinaryOp { left, right, .. } => {
+            collect_variables(left, names);
+            collect_variables(right, names);
+        }
+        AST::Call { args, .. } => {
+            for arg in args {
+                collect_variables(arg, names);
+            }
+        }
+        AST::Grouped(inner) => collect_variables(inner, names),
+    }
+}
+fn mentions(ast: &AST, var: Symbol) -> bool {
+    match ast {
+        AST::Number(..) | AST::Float(_) => false,
+        AST::Variable(name) => *name == var,
+        AST::UnaryOp { operand, .. } => mentions(operand, var),
+        AST::BinaryOp { left, right, .. } => mentions(left, var) || mentions(right, var),
+        AST::Call { args, .. } => args.iter().any(|arg| mentions(arg, var)),
+        AST::Grouped(inner) => mentions(inner, var),
+    }
+}
+// Evaluates `input` with unit suffixes, checking that the dimensions agree:
+// `5m + 2m` is `7 m`, `5m * 2s` is `10 m*s` and `5m + 2s` is an error.
+// An identifier written straight after a number, or one `env` doesn't bind,
+// is a base unit. A unit is never split into a magnitude suffix, so `5kg`
+// is `5 kg` and `3km` is `3 km`, but `2k * 3m` is still `6000 m`.
+#[allow(dead_code)]
+fn evaluate_units(input: &str, env: &Env) -> Result<Quantity, CalcError> {
+    let tokens = attach_units(lex_spanned(input)?);
+    let ast = parse_spanned(&tokens)?;
+    eval_units(&ast, env)
+}
+// Rewrites `5 m` (with no space) to `(5 * m)`, so the unit stays with its
+// number: `10m / 2s` is `(10 * m) / (2 * s)`.
+fn attach_units(tokens: Vec<(Token, Span)>) -> Vec<(Token, Span)> {
+    let mut result = Vec::with_capacity(tokens.len());
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some((token, span)) = tokens.next() {
+        let is_number = matches!(token, Token::Number(..) | Token::Float(_));
+        let unit = tokens.next_if(|(next, next_span)| {
+            is_number && matches!(next, Token::Identifier(_)) && next_span.start == span.end
+        });
+        match unit {
+            Some((unit, unit_span)) => result.extend([
+                (Token::LeftParen, Span::at(span.start)),
+                (token, span),
+                (Token::Operator('*'), Span::at(span.end)),
+                (unit, unit_span),
+                (Token::RightParen, Span::at(unit_span.end)),
+            ]),
+            None => result.push((token, span)),
+        }
+    }
+    result
+}
+fn eval_units(ast: &AST, env: &Env) -> Result<Quantity, CalcError> {
+    let plain = |value: f64| Quantity {
+        value,
+        units: Units::new(),
+    };
+    match ast {
+        AST::Number(n, _) => Ok(plain(*n as f64)),
+        AST::Float(x) => Ok(plain(*x)),
+        AST::Variable(name) => match env.get(name) {
+            Some(value) => Ok(plain(value.as_f64())),
+            None => Ok(Quantity {
+                value: 1.0,
+                units: Units::from([(*name, 1)]),
+            }),
+        },
+        AST::UnaryOp { op, operand } => {
+            let mut quantity = eval_units(operand, env)?;
+            quantity.value = match op {
+                '-' => -quantity.value,
+                '|' => quantity.value.abs(),
+                _ => return Err(CalcError::UnknownOperator(*op)),
+            };
+            Ok(quantity)
+        }
+        AST::BinaryOp {
+            op,
+            left,
+            right,
+            span,
+        } => {
+            let left = eval_units(left, env)?;
+            let right = eval_units(right, env)?;
+            match op {
+                '*' | '/' => {
+                    if *op == '/' && right.value == 0.0 {
+                        return Err(CalcError::DivisionByZero(*span));
+                    }
+                    let sign = if *op == '*' { 1 } else { -1 };
+                    let mut units = left.units;
+                    for (unit, power) in right.units {
+                        *units.entry(unit).or_insert(0) += sign * power;
+                    }
+                    units.retain(|_, power| *power != 0);
+                    Ok(Quantity {
+                        value: float_binary(*op, left.value, right.value)?,
+                        units,
+                    })
+                }
+                '^' => {
+                    if !right.units.is_empty() {
+                        return Err(CalcError::UnitsNotAllowed("an exponent".to_string()));
+                    }
+                    let mut units = left.units;
+                    if !units.is_empty() {
+                        let power = Value::Float(right.value).as_int()?;
+                        for exponent in units.values_mut() {
+                            *exponent = exponent.checked_mul(power).ok_or(CalcError::Overflow)?;
+                        }
+                        units.retain(|_, power| *power != 0);
+                    }
+                    Ok(Quantity {
+                        value: left.value.powf(right.value),
+                        units,
+                    })
+                }
+                _ if left.units != right.units => Err(CalcError::IncompatibleUnits {
+                    left: format_units(&left.units),
+                    right: format_units(&right.units),
+                }),
+                _ if is_comparison(*op) => {
+                    let holds = left
+                        .value
+                        .partial_cmp(&right.value)
+                        .map_or(*op == '≠', |ordering| comparison_holds(*op, ordering));
+                    Ok(plain(holds as i64 as f64))
+                }
+                _ => Ok(Quantity {
+                    value: float_binary(*op, left.value, right.value)?,
+                    units: left.units,
+                }),
+            }
+        }
+        AST::Call { name, args } => {
+            let args = args
+                .iter()
+                .map(|arg| eval_units(arg, env))
+                .collect::<Result<Vec<Quantity>, CalcError>>()?;
+            if let [arg] = args.as_slice() {
+                if name.is("abs") {
+                    return Ok(Quantity {
+                        value: arg.value.abs(),
+                        units: arg.units.clone(),
+                    });
+                }
+            }
+            if args.iter().any(|arg| !arg.units.is_empty()) {
+                return Err(CalcError::UnitsNotAllowed(format!("{}()", name)));
+            }
+            let call = AST::Call {
+                name: *name,
+                args: args.iter().map(|arg| AST::Float(arg.value)).collect(),
+            };
+            let value = evaluate_value(&call, env, &EvalOptions::default(), &Rng::from_time())?;
+            Ok(plain(value.as_f64()))
+        }
+        AST::Grouped(inner) => eval_units(inner, env),
+    }
+}
+// Positive powers first, then the negative ones after a `/`: `m*s`, `m/s^2`,
+// `kg/(m*s)`, or `1/s` with nothing above the line.
+fn format_units(units: &Units) -> String {
+    if units.is_empty() {
+        return "dimensionless".to_string();
+    }
+    let join = |positive: bool| {
+        units
+            .iter()
+            .filter(|(_, power)| (**power > 0) == positive)
+            .map(|(unit, power)| match power.abs() {
+                1 => unit.to_string(),
+                n => format!("{}^{}", unit, n),
+            })
+            .collect::<Vec<String>>()
+    };
+    let (above, below) = (join(true), join(false));
+    let above = if above.is_empty() {
+        "1".to_string()
+    } else {
+        above.join("*")
+    };
+    match below.len() {
+        0 => above,
+        1 => format!("{}/{}", above, below[0]),
+        _ => format!("{}/({})", above, below.join("*")),
+    }
+}
+// The subtree `path` leads to; the empty path is `ast` itself.
+#[allow(dead_code)]
+fn get_at<'a>(ast: &'a AST, path: &[PathStep]) -> Result<&'a AST, CalcError> {
+    path.iter()
+        .enumerate()
+        .try_fold(ast, |node, (depth, step)| {
+            child(node, *step).ok_or(CalcError::InvalidPath(depth))
+        })
+}
+// A copy of `ast` with the subtree at `path` swapped for `new`.
+#[allow(dead_code)]
+fn replace_at(ast: &AST, path: &[PathStep], new: AST) -> Result<AST, CalcError> {
+    let mut result = ast.clone();
+    let mut node = &mut result;
+    for (depth, step) in path.iter().enumerate() {
+        node = child_mut(node, *step).ok_or(CalcError::InvalidPath(depth))?;
+    }
+    *node = new;
+    Ok(result)
+}
+fn child(ast: &AST, step: PathStep) -> Option<&AST> {
+    match (ast, step) {
+        (AST::BinaryOp { left, .. }, PathStep::Left) => Some(left),
+        (AST::BinaryOp { right, .. }, PathStep::Right) => Some(right),
+        (AST::UnaryOp { operand, .. }, PathStep::Operand) => Some(operand),
+        (AST::Call { args, .. }, PathStep::Arg(i)) => args.get(i),
+        (AST::Grouped(inner), PathStep::Inner) => Some(inner),
+        _ => None,
+    }
+}
+fn child_mut(ast: &mut AST, step: PathStep) -> Option<&mut AST> {
+    match (ast, step) {
+        (AST::BinaryOp { left, .. }, PathStep::Left) => Some(left),
+        (AST::BinaryOp { right, .. }, PathStep::Right) => Some(right),
+        (AST::UnaryOp { operand, .. }, PathStep::Operand) => Some(operand),
+        (AST::Call { args, .. }, PathStep::Arg(i)) => args.get_mut(i),
+        (AST::Grouped(inner), PathStep::Inner) => Some(inner),
+        _ => None,
+    }
+}
+// Structural equality that ignores source positions, for comparing a parsed
+// tree against one built by hand.
+#[allow(dead_code)]
+fn eq_ignore_spans(a: &AST, b: &AST) -> bool {
+    match (a, b) {
+        (
+            AST::UnaryOp { op, operand },
+            AST::UnaryOp {
+                op: other_op,
+                operand: other_operand,
+            },
+        ) => op == other_op && eq_ignore_spans(operand, other_operand),
+        (
+            AST::BinaryOp {
+                op, left, right, ..
+            },
+            AST::BinaryOp {
+                op: other_op,
+                left: other_left,
+                right: other_right,
+                ..
+            },
+        ) => {
+            op == other_op
+                && eq_ignore_spans(left, other_left)
+                && eq_ignore_spans(right, other_right)
+        }
+        (
+            AST::Call { name, args },
+            AST::Call {
+                name: other_name,
+                args: other_args,
+            },
+        ) => {
+            name == other_name
+                && args.len() == other_args.len()
+                && args
+                    .iter()
+                    .zip(other_args)
+                    .all(|(a, b)| eq_ignore_spans(a, b))
+        }
+        (AST::Grouped(inner), AST::Grouped(other)) => eq_ignore_spans(inner, other),
+        _ => a == b,
+    }
+}
+// A hash of `ast` that is the same in every run, for caching trees by
+// structure. Trees that `eq_ignore_spans` considers equal get equal
+// fingerprints; names are hashed by spelling, not by interned id.
+#[allow(dead_code)]
+fn fingerprint(ast: &AST) -> u64 {
+    let mut hasher = Fnv1a::new();
+    hash_tree(ast, &mut hasher);
+    std::hash::Hasher::finish(&hasher)
+}
+// Each node starts with a tag byte, so differently shaped trees with the
+// same leaves still differ.
+fn hash_tree(ast: &AST, hasher: &mut Fnv1a) {
+    use std::hash::Hasher;
+    match ast {
+        AST::Number(n, radix) => {
+            hasher.write_u8(0);
+            hasher.write(&n.to_le_bytes());
+            hasher.write(&radix.to_le_bytes());
+        }
+        AST::Float(x) => {
+            hasher.write_u8(1);
+            hasher.write(&x.to_bits().to_le_bytes());
+        }
+        AST::Variable(name) => {
+            hasher.write_u8(2);
+            hash_name(*name, hasher);
+        }
+        AST::UnaryOp { op, operand } => {
+            hasher.write_u8(3);
+            hasher.write(&(*op as u32).to_le_bytes());
+            hash_tree(operand, hasher);
+        }
+        AST::BinaryOp {
+            op, left, right, ..
+        } => {
+            hasher.write_u8(4);
+            hasher.write(&(*op as u32).to_le_bytes());
+            hash_tree(left, hasher);
+            hash_tree(right, hasher);
+        }
+        AST::Call { name, args } => {
+            hasher.write_u8(5);
+            hash_name(*name, hasher);
+            hasher.write(&(args.len() as u64).to_le_bytes());
+            for arg in args {
+                hash_tree(arg, hasher);
+            }
+        }
+        AST::Grouped(inner) => {
+            hasher.write_u8(6);
+            hash_tree(inner, hasher);
+        }
+    }
+}
+// Length-prefixed, so `ab` and `a` followed by `b` can't collide.
+fn hash_name(name: Symbol, hasher: &mut Fnv1a) {
+    use std::hash::Hasher;
+    let text = name.name();
+    hasher.write(&(text.len() as u64).to_le_bytes());
+    hasher.write(text.as_bytes());
+}
+impl Fnv1a {
+    fn new() -> Fnv1a {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+}
+impl std::hash::Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+fn is_zero(literal: &AST) -> bool {
+    match literal {
+        AST::Number(n, _) => *n == 0,
+        AST::Float(x) => *x == 0.0,
+        _ => false,
+    }
+}
+impl Evaluator<'_> {
+    fn eval(&mut self, ast: &AST) -> Result<Value, CalcError> {
+        match ast {
+            AST::Number(n, _) => Ok(self.reduce(Value::Int(*n))),
+            AST::Float(x) => Ok(Value::Float(*x)),
+            AST::Variable(name) => self.lookup(*name).map(|value| self.reduce(value)),
+            AST::UnaryOp { op, operand } => {
+                let value = self.eval(operand)?;
+                self.unary(*op, value)
+            }
+            AST::BinaryOp {
+                op,
+                left,
+                right,
+                span,
+            } => {
+                // Exponents, and the operands of `%`, mean the same under a
+                // modulus as without one, so they aren't reduced.
+                let (left_val, right_val) = match op {
+                    '^' => (self.eval(left)?, self.eval_plain(right)?),
+                    '%' => (self.eval_plain(left)?, self.eval_plain(right)?),
+                    _ => (self.eval(left)?, self.eval(right)?),
+                };
+                self.binary(*op, left_val, right_val, *span)
+            }
+            AST::Call { name, args } => {
+                let value = self.call(*name, args)?;
+                self.fit_width(self.reduce(value))
+            }
+            AST::Grouped(inner) => self.eval(inner),
+        }
+    }
+    // Evaluates `ast` as an ordinary integer even under a modulus.
+    fn eval_plain(&mut self, ast: &AST) -> Result<Value, CalcError> {
+        let modulus = self.modulus.take();
+        let value = self.eval(ast);
+        self.modulus = modulus;
+        value
+    }
+    // Brings an integer into `0..modulus` under `EvalOptions::modulus`.
+    fn reduce(&self, value: Value) -> Value {
+        match (value, self.modulus) {
+            (Value::Int(n), Some(modulus)) => Value::Int(n.rem_euclid(modulus)),
+            (Value::Big(n), Some(modulus)) => {
+                let remainder = n.div_rem(&BigInt::from_i64(modulus)).1;
+                Value::Int(remainder.to_i64().unwrap_or(0).rem_euclid(modulus))
+            }
+            (value, _) => value,
+        }
+    }
+    fn lookup(&mut self, name: Symbol) -> Result<Value, CalcError> {
+        match self.env.get(&name) {
+            Some(value) => Ok(value.clone()),
+            None if self.options.undefined_as_zero => {
+                self.warnings
+                    .push(format!("undefined variable '{}' treated as 0", name));
+                Ok(Value::Int(0))
+            }
+            None => Err(CalcError::UndefinedVariable(name.to_string())),
+        }
+    }
+    fn unary(&mut self, op: char, value: Value) -> Result<Value, CalcError> {
+        self.step()?;
+        match (op, value) {
+            ('-', Value::Int(n)) => match self.modulus {
+                Some(modulus) => Ok(Value::Int((-(n as i128)).rem_euclid(modulus as i128) as i64)),
+                None => self.promoted(int_unary('-', n, self.options.overflow), || {
+                    integer_binary('-', Integer::Small(0), Integer::Small(n), Span::default())
+                }),
+            },
+            ('-', Value::Float(x)) => Ok(Value::Float(-x)),
+            ('-', Value::Rational(numerator, denominator)) => {
+                ratio(-(numerator as i128), denominator as i128)
+            }
+            ('|', Value::Rational(numerator, denominator)) => {
+                ratio((numerator as i128).abs(), denominator as i128)
+            }
+            ('|', Value::Int(n)) => self.promoted(int_unary('|', n, self.options.overflow), || {
+                integer_binary('-', Integer::Small(0), Integer::Small(n), Span::default())
+            }),
+            ('|', Value::Float(x)) => Ok(Value::Float(x.abs())),
+            ('-', Value::Big(n)) => Ok(Value::from_integer(Integer::from_big(n.negated()))),
+            ('|', Value::Big(n)) => Ok(Value::Big(BigInt::new(false, n.digits))),
+            ('-' | '|', Value::Bool(_)) => Err(CalcError::BooleanOperand(op.to_string())),
+            _ => Err(CalcError::UnknownOperator(op)),
+        }
+        .and_then(|value| self.fit_width(value))
+    }
+    fn binary(
+        &mut self,
+        op: char,
+        left_val: Value,
+        right_val: Value,
+        span: Span,
+    ) -> Result<Value, CalcError> {
+        self.step()?;
+        let result = match (&left_val, &right_val) {
+            _ if is_comparison(op) => {
+                let holds = compare(op, &left_val, &right_val)?;
+                if self.options.strict_bools {
+                    Ok(Value::Bool(holds))
+                } else {
+                    Ok(Value::Int(holds as i64))
+                }
+            }
+            (Value::Bool(_), _) | (_, Value::Bool(_)) => {
+                Err(CalcError::BooleanOperand(operator_text(op)))
+            }
+            // A negative power of an integer is a fraction, so it becomes a
+            // float, or an exact fraction under `rationals`.
+            (Value::Int(_) | Value::Big(_), Value::Int(_) | Value::Big(_))
+                if op == '^' && right_val.as_f64() < 0.0 && self.modulus.is_none() =>
+            {
+                if self.options.rationals {
+                    rational_binary(op, &left_val, &right_val, span)
+                } else if left_val.as_f64() == 0.0 {
+                    Err(CalcError::DivisionByZero(span))
+                } else {
+                    Ok(Value::Float(left_val.as_f64().powf(right_val.as_f64())))
+                }
+            }
+            (&Value::Int(a), &Value::Int(b))
+                if op == '/'
+                    && self.options.rationals
+                    && self.modulus.is_none()
+                    && b != 0
+                    && a.checked_rem(b).is_some_and(|rem| rem != 0) =>
+            {
+                ratio(a as i128, b as i128)
+            }
+            (&Value::Int(a), &Value::Int(b)) => match self.modulus {
+                Some(modulus) => modular_binary(op, a, b, modulus, span).map(Value::Int),
+                None => self.promoted(int_binary(op, a, b, span, self.options.overflow), || {
+                    integer_binary(op, Integer::Small(a), Integer::Small(b), span)
+                }),
+            },
+            (Value::Int(_) | Value::Big(_), Value::Int(_) | Value::Big(_)) => {
+                self.big_binary(op, &left_val, &right_val, span)
+            }
+            (Value::Int(_) | Value::Rational(..), Value::Int(_) | Value::Rational(..)) => {
+                rational_binary(op, &left_val, &right_val, span)
+            }
+            (a, b) => {
+                let (a, b) = (a.as_f64(), b.as_f64());
+                let result = float_binary(op, a, b)?;
+                if self.options.float_warnings {
+                    self.warnings.extend(float_warning(op, a, b, result));
+                }
+                Ok(Value::Float(result))
+            }
+        }
+        .and_then(|value| self.fit_width(value))?;
+        debug_log!(
+            "evaluated {} {} {} = {}",
+            left_val,
+            operator_text(op),
+            right_val,
+            result
+        );
+        Ok(result)
+    }
+    // Under the default checked policy and with no `width`, an `i64`
+    // overflow is redone exactly instead of being reported.
+    fn promoted(
+        &self,
+        result: Result<i64, CalcError>,
+        exact: impl FnOnce() -> Result<Integer, CalcError>,
+    ) -> Result<Value, CalcError> {
+        match result {
+            Err(CalcError::Overflow)
+                if self.options.overflow == OverflowPolicy::Checked
+                    && self.options.width.is_none() =>
+            {
+                exact().map(Value::from_integer)
+            }
+            result => result.map(Value::Int),
+        }
+    }
+    // At least one side is a `Big`. Under a modulus that's only an exponent
+    // or an operand of `%`, which `eval` leaves unreduced. Otherwise the
+    // exact quotient can't be a `Rational`, whose parts are `i64`s, so under
+    // `rationals` an inexact division overflows.
+    fn big_binary(
+        &self,
+        op: char,
+        left_val: &Value,
+        right_val: &Value,
+        span: Span,
+    ) -> Result<Value, CalcError> {
+        let (Some(left), Some(right)) = (left_val.as_integer(), right_val.as_integer()) else {
+            unreachable!("`binary` only passes integers");
+        };
+        match (self.modulus, &left) {
+            (Some(modulus), &Integer::Small(base)) if op == '^' => {
+                return modular_big_power(base, &right.to_big(), modulus).map(Value::Int);
+            }
+            (Some(_), _) => {
+                let result = integer_binary(op, left, right, span)?;
+                return Ok(self.reduce(Value::from_integer(result)));
+            }
+            (None, _) => {}
+        }
+        if op == '/' && self.options.rationals {
+            let remainder = integer_binary('%', left.clone(), right.clone(), span)?;
+            if remainder != Integer::Small(0) {
+                return Err(CalcError::Overflow);
+            }
+        }
+        integer_binary(op, left, right, span).map(Value::from_integer)
+    }
+    // With a `width` below 64, integer results must fit that many bits as a
+    // signed two's-complement number, or wrap or clamp under the policy.
+    fn fit_width(&self, value: Value) -> Result<Value, CalcError> {
+        let (Some(width), &Value::Int(n)) = (self.options.width, &value) else {
+            return Ok(value);
+        };
+        let bound = 1i128 << (width.min(64) - 1);
+        let wide = n as i128;
+        if width >= 64 || (-bound..bound).contains(&wide) {
+            return Ok(value);
+        }
+        match self.options.overflow {
+            OverflowPolicy::Checked => Err(CalcError::ExceedsWidth { value: n, width }),
+            OverflowPolicy::Wrapping => {
+                let shift = 128 - width;
+                Ok(Value::Int(((wide << shift) >> shift) as i64))
+            }
+            OverflowPolicy::Saturating => Ok(Value::Int(wide.clamp(-bound, bound - 1) as i64)),
+        }
+    }
+    fn call(&mut self, name: Symbol, args: &[AST]) -> Result<Value, CalcError> {
+        self.check_call(name, args.len())?;
+        if name.is("if") {
+            return self.conditional(args);
+        }
+        let values = args
+            .iter()
+            .map(|arg| self.eval(arg))
+            .collect::<Result<Vec<Value>, CalcError>>()?;
+        self.apply(name, &values)
+    }
+    // Calls a built-in on already-evaluated arguments, whose count the
+    // caller has checked.
+    fn apply(&mut self, name: Symbol, values: &[Value]) -> Result<Value, CalcError> {
+        self.step()?;
+        if !name.is("int") && values.iter().any(|value| matches!(value, Value::Bool(_))) {
+            return Err(CalcError::BooleanOperand(name.to_string()));
+        }
+        match (name.name().as_str(), values) {
+            ("abs", &[Value::Float(x)]) => Ok(Value::Float(x.abs())),
+            ("float", [value]) => Ok(Value::Float(value.as_f64())),
+            ("int", &[Value::Float(x)]) => truncate(x).map(Value::Int),
+            ("abs", [Value::Big(n)]) => Ok(Value::Big(BigInt::new(false, n.digits.clone()))),
+            ("int", [Value::Big(n)]) => Ok(Value::Big(n.clone())),
+            ("abs", &[Value::Rational(numerator, denominator)]) => {
+                ratio((numerator as i128).abs(), denominator as i128)
+            }
+            ("int", &[Value::Rational(numerator, denominator)]) => {
+                Ok(Value::Int(numerator / denominator))
+            }
+            // `pct(part, whole)` is `part` as a percentage of `whole`, and
+            // `change(old, new)` the percentage change from `old` to `new`.
+            ("pct" | "change", [a, b]) => {
+                let (numerator, denominator) = if name.is("pct") {
+                    (a.as_f64(), b.as_f64())
+                } else {
+                    (b.as_f64() - a.as_f64(), a.as_f64())
+                };
+                if denominator == 0.0 {
+                    return Err(CalcError::DivisionByZero(Span::default()));
+                }
+                Ok(Value::Float(numerator / denominator * 100.0))
+            }
+            // Folds with `+` or `*` from the identity, so the arguments mix
+            // like operands would and `sum()` is 0.
+            ("sum" | "prod", _) => {
+                let (op, identity) = if name.is("sum") { ('+', 0) } else { ('*', 1) };
+                values
+                    .iter()
+                    .try_fold(Value::Int(identity), |total, value| {
+                        self.binary(op, total, value.clone(), Span::default())
+                    })
+            }
+            ("rand", [hi]) => self.rng.range(1, hi.as_int()?).map(Value::Int),
+            ("rand", [lo, hi]) => self.rng.range(lo.as_int()?, hi.as_int()?).map(Value::Int),
+            ("max" | "min", _) if values.iter().any(|value| matches!(value, Value::Float(_))) => {
+                let floats = values.iter().map(|value| value.as_f64());
+                let pick = if name.is("max") { f64::max } else { f64::min };
+                Ok(Value::Float(floats.reduce(pick).unwrap_or(f64::NAN)))
+            }
+            _ => {
+                let ints = values
+                    .iter()
+                    .map(|value| value.as_int())
+                    .collect::<Result<Vec<i64>, CalcError>>()?;
+                match self.functions {
+                    Some(functions) => functions.call(name, &ints),
+                    None => call_builtin(&name.name(), &ints),
+                }
+                .map(Value::Int)
+            }
+        }
+    }
+    // Built-ins have a fixed arity; registered functions only need to exist.
+    fn check_call(&self, name: Symbol, found: usize) -> Result<(), CalcError> {
+        match self.functions {
+            Some(functions) if !is_builtin(&name.name()) && functions.contains(name) => Ok(()),
+            _ => check_arity(&name.name(), found),
+        }
+    }
+    // `if(cond, a, b)` only evaluates the branch it picks, so
+    // `if(1, 5, 1/0)` is 5.
+    fn conditional(&mut self, args: &[AST]) -> Result<Value, CalcError> {
+        let [cond, then, otherwise] = args else {
+            unreachable!("`call` checks the arity of `if`");
+        };
+        let cond = self.eval(cond)?;
+        self.step()?;
+        if cond.as_f64() != 0.0 {
+            self.eval(then)
+        } else {
+            self.eval(otherwise)
+        }
+    }
+    fn step(&mut self) -> Result<(), CalcError> {
+        self.steps += 1;
+        match self.options.budget {
+            Some(budget) if self.steps > budget => Err(CalcError::BudgetExceeded(budget)),
+            _ => Ok(()),
+        }
+    }
+}
+// `int(x)` rounds toward zero: `int(3.9)` is 3 and `int(-3.9)` is -3.
+fn truncate(x: f64) -> Result<i64, CalcError> {
+    if !x.is_finite() {
+        return Err(CalcError::NotFinite(x));
+    }
+    let truncated = x.trunc();
+    if !(i64::MIN as f64..i64::MAX as f64).contains(&truncated) {
+        return Err(CalcError::Overflow);
+    }
+    Ok(truncated as i64)
+}
+// Booleans only compare for equality with each other.
+fn compare(op: char, left: &Value, right: &Value) -> Result<bool, CalcError> {
+    let ordering = match (left, right) {
+        (Value::Bool(a), Value::Bool(b)) if op == '=' || op == '≠' => a.cmp(b),
+        (Value::Bool(_), _) | (_, Value::Bool(_)) => {
+            return Err(CalcError::BooleanOperand(operator_text(op)))
+        }
+        (Value::Int(a), Value::Int(b)) => a.cmp(b),
+        (Value::Int(_) | Value::Big(_), Value::Int(_) | Value::Big(_)) => {
+            let (a, b) = (left.as_integer().unwrap(), right.as_integer().unwrap());
+            a.to_big().cmp(&b.to_big())
+        }
+        (Value::Int(_) | Value::Rational(..), Value::Int(_) | Value::Rational(..)) => {
+            let ((a, b), (c, d)) = (left.as_ratio().unwrap(), right.as_ratio().unwrap());
+            (a * d).cmp(&(c * b))
+        }
+        (a, b) => match a.as_f64().partial_cmp(&b.as_f64()) {
+            Some(ordering) => ordering,
+            None => return Ok(op == '≠'),
+        },
+    };
+    Ok(comparison_holds(op, ordering))
+}
+fn comparison_holds(op: char, ordering: std::cmp::Ordering) -> bool {
+    use std::cmp::Ordering::*;
+    match op {
+        '=' => ordering == Equal,
+        '≠' => ordering != Equal,
+        '<' => ordering == Less,
+        '≤' => ordering != Greater,
+        '>' => ordering == Greater,
+        '≥' => ordering != Less,
+        _ => false,
+    }
+}
+// Division multiplies by the inverse, `b^(modulus - 2)` by Fermat's little
+// theorem, so it only exists when `b` isn't a multiple of the modulus. The
+// exponent of `^` and both operands of `%` arrive unreduced: `a^-k` is the
+// inverse of `a` to the `k`, and `%` is the integer remainder, reduced.
+// Residues are widened to `i128` so their products can't overflow.
+fn modular_binary(
+    op: char,
+    left: i64,
+    right: i64,
+    modulus: i64,
+    span: Span,
+) -> Result<i64, CalcError> {
+    let m = modulus as i128;
+    let (a, b) = ((left as i128).rem_euclid(m), (right as i128).rem_euclid(m));
+    let result = match op {
+        '+' => (a + b) % m,
+        '-' => (a - b).rem_euclid(m),
+        '*' => a * b % m,
+        '/' if b == 0 => {
+            return Err(CalcError::NoInverse {
+                value: right,
+                modulus,
+            })
+        }
+        '/' => a * mod_pow(b, m - 2, m) % m,
+        '%' if right == 0 => return Err(CalcError::DivisionByZero(span)),
+        '%' => (left as i128 % right as i128).rem_euclid(m),
+        '^' if right < 0 && a == 0 => {
+            return Err(CalcError::NoInverse {
+                value: left,
+                modulus,
+            })
+        }
+        '^' if right < 0 => mod_pow(mod_pow(a, m - 2, m), -(right as i128), m),
+        '^' => mod_pow(a, right as i128, m),
+        _ => return Err(CalcError::UnknownOperator(op)),
+    };
+    Ok(result as i64)
+}
+// `modular_binary`'s `^` for an exponent past `i64`, taken 32 bits at a
+// time from the top: each digit raises the result so far to the 2^32.
+fn modular_big_power(base: i64, exponent: &BigInt, modulus: i64) -> Result<i64, CalcError> {
+    let m = modulus as i128;
+    let mut a = (base as i128).rem_euclid(m);
+    if exponent.negative {
+        if a == 0 {
+            return Err(CalcError::NoInverse {
+                value: base,
+                modulus,
+            });
+        }
+        a = mod_pow(a, m - 2, m);
+    }
+    let result = exponent.digits.iter().rev().fold(1 % m, |result, &digit| {
+        mod_pow(result, 1 << 32, m) * mod_pow(a, digit as i128, m) % m
+    });
+    Ok(result as i64)
+}
+fn mod_pow(mut base: i128, mut exponent: i128, modulus: i128) -> i128 {
+    let mut result = 1 % modulus;
+    base %= modulus;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result * base % modulus;
+        }
+        base = base * base % modulus;
+        exponent >>= 1;
+    }
+    result
+}
+// Deterministic Miller-Rabin: these bases witness every composite below
+// 2^64, so there are no false positives in the `i64` range.
+fn is_prime(n: i64) -> bool {
+    let n = n as i128;
+    if n < 2 {
+        return false;
+    }
+    const BASES: [i128; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+    if let Some(&base) = BASES.iter().find(|&&base| n % base == 0) {
+        return n == base;
+    }
+    let (mut odd, mut twos) = (n - 1, 0);
+    while odd % 2 == 0 {
+        odd /= 2;
+        twos += 1;
+    }
+    BASES.iter().all(|&base| {
+        let mut x = mod_pow(base, odd, n);
+        if x == 1 || x == n - 1 {
+            return true;
+        }
+        for _ in 1..twos {
+            x = x * x % n;
+            if x == n - 1 {
+                return true;
+            }
+        }
+        false
+    })
+}
+fn int_binary(
+    op: char,
+    left: i64,
+    right: i64,
+    span: Span,
+    policy: OverflowPolicy,
+) -> Result<i64, CalcError> {
+    use OverflowPolicy::*;
+    let result = match (op, policy) {
+        ('/' | '%', _) if right == 0 => return Err(CalcError::DivisionByZero(span)),
+        ('^', _) if right < 0 => return Err(CalcError::NegativeExponent(Integer::Small(right))),
+        ('+', Checked) => left.checked_add(right),
+        ('+', Wrapping) => Some(left.wrapping_add(right)),
+        ('+', Saturating) => Some(left.saturating_add(right)),
+        ('-', Checked) => left.checked_sub(right),
+        ('-', Wrapping) => Some(left.wrapping_sub(right)),
+        ('-', Saturating) => Some(left.saturating_sub(right)),
+        ('*', Checked) => left.checked_mul(right),
+        ('*', Wrapping) => Some(left.wrapping_mul(right)),
+        ('*', Saturating) => Some(left.saturating_mul(right)),
+        ('/', Checked) => left.checked_div(right),
+        ('/', Wrapping) => Some(left.wrapping_div(right)),
+        ('/', Saturating) => Some(left.saturating_div(right)),
+        ('%', Checked) => left.checked_rem(right),
+        ('%', _) => Some(left.wrapping_rem(right)),
+        ('^', Checked) => checked_power(left, right as u64),
+        ('^', Wrapping) => Some(wrapping_power(left, right as u64)),
+        ('^', Saturating) => Some(saturating_power(left, right as u64)),
+        _ => return Err(CalcError::UnknownOperator(op)),
+    };
+    result.ok_or(CalcError::Overflow)
+}
+// Exact arithmetic where at least one side is a fraction. Products of two
+// `i64`s fit an `i128`, so only the reduced result is range-checked. `%`
+// and fractional powers fall back to floats.
+fn rational_binary(op: char, left: &Value, right: &Value, span: Span) -> Result<Value, CalcError> {
+    let ((a, b), (c, d)) = match (left.as_ratio(), right.as_ratio()) {
+        (Some(left), Some(right)) => (left, right),
+        _ => unreachable!("`binary` only passes integers and fractions"),
+    };
+    match op {
+        '+' => ratio(a * d + c * b, b * d),
+        '-' => ratio(a * d - c * b, b * d),
+        '*' => ratio(a * c, b * d),
+        '/' if c == 0 => Err(CalcError::DivisionByZero(span)),
+        '/' => ratio(a * d, b * c),
+        '^' if d == 1 => {
+            let (base_numerator, base_denominator) = if c < 0 { (b, a) } else { (a, b) };
+            if base_denominator == 0 {
+                return Err(CalcError::DivisionByZero(span));
+            }
+            let exponent = c.unsigned_abs() as u64;
+            match (
+                checked_power(base_numerator as i64, exponent),
+                checked_power(base_denominator as i64, exponent),
+            ) {
+                (Some(numerator), Some(denominator)) => {
+                    ratio(numerator as i128, denominator as i128)
+                }
+                _ => Err(CalcError::Overflow),
+            }
+        }
+        _ => float_binary(op, left.as_f64(), right.as_f64()).map(Value::Float),
+    }
+}
+// `numerator / denominator` in lowest terms, as an `Int` when it divides
+// evenly. The denominator must be nonzero.
+fn ratio(numerator: i128, denominator: i128) -> Result<Value, CalcError> {
+    let divisor = gcd(numerator.unsigned_abs(), denominator.unsigned_abs()) as i128;
+    let sign = denominator.signum();
+    let (numerator, denominator) = (sign * numerator / divisor, sign * denominator / divisor);
+    let fits = |n: i128| {
+        if (i64::MIN as i128..=i64::MAX as i128).contains(&n) {
+            Ok(n as i64)
+        } else {
+            Err(CalcError::Overflow)
+        }
+    };
+    if denominator == 1 {
+        Ok(Value::Int(fits(numerator)?))
+    } else {
+        Ok(Value::Rational(fits(numerator)?, fits(denominator)?))
+    }
+}
+fn int_unary(op: char, operand: i64, policy: OverflowPolicy) -> Result<i64, CalcError> {
+    let result = match (op, policy) {
+        ('-', OverflowPolicy::Checked) => operand.checked_neg(),
+        ('-', OverflowPolicy::Wrapping) => Some(operand.wrapping_neg()),
+        ('-', OverflowPolicy::Saturating) => Some(operand.saturating_neg()),
+        ('|', OverflowPolicy::Checked) => operand.checked_abs(),
+        ('|', OverflowPolicy::Wrapping) => Some(operand.wrapping_abs()),
+        ('|', OverflowPolicy::Saturating) => Some(operand.saturating_abs()),
+        _ => return Err(CalcError::UnknownOperator(op)),
+    };
+    result.ok_or(CalcError::Overflow)
+}
+// Exponentiation by squaring, `None` on overflow. The base is only squared
+// while bits of the exponent remain, so `(-2) ^ 63` reaches `i64::MIN`
+// without overflowing on a square it never uses.
+fn checked_power(mut base: i64, mut exponent: u64) -> Option<i64> {
+    let mut result: i64 = 1;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result.checked_mul(base)?;
+        }
+        exponent >>= 1;
+        if exponent > 0 {
+            base = base.checked_mul(base)?;
+        }
+    }
+    Some(result)
+}
+fn wrapping_power(mut base: i64, mut exponent: u64) -> i64 {
+    let mut result: i64 = 1;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result.wrapping_mul(base);
+        }
+        base = base.wrapping_mul(base);
+        exponent >>= 1;
+    }
+    result
+}
+// An overflowing power is negative only for a negative base and an odd
+// exponent.
+fn saturating_power(base: i64, exponent: u64) -> i64 {
+    checked_power(base, exponent).unwrap_or(if base < 0 && exponent % 2 == 1 {
+        i64::MIN
+    } else {
+        i64::MAX
+    })
+}
+// Follows IEEE 754, so dividing by zero gives an infinity rather than an error.
+fn float_binary(op: char, left: f64, right: f64) -> Result<f64, CalcError> {
+    match op {
+        '+' => Ok(left + right),
+        '-' => Ok(left - right),
+        '*' => Ok(left * right),
+        '/' => Ok(left / right),
+        '%' => Ok(left % right),
+        '^' => Ok(left.powf(right)),
+        _ => Err(CalcError::UnknownOperator(op)),
+    }
+}
+// `core` has no float math beyond the operators, so without `std` these
+// stand in for the `f64` methods the evaluators call.
+#[cfg(feature = "no_std")]
+trait CoreFloat {
+    fn trunc(self) -> f64;
+    fn fract(self) -> f64;
+    fn powf(self, exponent: f64) -> f64;
+}
+
+#[cfg(feature = "no_std")]
+impl CoreFloat for f64 {
+    fn trunc(self) -> f64 {
+        if self.is_finite() {
+            (self - self % 1.0).copysign(self)
+        } else {
+            self
+        }
+    }
+    fn fract(self) -> f64 {
+        self % 1.0
+    }
+    // Integer exponents multiply by squaring, like `powi`; the rest go
+    // through `exp(exponent * ln(self))`.
+    fn powf(self, exponent: f64) -> f64 {
+        if exponent == 0.0 {
+            return 1.0;
+        }
+        if exponent.fract() == 0.0 && exponent.abs() < 9.0e18 {
+            let (mut base, mut n, mut result) = (self, exponent.abs() as u64, 1.0);
+            while n > 0 {
+                if n & 1 == 1 {
+                    result *= base;
+                }
+                base *= base;
+                n >>= 1;
+            }
+            return if exponent < 0.0 { 1.0 / result } else { result };
+        }
+        if self < 0.0 && exponent.fract() != 0.0 {
+            return f64::NAN;
+        }
+        core_exp(exponent * core_ln(self.abs()))
+    }
+}
+// Splits `x` into `m * 2^e` with `m` in `[1, 2)`, then sums
+// `ln(m) = 2 * atanh((m - 1) / (m + 1))`, whose terms shrink ninefold.
+#[cfg(feature = "no_std")]
+fn core_ln(x: f64) -> f64 {
+    if x.is_nan() || x < 0.0 {
+        return f64::NAN;
+    }
+    if x == 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if x.is_infinite() {
+        return x;
+    }
+    // Subnormals are scaled up by 2^54 first so the exponent field is set.
+    let (x, offset) = if x < f64::MIN_POSITIVE {
+        (x * 18_014_398_509_481_984.0, -54)
+    } else {
+        (x, 0)
+    };
+    let bits = x.to_bits();
+    let exponent = ((bits >> 52) & 0x7ff) as i64 - 1023 + offset;
+    let m = f64::from_bits((bits & 0x000f_ffff_ffff_ffff) | 0x3ff0_0000_0000_0000);
+    let t = (m - 1.0) / (m + 1.0);
+    let (mut term, mut sum) = (t, 0.0);
+    for k in 0..20 {
+        sum += term / (2 * k + 1) as f64;
+        term *= t * t;
+    }
+    2.0 * sum + exponent as f64 * std::f64::consts::LN_2
+}
+// Reduces `x` to `r + k * ln(2)` with `|r| <= ln(2) / 2`, sums the Taylor
+// series for `exp(r)` and scales by `2^k`. `ln(2)` is split in two so the
+// reduction stays exact for large `k`.
+#[cfg(feature = "no_std")]
+fn core_exp(x: f64) -> f64 {
+    const LN_2_HI: f64 = 6.931_471_803_691_238e-1;
+    const LN_2_LO: f64 = 1.908_214_929_270_587_7e-10;
+    if x.is_nan() {
+        return x;
+    }
+    if x > 709.8 {
+        return f64::INFINITY;
+    }
+    if x < -745.2 {
+        return 0.0;
+    }
+    let k = (x / std::f64::consts::LN_2 + 0.5f64.copysign(x)) as i64;
+    let r = (x - k as f64 * LN_2_HI) - k as f64 * LN_2_LO;
+    let (mut term, mut sum) = (1.0, 1.0);
+    for n in 1..=25 {
+        term *= r / n as f64;
+        sum += term;
+    }
+    // Two factors, so neither power of two leaves the normal range.
+    let power = |e: i64| f64::from_bits(((e + 1023) as u64) << 52);
+    sum * power(k / 2) * power(k - k / 2)
+}
+// A float difference smaller than this fraction of its larger operand has
+// lost about half of an `f64`'s 16 significant digits to cancellation.
+const CANCELLATION_RATIO: f64 = 1e-8;
+fn float_warning(op: char, left: f64, right: f64, result: f64) -> Option<String> {
+    let expr = format!("{} {} {}", left, operator_text(op), right);
+    if result.is_nan() {
+        return Some(format!("{} is NaN", expr));
+    }
+    if result.is_infinite() && left.is_finite() && right.is_finite() {
+        return Some(format!("{} overflows to {}", expr, result));
+    }
+    let subtracts = match op {
+        '-' => left.signum() == right.signum(),
+        '+' => left.signum() != right.signum(),
+        _ => false,
+    };
+    let scale = left.abs().max(right.abs());
+    if subtracts && result != 0.0 && result.abs() < scale * CANCELLATION_RATIO {
+        return Some(format!(
+            "{} cancels nearly equal values; {} may be imprecise",
+            expr, result
+        ));
+    }
+    None
+}
+fn is_builtin(name: &str) -> bool {
+    BUILTINS.iter().any(|(builtin, _)| *builtin == name)
+}
+fn check_arity(name: &str, found: usize) -> Result<(), CalcError> {
+    let Some(&(_, expected)) = BUILTINS.iter().find(|(builtin, _)| *builtin == name) else {
+        return Err(CalcError::UnknownFunction(name.to_string()));
+    };
+    let fits = match expected {
+        Arity::Exact(n) => found == n,
+        Arity::AtLeast(n) => found >= n,
+        Arity::Range(lo, hi) => (lo..=hi).contains(&found),
+    };
+    if fits {
+        Ok(())
+    } else {
+        Err(CalcError::WrongArgCount {
+            name: name.to_string(),
+            expected,
+            found,
+        })
+    }
+}
+// Callers check the arity first, so each arm can index its arguments.
+// `mod` is the Euclidean remainder, always nonnegative: `mod(-7, 3)` is 2
+// where `-7 % 3` is -1.
+fn call_builtin(name: &str, args: &[i64]) -> Result<i64, CalcError> {
+    match name {
+        "abs" => args[0].checked_abs().ok_or(CalcError::Overflow),
+        "int" => Ok(args[0]),
+        "mod" => {
+            if args[1] == 0 {
+                return Err(CalcError::DivisionByZero(Span::default()));
+            }
+            args[0]
+                .checked_rem_euclid(args[1])
+                .ok_or(CalcError::Overflow)
+        }
+        "gcd" => non_negative(gcd(
+            args[0].unsigned_abs() as u128,
+            args[1].unsigned_abs() as u128,
+        )),
+        "lcm" => {
+            let (a, b) = (args[0].unsigned_abs(), args[1].unsigned_abs());
+            if a == 0 || b == 0 {
+                return Ok(0);
+            }
+            let (a, b) = (a as u128, b as u128);
+            non_negative(a / gcd(a, b) * b)
+        }
+        "max" => Ok(args.iter().copied().max().unwrap_or_default()),
+        "min" => Ok(args.iter().copied().min().unwrap_or_default()),
+        _ => Err(CalcError::UnknownFunction(name.to_string())),
+    }
+}
+// `gcd(0, 0)` is 0.
+fn gcd(mut a: u128, mut b: u128) -> u128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+fn non_negative(magnitude: u128) -> Result<i64, CalcError> {
+    if magnitude > i64::MAX as u128 {
+        return Err(CalcError::Overflow);
+    }
+    Ok(magnitude as i64)
+}
+fn write_operand(
+    f: &mut std::fmt::Formatter,
+    operand: &AST,
+    parent_precedence: u8,
+    parens_on_tie: bool,
+) -> std::fmt::Result {
+    let operand_precedence = match operand {
+        AST::BinaryOp { op, .. } => precedence(*op),
+        AST::UnaryOp { op: '-', .. } => UNARY_PRECEDENCE,
+        AST::Number(n, _) if *n < 0 => UNARY_PRECEDENCE,
+        AST::Float(x) if x.is_sign_negative() => UNARY_PRECEDENCE,
+        _ => u8::MAX,
+    };
+    let needs_parens = operand_precedence < parent_precedence
+        || (operand_precedence == parent_precedence && parens_on_tie);
+    if needs_parens {
+        write!(f, "({})", operand)
+    } else {
+        write!(f, "{}", operand)
+    }
+}
+// Strips one trailing `\n` or `\r\n` from a line read from input.
+fn normalize_input(raw: &str) -> &str {
+    raw.strip_suffix("\r\n")
+        .or_else(|| raw.strip_suffix('\n'))
+        .unwrap_or(raw)
+}
+#[cfg(not(any(feature = "no_std", test)))]
+fn debug_logging_enabled() -> bool {
+    static ENABLED: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+    *ENABLED.get_or_init(|| {
+        std::env::var("RUST_LOG")
+            .map(|filter| filter.split(',').any(|f| f == "debug" || f == "trace"))
+            .unwrap_or(false)
+    })
+}
+#[cfg(not(any(feature = "no_std", test)))]
+fn log_event(event: std::fmt::Arguments) {
+    eprintln!("[DEBUG ast] {}", event);
+}
+// Under test, events are collected per thread instead, and only while a
+// test has started capturing them.
+#[cfg(test)]
+thread_local! {
+    static CAPTURED_LOG: std::cell::RefCell<Option<Vec<String>>> =
+        const { std::cell::RefCell::new(None) };
+}
+#[cfg(test)]
+fn debug_logging_enabled() -> bool {
+    CAPTURED_LOG.with(|log| log.borrow().is_some())
+}
+#[cfg(test)]
+fn log_event(event: std::fmt::Arguments) {
+    CAPTURED_LOG.with(|log| {
+        if let Some(events) = log.borrow_mut().as_mut() {
+            events.push(event.to_string());
+        }
+    });
+}
+impl FunctionRegistry {
+    fn empty() -> Self {
+        FunctionRegistry {
+            functions: HashMap::new(),
+        }
+    }
+    // Registers `function` under `name`, replacing any earlier one.
+    fn register<F>(&mut self, name: &str, function: F)
+    where
+        F: Fn(&[i64]) -> Result<i64, CalcError> + 'static,
+    {
+        self.functions
+            .insert(Symbol::intern(name), Box::new(function));
+    }
+    fn contains(&self, name: Symbol) -> bool {
+        self.functions.contains_key(&name)
+    }
+    fn call(&self, name: Symbol, args: &[i64]) -> Result<i64, CalcError> {
+        match self.functions.get(&name) {
+            Some(function) => function(args),
+            None => Err(CalcError::UnknownFunction(name.to_string())),
+        }
+    }
+}
+
+impl Default for FunctionRegistry {
+    fn default() -> Self {
+        let mut registry = FunctionRegistry::empty();
+        for &name in &["abs", "int", "mod", "gcd", "lcm", "max", "min"] {
+            registry.register(name, move |args| call_builtin(name, args));
+        }
+        registry
+    }
+}
+
+#[cfg(not(feature = "no_std"))]
+impl Prompts {
+    // `lookup` resolves an environment variable; `main` passes
+    // `std::env::var`.
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Prompts {
+        let resolve = |key: &str, default: &str| lookup(key).unwrap_or_else(|| default.to_string());
+        Prompts {
+            input: resolve("BASIC_ASTS_PROMPT", "in> "),
+            continuation: resolve("BASIC_ASTS_CONT_PROMPT", "...> "),
+            output: resolve("BASIC_ASTS_OUT_PROMPT", "out> "),
+            error: resolve("BASIC_ASTS_ERR_PROMPT", "err> "),
+            warning: resolve("BASIC_ASTS_WARN_PROMPT", "warn> "),
+            step: resolve("BASIC_ASTS_STEP_PROMPT", "step> "),
+        }
+    }
+    // The text printed for `output`, if any.
+    fn render(&self, output: &ReplOutput) -> Option<String> {
+        match output {
+            ReplOutput::Result(value) => Some(format!("{}{}", self.output, value)),
+            ReplOutput::Steps(steps, value) => Some(
+                steps
+                    .iter()
+                    .map(|step| format!("{}{}\n", self.step, step))
+                    .chain(Some(format!("{}{}", self.output, value)))
+                    .collect(),
+            ),
+            ReplOutput::Error(e) => Some(format!("{}{e}", self.error)),
+            ReplOutput::Command(message) => Some(message.clone()),
+            ReplOutput::Incomplete | ReplOutput::Empty => None,
+        }
+    }
+}
+#[cfg(not(feature = "no_std"))]
+impl Repl {
+    // Handles one line of input. A line that leaves an expression incomplete,
+    // such as `(1 + 2 *`, is held until a later line completes it; commands
+    // are always complete, and one typed in the middle runs straight away,
+    // leaving the held lines to be continued.
+    fn feed_line(&mut self, line: &str) -> ReplOutput {
+        if line.trim_start().starts_with(':') {
+            return self.submit(line.trim_start().to_string());
+        }
+        if self.pending.is_empty() {
+            if line.trim().is_empty() {
+                return ReplOutput::Empty;
+            }
+        } else {
+            self.pending.push(' ');
+        }
+        self.pending.push_str(line);
+        if !is_complete(&self.pending) {
+            return ReplOutput::Incomplete;
+        }
+        let line = std::mem::take(&mut self.pending);
+        self.submit(line)
+    }
+    // At end of input, runs whatever is still held, complete or not.
+    fn finish(&mut self) -> Option<ReplOutput> {
+        if self.pending.is_empty() {
+            None
+        } else {
+            let line = std::mem::take(&mut self.pending);
+            Some(self.submit(line))
+        }
+    }
+    fn submit(&mut self, line: String) -> ReplOutput {
+        self.warnings.clear();
+        self.steps.clear();
+        let output = if let Some(command) = line.strip_prefix(':') {
+            match self.run_command(command) {
+                Ok(message) => ReplOutput::Command(message),
+                Err(e) => ReplOutput::Error(e),
+            }
+        } else {
+            match self.eval_display(&line) {
+                Ok(text) if self.steps.is_empty() => ReplOutput::Result(text),
+                Ok(text) => ReplOutput::Steps(std::mem::take(&mut self.steps), text),
+                Err(e) => ReplOutput::Error(e),
+            }
+        };
+        if let Some(text) = self.prompts.render(&output) {
+            self.record(&line, &text);
+        }
+        output
+    }
+    fn run_command(&mut self, command: &str) -> Result<String, CalcError> {
+        let command = command.trim();
+        let (name, rest) = command.split_once(' ').unwrap_or((command, ""));
+        let args: Vec<&str> = rest.split_whitespace().collect();
+        match (name, args.as_slice()) {
+            ("step", []) => {
+                self.step_mode = !self.step_mode;
+                Ok(format!("step mode {}", on_off(self.step_mode)))
+            }
+            ("sign", [flag]) => {
+                self.explicit_sign = parse_on_off(name, flag)?;
+                Ok(format!("explicit sign {}", on_off(self.explicit_sign)))
+            }
+            ("base", [radix]) => {
+                self.base = match *radix {
+                    "2" => 2,
+                    "8" => 8,
+                    "10" => 10,
+                    "16" => 16,
+                    _ => return Err(invalid_argument(name, radix)),
+                };
+                Ok(format!("output base {}", self.base))
+            }
+            ("neg", [mode]) => {
+                self.negative_format = match *mode {
+                    "signed" => NegativeFormat::Signed,
+                    "bits" => NegativeFormat::Bits,
+                    _ => return Err(invalid_argument(name, mode)),
+                };
+                Ok(format!("negative results as {}", mode))
+            }
+            ("seed", ["off"]) => {
+                self.seed = None;
+                self.rng = Rng::from_time();
+                Ok("seed off".to_string())
+            }
+            ("seed", [seed]) => {
+                let seed = seed.parse().map_err(|_| invalid_argument(name, seed))?;
+                self.seed = Some(seed);
+                self.rng = Rng::seeded(seed);
+                Ok(format!("seed {}", seed))
+            }
+            ("decimal", [separator]) => {
+                self.lex_options.decimal_comma = match *separator {
+                    "comma" => true,
+                    "point" => false,
+                    _ => return Err(invalid_argument(name, separator)),
+                };
+                Ok(format!("decimal separator {}", separator))
+            }
+            ("assoc", ["sub", side]) => {
+                self.parse_options.right_assoc_sub = match *side {
+                    "right" => true,
+                    "left" => false,
+                    _ => return Err(invalid_argument(name, side)),
+                };
+                Ok(format!("- and / associate {}", side))
+            }
+            ("modulus", ["off"]) => {
+                self.eval_options.modulus = None;
+                Ok("modulus off".to_string())
+            }
+            ("modulus", [modulus]) => {
+                let modulus = modulus
+                    .parse()
+                    .ok()
+                    .filter(|&m| is_prime(m))
+                    .ok_or_else(|| invalid_argument(name, modulus))?;
+                self.eval_options.modulus = Some(modulus);
+                Ok(format!("arithmetic modulo {}", modulus))
+            }
+            ("width", [bits]) => {
+                self.eval_options.width = match *bits {
+                    "8" => Some(8),
+                    "16" => Some(16),
+                    "32" => Some(32),
+                    "64" => None,
+                    _ => return Err(invalid_argument(name, bits)),
+                };
+                Ok(format!("integer width {}", bits))
+            }
+            ("overflow", [policy]) => {
+                self.eval_options.overflow = match *policy {
+                    "checked" => OverflowPolicy::Checked,
+                    "wrapping" => OverflowPolicy::Wrapping,
+                    "saturating" => OverflowPolicy::Saturating,
+                    _ => return Err(invalid_argument(name, policy)),
+                };
+                Ok(format!("overflow {}", policy))
+            }
+            ("strict", [flag]) => {
+                self.eval_options.strict_bools = parse_on_off(name, flag)?;
+                Ok(format!(
+                    "strict booleans {}",
+                    on_off(self.eval_options.strict_bools)
+                ))
+            }
+            ("rational", [flag]) => {
+                self.eval_options.rationals = parse_on_off(name, flag)?;
+                Ok(format!("rationals {}", on_off(self.eval_options.rationals)))
+            }
+            ("undefined", [mode]) => {
+                self.eval_options.undefined_as_zero = match *mode {
+                    "zero" => true,
+                    "error" => false,
+                    _ => return Err(invalid_argument(name, mode)),
+                };
+                Ok(format!("undefined variables are {}", mode))
+            }
+            ("warn", [flag]) => {
+                self.eval_options.float_warnings = parse_on_off(name, flag)?;
+                Ok(format!(
+                    "float warnings {}",
+                    on_off(self.eval_options.float_warnings)
+                ))
+            }
+            ("bool", [style]) => {
+                self.bool_words = match *style {
+                    "words" => true,
+                    "digits" => false,
+                    _ => return Err(invalid_argument(name, style)),
+                };
+                Ok(format!("booleans as {}", style))
+            }
+            // Reading and printing numerals go together.
+            ("roman", [mode]) => {
+                self.lex_options.roman = match *mode {
+                    "off" => RomanNumerals::Off,
+                    "on" => RomanNumerals::Lenient,
+                    "strict" => RomanNumerals::Strict,
+                    _ => return Err(invalid_argument(name, mode)),
+                };
+                Ok(format!("roman numerals {}", mode))
+            }
+            ("words", [flag]) => {
+                self.words = parse_on_off(name, flag)?;
+                Ok(format!("number words {}", on_off(self.words)))
+            }
+            ("intsci", ["off"]) => {
+                self.int_sci = None;
+                Ok("integer scientific notation off".to_string())
+            }
+            // The threshold may be written `1e9` as well as `1000000000`.
+            ("intsci", [threshold]) => {
+                let limit = threshold
+                    .parse::<u64>()
+                    .ok()
+                    .or_else(|| {
+                        let t = threshold.parse::<f64>().ok()?;
+                        (t.fract() == 0.0 && t < u64::MAX as f64).then_some(t as u64)
+                    })
+                    .filter(|&limit| limit >= 1)
+                    .ok_or_else(|| invalid_argument(name, threshold))?;
+                self.int_sci = Some(limit);
+                Ok(format!("integer scientific notation from {}", threshold))
+            }
+            ("transcript", [flag @ ("on" | "off")]) => {
+                self.recording = parse_on_off(name, flag)?;
+                Ok(format!("transcript {}", flag))
+            }
+            ("transcript", ["save", path]) => {
+                std::fs::write(path, self.transcript_text())
+                    .map_err(|e| CalcError::Io(e.to_string()))?;
+                Ok(format!(
+                    "saved {} entries to {}",
+                    self.transcript.len(),
+                    path
+                ))
+            }
+            ("format", _) => format_expr_with(rest, &self.lex_options, &self.parse_options),
+            ("explain-parse", _) => {
+                explain_parse_with(rest, &self.lex_options, &self.parse_options)
+                    .map(|steps| steps.join("\n"))
+            }
+            ("bench", _) => {
+                let tokens = lex_spanned_with(rest, &self.lex_options)?;
+                let ast = parse_spanned_with(&tokens, &self.parse_options)?;
+                Ok(time_evaluations(&ast, &self.env, BENCH_ITERATIONS)?.to_string())
+            }
+            ("precision", ["off"]) => {
+                self.precision = None;
+                Ok("float precision off".to_string())
+            }
+            ("precision", [places, rest @ ..]) if matches!(rest, [] | ["trim"]) => {
+                let places = places
+                    .parse()
+                    .ok()
+                    .filter(|&places| places <= MAX_PRECISION)
+                    .ok_or_else(|| invalid_argument(name, places))?;
+                self.precision = Some(places);
+                self.trim_zeros = !rest.is_empty();
+                Ok(format!("float precision {}", args.join(" ")))
+            }
+            ("swap", [a, b]) => self.swap(a, b),
+            ("undo", []) => self.undo(),
+            ("config", []) => Ok(self.config_text()),
+            ("reset", []) => {
+                self.reset_settings();
+                Ok("settings reset".to_string())
+            }
+            // Back to a fresh session: settings, variables, `ans` and the
+            // `_N` results, and the transcript all go.
+            ("reset", ["all"]) => {
+                *self = Repl {
+                    max_input_len: self.max_input_len,
+                    prompts: self.prompts.clone(),
+                    ..Repl::default()
+                };
+                Ok("session reset".to_string())
+            }
+            _ => Err(CalcError::UnknownCommand(command.to_string())),
+        }
+    }
+    // Exchanges the values of two variables, which must both be defined.
+    // Nothing is bound to `ans`: the message just shows the new values.
+    fn swap(&mut self, a: &str, b: &str) -> Result<String, CalcError> {
+        let (a, b) = (Symbol::intern(a), Symbol::intern(b));
+        for name in [a, b] {
+            if is_reserved_name(&name.name()) {
+                return Err(CalcError::ReservedName(name.to_string()));
+            }
+        }
+        let lookup = |name: Symbol| {
+            self.env
+                .get(&name)
+                .cloned()
+                .ok_or_else(|| CalcError::UndefinedVariable(name.to_string()))
+        };
+        let (a_value, b_value) = (lookup(a)?, lookup(b)?);
+        let message = format!(
+            "{} = {}, {} = {}",
+            a,
+            self.format_result(&b_value),
+            b,
+            self.format_result(&a_value)
+        );
+        self.assign(&[(a, b_value), (b, a_value)]);
+        Ok(message)
+    }
+    // Binds variables as one undoable step.
+    fn assign(&mut self, bindings: &[(Symbol, Value)]) {
+        let previous = bindings
+            .iter()
+            .map(|(name, value)| (*name, self.env.insert(*name, value.clone())))
+            .collect();
+        if self.undo.len() == UNDO_LIMIT {
+            self.undo.remove(0);
+        }
+        self.undo.push(previous);
+    }
+    // Reverts the latest assignment or `:swap`, restoring old values and
+    // removing variables it created.
+    fn undo(&mut self) -> Result<String, CalcError> {
+        let step = self.undo.pop().ok_or(CalcError::NothingToUndo)?;
+        let mut changes = Vec::new();
+        for (name, previous) in step.into_iter().rev() {
+            match previous {
+                Some(value) => {
+                    changes.push(format!("{} = {}", name, self.format_result(&value)));
+                    self.env.insert(name, value);
+                }
+                None => {
+                    self.env.remove(&name);
+                    changes.push(format!("removed {}", name));
+                }
+            }
+        }
+        changes.reverse();
+        Ok(changes.join(", "))
+    }
+    // One `name value` line per setting, in the spelling of the command that
+    // changes it.
+    fn config_text(&self) -> String {
+        let neg = match self.negative_format {
+            NegativeFormat::Signed => "signed",
+            NegativeFormat::Bits => "bits",
+        };
+        let modulus = self
+            .eval_options
+            .modulus
+            .map_or("off".to_string(), |m| m.to_string());
+        let overflow = match self.eval_options.overflow {
+            OverflowPolicy::Checked => "checked",
+            OverflowPolicy::Wrapping => "wrapping",
+            OverflowPolicy::Saturating => "saturating",
+        };
+        let lines = [
+            ("step", on_off(self.step_mode).to_string()),
+            ("sign", on_off(self.explicit_sign).to_string()),
+            ("base", self.base.to_string()),
+            ("neg", neg.to_string()),
+            (
+                "decimal",
+                if self.lex_options.decimal_comma {
+                    "comma"
+                } else {
+                    "point"
+                }
+                .to_string(),
+            ),
+            (
+                "assoc",
+                if self.parse_options.right_assoc_sub {
+                    "sub right"
+                } else {
+                    "sub left"
+                }
+                .to_string(),
+            ),
+            ("modulus", modulus),
+            ("width", self.eval_options.width.unwrap_or(64).to_string()),
+            ("overflow", overflow.to_string()),
+            ("strict", on_off(self.eval_options.strict_bools).to_string()),
+            ("warn", on_off(self.eval_options.float_warnings).to_string()),
+            ("rational", on_off(self.eval_options.rationals).to_string()),
+            (
+                "undefined",
+                if self.eval_options.undefined_as_zero {
+                    "zero"
+                } else {
+                    "error"
+                }
+                .to_string(),
+            ),
+            (
+                "bool",
+                if self.bool_words { "words" } else { "digits" }.to_string(),
+            ),
+            ("words", on_off(self.words).to_string()),
+            (
+                "intsci",
+                self.int_sci
+                    .map_or("off".to_string(), |limit| limit.to_string()),
+            ),
+            (
+                "roman",
+                match self.lex_options.roman {
+                    RomanNumerals::Off => "off",
+                    RomanNumerals::Lenient => "on",
+                    RomanNumerals::Strict => "strict",
+                }
+                .to_string(),
+            ),
+            (
+                "precision",
+                match self.precision {
+                    None => "off".to_string(),
+                    Some(places) if self.trim_zeros => format!("{} trim", places),
+                    Some(places) => places.to_string(),
+                },
+            ),
+            (
+                "seed",
+                self.seed.map_or("off".to_string(), |seed| seed.to_string()),
+            ),
+            ("transcript", on_off(self.recording).to_string()),
+        ];
+        lines
+            .iter()
+            .map(|(name, value)| format!("{} {}", name, value))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+    // Restores every setting `:config` lists, and nothing else. The seed is
+    // kept, and `rand` carries on from where its sequence was.
+    fn reset_settings(&mut self) {
+        *self = Repl {
+            rng: self.rng.clone(),
+            seed: self.seed,
+            env: std::mem::take(&mut self.env),
+            results: self.results,
+            max_input_len: self.max_input_len,
+            transcript: std::mem::take(&mut self.transcript),
+            undo: std::mem::take(&mut self.undo),
+            prompts: self.prompts.clone(),
+            ..Repl::default()
+        };
+    }
+    // Called for each submitted line; only kept while `:transcript on`.
+    fn record(&mut self, input: &str, output: &str) {
+        if self.recording {
+            self.transcript
+                .push((input.to_string(), output.to_string()));
+        }
+    }
+    fn transcript_text(&self) -> String {
+        self.transcript
+            .iter()
+            .map(|(input, output)| format!("{}{}\n{}\n", self.prompts.input, input, output))
+            .collect()
+    }
+    // With `:sign on`, positive numbers get a leading `+`; zero stays `0`,
+    // and booleans never take a sign.
+    // Floats are always printed in decimal, using the `:decimal` separator.
+    // Spelled-out integers say `negative` instead of taking a sign; ones
+    // past `i64` keep their digits. Under `:roman`, integers from 1 to 3999
+    // print as numerals; `:words` wins. Past the `:intsci` threshold,
+    // base-10 integers print as `2.0e9`.
+    fn format_result(&self, value: &Value) -> String {
+        if let (&Value::Int(n), false) = (value, self.words) {
+            if let Some(numeral) =
+                to_roman(n).filter(|_| self.lex_options.roman != RomanNumerals::Off)
+            {
+                return numeral;
+            }
+        }
+        let separated = |text: String| {
+            if self.lex_options.decimal_comma {
+                text.replace('.', ",")
+            } else {
+                text
+            }
+        };
+        let digits = match *value {
+            Value::Int(n) if self.words => return number_words(n),
+            Value::Int(n)
+                if self.base == 10
+                    && self.int_sci.is_some_and(|limit| n.unsigned_abs() >= limit) =>
+            {
+                separated(int_scientific(n, self.precision, self.trim_zeros))
+            }
+            Value::Int(n) => format_radix(n, self.base, self.negative_format),
+            Value::Big(ref n) if self.base == 10 && self.int_sci.is_some() => {
+                let decimal = n.to_string();
+                separated(decimal_scientific(
+                    &decimal,
+                    self.precision,
+                    self.trim_zeros,
+                ))
+            }
+            Value::Big(ref n) => format_big_radix(n, self.base),
+            Value::Bool(b) if self.bool_words => b.to_string(),
+            Value::Bool(b) => format_radix(b as i64, self.base, self.negative_format),
+            Value::Float(x) if self.lex_options.decimal_comma => {
+                format_float(x, self.precision, self.trim_zeros).replace('.', ",")
+            }
+            Value::Float(x) => format_float(x, self.precision, self.trim_zeros),
+            Value::Rational(numerator, denominator) => format!(
+                "{}/{}",
+                format_radix(numerator, self.base, self.negative_format),
+                format_radix(denominator, self.base, self.negative_format)
+            ),
+        };
+        let numeric = !matches!(value, Value::Bool(_));
+        if self.explicit_sign && numeric && value.as_f64() > 0.0 {
+            format!("+{}", digits)
+        } else {
+            digits
+        }
+    }
+    // Evaluates an expression or an assignment, written `name = expr` or
+    // `expr => name`. Every result is also bound to `ans` and to `_N`, where
+    // N counts results from 1.
+    #[allow(dead_code)]
+    fn eval_line(&mut self, line: &str) -> Result<Value, CalcError> {
+        check_input_len(line, self.max_input_len)?;
+        let tokens = lex_spanned_with(line, &self.lex_options)?;
+        self.eval_tokens(&tokens)
+    }
+    // Evaluates a line for printing. A top-level comma list is a tuple,
+    // shown as `(2, 6, 4)`; its elements are plain expressions, and the
+    // tuple isn't bound to `ans` or `_N`.
+    fn eval_display(&mut self, line: &str) -> Result<String, CalcError> {
+        check_input_len(line, self.max_input_len)?;
+        let tokens = lex_spanned_with(line, &self.lex_options)?;
+        let elements = split_tuple(&tokens);
+        if let [_] = elements.as_slice() {
+            let value = self.eval_tokens(&tokens)?;
+            return Ok(self.format_result(&value));
+        }
+        let shown = elements
+            .iter()
+            .map(|element| {
+                let (value, boolean) = self.eval_expression(element)?;
+                Ok(self.format_result(&self.displayed(value, boolean)))
+            })
+            .collect::<Result<Vec<String>, CalcError>>()?;
+        // `,` is the decimal separator under `:decimal comma`.
+        let separator = if self.lex_options.decimal_comma {
+            "; "
+        } else {
+            ", "
+        };
+        Ok(format!("({})", shown.join(separator)))
+    }
+    fn eval_tokens(&mut self, tokens: &[(Token, Span)]) -> Result<Value, CalcError> {
+        let (target, expression) = match tokens {
+            [(Token::Identifier(name), _), (Token::Assign, _), rest @ ..] => {
+                if is_reserved_name(&name.name()) {
+                    return Err(CalcError::ReservedName(name.to_string()));
+                }
+                (Some(*name), rest)
+            }
+            [rest @ .., (Token::Arrow, _), (Token::Identifier(name), _)] => {
+                if is_reserved_name(&name.name()) {
+                    return Err(CalcError::ReservedName(name.to_string()));
+                }
+                (Some(*name), rest)
+            }
+            _ => (None, tokens),
+        };
+        let (value, boolean) = self.eval_expression(expression)?;
+        if let Some(name) = target {
+            self.assign(&[(name, value.clone())]);
+        }
+        self.results += 1;
+        self.env
+            .insert(Symbol::intern(&format!("_{}", self.results)), value.clone());
+        self.env.insert(Symbol::intern("ans"), value.clone());
+        Ok(self.displayed(value, boolean))
+    }
+    // The value of one expression, and whether it is a comparison.
+    fn eval_expression(&mut self, tokens: &[(Token, Span)]) -> Result<(Value, bool), CalcError> {
+        // `solve(lhs = rhs, var)` must be the whole expression, since `=`
+        // only means equality inside it.
+        let solve_args = match tokens {
+            [(Token::Identifier(name), _), (Token::LeftParen, _), args @ .., (Token::RightParen, _)]
+                if name.is("solve") =>
+            {
+                Some(args)
+            }
+            _ => None,
+        };
+        if let Some([equation @ .., (Token::Comma, _), (Token::Identifier(var), _)]) = solve_args {
+            let (lhs, rhs) = parse_equation(equation, &self.parse_options)?;
+            return Ok((solve(&lhs, &rhs, *var, &self.env)?, false));
+        }
+        let ast = parse_spanned_with(tokens, &self.parse_options)?;
+        let boolean = result_kind(&ast) == ResultKind::Bool;
+        let value = if self.step_mode {
+            let (steps, value) = step_through(ast, &self.env, &self.eval_options, &self.rng)?;
+            self.steps.extend(steps);
+            value
+        } else {
+            let (value, warnings) =
+                evaluate_with_warnings(&ast, &self.env, &self.eval_options, &self.rng)?;
+            self.warnings.extend(warnings);
+            value
+        };
+        Ok((value, boolean))
+    }
+    // Only the returned value turns into a `Bool`; the bindings keep the 1
+    // or 0 so arithmetic on `ans` still works.
+    fn displayed(&self, value: Value, boolean: bool) -> Value {
+        if self.bool_words && boolean {
+            Value::Bool(value.as_f64() != 0.0)
+        } else {
+            value
+        }
+    }
+}
+// Predicts the kind of result from the tree alone: comparisons are
+// booleans, and arithmetic is float as soon as one operand is. Variables
+// are assumed to hold integers, since their values aren't known yet.
+fn result_kind(ast: &AST) -> ResultKind {
+    let widen = |kinds: &[ResultKind]| {
+        if kinds.contains(&ResultKind::Float) {
+            ResultKind::Float
+        } else {
+            ResultKind::Int
+        }
+    };
+    match ast {
+        AST::Number(..) | AST::Variable(_) => ResultKind::Int,
+        AST::Float(_) => ResultKind::Float,
+        AST::UnaryOp { operand, .. } => result_kind(operand),
+        AST::BinaryOp { op, .. } if is_comparison(*op) => ResultKind::Bool,
+        AST::BinaryOp { left, right, .. } => widen(&[result_kind(left), result_kind(right)]),
+        AST::Call { name, args } => match (name.name().as_str(), args.as_slice()) {
+            ("float" | "pct" | "change", _) => ResultKind::Float,
+            ("abs", [arg]) => result_kind(arg),
+            ("max" | "min" | "sum" | "prod", _) => {
+                widen(&args.iter().map(result_kind).collect::<Vec<_>>())
+            }
+            ("if", [_, then, otherwise]) => match (result_kind(then), result_kind(otherwise)) {
+                (ResultKind::Bool, ResultKind::Bool) => ResultKind::Bool,
+                (then, otherwise) => widen(&[then, otherwise]),
+            },
+            _ => ResultKind::Int,
+        },
+        AST::Grouped(inner) => result_kind(inner),
+    }
+}
+// An input is incomplete while it has unclosed parentheses or ends in an
+// operator, so the REPL keeps reading: `(1 + 2 *` waits for more. Input that
+// doesn't lex counts as complete, so its error is reported straight away.
+fn is_complete(input: &str) -> bool {
+    let (tokens, errors) = lex_all(input);
+    if !errors.is_empty() {
+        return true;
+    }
+    let depth = tokens.iter().fold(0i32, |depth, token| match token {
+        Token::LeftParen => depth + 1,
+        Token::RightParen => depth - 1,
+        _ => depth,
+    });
+    let dangling = matches!(
+        tokens.last(),
+        Some(Token::Operator(_) | Token::Comma | Token::Assign | Token::Arrow)
+    );
+    depth <= 0 && !dangling
+}
+fn is_reserved_name(name: &str) -> bool {
+    name == "ans"
+        || name
+            .strip_prefix('_')
+            .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+fn parse_on_off(command: &str, arg: &str) -> Result<bool, CalcError> {
+    match arg {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(invalid_argument(command, arg)),
+    }
+}
+fn invalid_argument(command: &str, arg: &str) -> CalcError {
+    CalcError::InvalidArgument {
+        command: command.to_string(),
+        arg: arg.to_string(),
+    }
+}
+// Formats `value` in base 2, 8, 10 or 16 with a `0b`/`0o`/`0x` prefix.
+// Outside base 10, `Bits` prints negatives as their 64-bit two's-complement
+// pattern, so -1 is `0xffffffffffffffff` rather than `-0x1`.
+fn format_radix(value: i64, radix: u32, negative_format: NegativeFormat) -> String {
+    let bits = value as u64;
+    let magnitude = value.unsigned_abs();
+    let (prefix, pattern, digits) = match radix {
+        2 => ("0b", format!("{:b}", bits), format!("{:b}", magnitude)),
+        8 => ("0o", format!("{:o}", bits), format!("{:o}", magnitude)),
+        16 => ("0x", format!("{:x}", bits), format!("{:x}", magnitude)),
+        _ => return value.to_string(),
+    };
+    match negative_format {
+        _ if value >= 0 => format!("{}{}", prefix, digits),
+        NegativeFormat::Signed => format!("-{}{}", prefix, digits),
+        NegativeFormat::Bits => format!("{}{}", prefix, pattern),
+    }
+}
+// A `BigInt` has no fixed width to show the bits of, so a negative one
+// always takes a sign.
+fn format_big_radix(n: &BigInt, radix: u32) -> String {
+    let prefix = match radix {
+        2 => "0b",
+        8 => "0o",
+        16 => "0x",
+        _ => return n.to_string(),
+    };
+    let mut magnitude = n.digits.clone();
+    let mut digits = Vec::new();
+    while !magnitude.is_empty() {
+        let (quotient, remainder) = div_small(&magnitude, radix);
+        digits.push(std::char::from_digit(remainder, radix).unwrap());
+        magnitude = quotient;
+    }
+    let sign = if n.negative { "-" } else { "" };
+    format!(
+        "{}{}{}",
+        sign,
+        prefix,
+        digits.iter().rev().collect::<String>()
+    )
+}
+// An `f64` holds about 17 significant digits; more places only add noise.
+const MAX_PRECISION: usize = 20;
+// With a precision, `format_float(2.5, Some(3), false)` is `2.500`; trimming
+// drops trailing zeros, and the point too if nothing follows it. The stored
+// value keeps every digit either way.
+fn format_float(x: f64, precision: Option<usize>, trim_zeros: bool) -> String {
+    let Some(places) = precision else {
+        return format!("{:?}", x);
+    };
+    let text = format!("{:.*}", places, x);
+    if trim_zeros && text.contains('.') {
+        text.trim_end_matches('0').trim_end_matches('.').to_string()
+    } else {
+        text
+    }
+}
+// `int_scientific(2_000_000_000, None, false)` is `2.0e9`. The mantissa
+// keeps every digit of `n` (at least one after the point), or with a
+// precision is rounded half away from zero to that many places, so
+// 2_049_999_999 at 2 places is `2.05e9` and 9_999_999 at 1 is `1.0e7`.
+// Trimming works as in `format_float`.
+fn int_scientific(n: i64, precision: Option<usize>, trim_zeros: bool) -> String {
+    decimal_scientific(&n.to_string(), precision, trim_zeros)
+}
+// `int_scientific` on an integer's decimal digits, which may be too many
+// for an `i64`.
+fn decimal_scientific(decimal: &str, precision: Option<usize>, trim_zeros: bool) -> String {
+    let (sign, magnitude) = match decimal.strip_prefix('-') {
+        Some(magnitude) => ("-", magnitude),
+        None => ("", decimal),
+    };
+    let mut digits = magnitude.as_bytes().to_vec();
+    let mut exponent = digits.len() - 1;
+    let mut fraction = match precision {
+        None => {
+            while digits.len() > 2 && digits.last() == Some(&b'0') {
+                digits.pop();
+            }
+            digits.resize(digits.len().max(2), b'0');
+            digits.split_off(1)
+        }
+        Some(places) => {
+            let round_up = digits.get(places + 1).is_some_and(|&d| d >= b'5');
+            digits.resize(places + 1, b'0');
+            if round_up {
+                let mut i = digits.len();
+                while i > 0 && digits[i - 1] == b'9' {
+                    digits[i - 1] = b'0';
+                    i -= 1;
+                }
+                if i == 0 {
+                    digits.insert(0, b'1');
+                    digits.pop();
+                    exponent += 1;
+                } else {
+                    digits[i - 1] += 1;
+                }
+            }
+            digits.split_off(1)
+        }
+    };
+    if trim_zeros && precision.is_some() {
+        while fraction.last() == Some(&b'0') {
+            fraction.pop();
+        }
+    }
+    let lead = digits[0] as char;
+    match String::from_utf8(fraction) {
+        Ok(fraction) if !fraction.is_empty() => {
+            format!("{}{}.{}e{}", sign, lead, fraction, exponent)
+        }
+        _ => format!("{}{}e{}", sign, lead, exponent),
+    }
+}
+// `number_words(-1042)` is `negative one thousand forty-two`. Every `i64`
+// fits below a sextillion.
+fn number_words(n: i64) -> String {
+    let magnitude = n.unsigned_abs();
+    let mut parts = Vec::new();
+    let mut rest = magnitude;
+    for &(scale, name) in &[
+        (1_000_000_000_000_000_000, "quintillion"),
+        (1_000_000_000_000_000, "quadrillion"),
+        (1_000_000_000_000, "trillion"),
+        (1_000_000_000, "billion"),
+        (1_000_000, "million"),
+        (1_000, "thousand"),
+    ] {
+        if rest >= scale {
+            parts.push(format!(
+                "{} {}",
+                hundreds_words((rest / scale) as u32),
+                name
+            ));
+            rest %= scale;
+        }
+    }
+    if rest > 0 || parts.is_empty() {
+        parts.push(hundreds_words(rest as u32));
+    }
+    let words = parts.join(" ");
+    if n < 0 {
+        format!("negative {}", words)
+    } else {
+        words
+    }
+}
+// Words for `0..1000`.
+fn hundreds_words(n: u32) -> String {
+    const ONES: [&str; 20] = [
+        "zero",
+        "one",
+        "two",
+        "three",
+        "four",
+        "five",
+        "six",
+        "seven",
+        "eight",
+        "nine",
+        "ten",
+        "eleven",
+        "twelve",
+        "thirteen",
+        "fourteen",
+        "fifteen",
+        "sixteen",
+        "seventeen",
+        "eighteen",
+        "nineteen",
+    ];
+    const TENS: [&str; 10] = [
+        "", "", "twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety",
+    ];
+    let (hundreds, rest) = ((n / 100) as usize, (n % 100) as usize);
+    let tail = match rest {
+        0..=19 => ONES[rest].to_string(),
+        _ if rest % 10 == 0 => TENS[rest / 10].to_string(),
+        _ => format!("{}-{}", TENS[rest / 10], ONES[rest % 10]),
+    };
+    match (hundreds, rest) {
+        (0, _) => tail,
+        (_, 0) => format!("{} hundred", ONES[hundreds]),
+        _ => format!("{} hundred {}", ONES[hundreds], tail),
+    }
+}
+fn on_off(flag: bool) -> &'static str {
+    if flag {
+        "on"
+    } else {
+        "off"
+    }
+}
+#[cfg(not(feature = "no_std"))]
+fn read_line(prompt: &str) -> Option<String> {
+    use std::io::Write;
+    print!("{}", prompt);
+    std::io::stdout().flush().unwrap();
+    let mut line = String::new();
+    match std::io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(normalize_input(&line).to_string()),
+    }
+}
+// Every intermediate expression from `ast` down to a single number, the
+// first being `ast` itself, and the value it reduces to.
+fn step_through(
+    ast: AST,
+    env: &Env,
+    options: &EvalOptions,
+    rng: &Rng,
+) -> Result<(Vec<String>, Value), CalcError> {
+    let mut current = ast;
+    let mut steps = vec![current.to_string()];
+    while let Some(next) = small_step_with(&current, env, options, rng)? {
+        current = next;
+        steps.push(current.to_string());
+    }
+    Ok((steps, evaluate_value(&current, env, options, rng)?))
+}
+// An empty `BASIC_ASTS_EXPR` counts as unset.
+#[cfg(not(feature = "no_std"))]
+fn input_source(env_expr: Option<String>, args: &[String]) -> InputSource {
+    if let Some(expr) = env_expr.filter(|expr| !expr.trim().is_empty()) {
+        return InputSource::Expressions(vec![expr]);
+    }
+    match args {
+        [flag, input] if flag == "--explain" => InputSource::Explain(input.clone()),
+        [flag, input] if flag == "--json-ast" => InputSource::JsonAst(input.clone()),
+        [flag] if flag == "--csv" => InputSource::Csv,
+        [] => InputSource::Repl,
+        _ if args.iter().all(|arg| !arg.starts_with("--")) => {
+            InputSource::Expressions(args.to_vec())
+        }
+        [flag, ..] if flag == "--explain" || flag == "--json-ast" => {
+            InputSource::Usage(format!("{} takes exactly one expression", flag))
+        }
+        [flag, ..] if flag == "--csv" => {
+            InputSource::Usage("--csv reads stdin and takes no arguments".to_string())
+        }
+        _ => {
+            let flag = args.iter().find(|arg| arg.starts_with("--")).unwrap();
+            if ["--explain", "--json-ast", "--csv"].contains(&flag.as_str()) {
+                InputSource::Usage(format!("{} can't follow an expression argument", flag))
+            } else {
+                InputSource::Usage(format!("unknown option {}", flag))
+            }
+        }
+    }
+}
+// Evaluates command-line expressions in order in one session, so a later
+// one can use a variable an earlier one assigned.
+#[cfg(not(feature = "no_std"))]
+fn eval_args(exprs: &[String], limit: usize) -> Vec<Result<String, CalcError>> {
+    let mut repl = Repl {
+        max_input_len: limit,
+        ..Repl::default()
+    };
+    exprs
+        .iter()
+        .map(|expr| repl.eval_display(normalize_input(expr)))
+        .collect()
+}
+#[cfg(not(feature = "no_std"))]
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // Every input source shares the one limit.
+    let limit = match std::env::var("BASIC_ASTS_MAX_INPUT") {
+        Ok(limit) => limit.parse().unwrap_or_else(|_| {
+            eprintln!("ignoring invalid BASIC_ASTS_MAX_INPUT: {}", limit);
+            DEFAULT_MAX_INPUT_LEN
+        }),
+        Err(_) => DEFAULT_MAX_INPUT_LEN,
+    };
+    match input_source(std::env::var("BASIC_ASTS_EXPR").ok(), &args) {
+        // A failing expression doesn't stop the rest, but sets the exit
+        // status.
+        InputSource::Expressions(exprs) => {
+            let mut failed = false;
+            for result in eval_args(&exprs, limit) {
+                match result {
+                    Ok(text) => println!("{}", text),
+                    Err(e) => {
+                        eprintln!("err> {e}");
+                        failed = true;
+                    }
+                }
+            }
+            if failed {
+                std::process::exit(1);
+            }
+            return;
+        }
+        InputSource::Explain(input) => {
+            match check_input_len(&input, limit).and_then(|()| explain(&input)) {
+                Ok(text) => print!("{}", text),
+                Err(e) => {
+                    eprintln!("err> {e}");
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        // Parses without evaluating and prints the tree as `to_json` does.
+        InputSource::JsonAst(input) => {
+            let ast = check_input_len(&input, limit)
+                .and_then(|()| lex(&input))
+                .and_then(|tokens| parse(&tokens));
+            match ast {
+                Ok(ast) => println!("{}", to_json(&ast)),
+                Err(e) => {
+                    eprintln!("err> {e}");
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        // One expression per line of stdin; blank lines are skipped.
+        InputSource::Csv => {
+            let lines: Vec<String> = std::io::stdin()
+                .lines()
+                .map_while(Result::ok)
+                .filter(|line| !line.trim().is_empty())
+                .collect();
+            let inputs: Vec<&str> = lines.iter().map(|line| normalize_input(line)).collect();
+            print!("{}", to_csv(&run_many(&inputs, limit)));
+            return;
+        }
+        InputSource::Usage(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            std::process::exit(2);
+        }
+        InputSource::Repl => {}
+    }
+    let mut repl = Repl {
+        prompts: Prompts::from_lookup(|key| std::env::var(key).ok()),
+        max_input_len: limit,
+        ..Repl::default()
+    };
+    let mut prompt = repl.prompts.input.clone();
+    loop {
+        let output = match read_line(&prompt) {
+            Some(line) => repl.feed_line(&line),
+            None => match repl.finish() {
+                Some(output) => output,
+                None => break,
+            },
+        };
+        prompt = if repl.pending.is_empty() {
+            repl.prompts.input.clone()
+        } else {
+            repl.prompts.continuation.clone()
+        };
+        for warning in repl.warnings.drain(..) {
+            println!("{}{}", repl.prompts.warning, warning);
+        }
+        if let Some(text) = repl.prompts.render(&output) {
+            println!("{}", text);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn brackets_and_braces_group_like_parentheses() {
+        assert_eq!(run("[1+2]*3"), Ok(9));
+        assert_eq!(run("{2*[1+2]}^2"), Ok(36));
+    }
+    #[test]
+    fn mismatched_bracket_names_both_sides() {
+        assert_eq!(
+            run("[1+2)*3"),
+            Err(CalcError::MismatchedBracket {
+                expected: ']',
+                found: ')',
+                span: Span { start: 4, end: 5 },
+            })
+        );
+    }
+    #[test]
+    fn budget_stops_evaluation_after_that_many_operations() {
+        let ast = parse(&lex("1+2+3+4").unwrap()).unwrap();
+        let tight = EvalOptions {
+            budget: Some(2),
+            ..EvalOptions::default()
+        };
+        assert_eq!(
+            evaluate_with(&ast, &Env::new(), &tight),
+            Err(CalcError::BudgetExceeded(2))
+        );
+        let enough = EvalOptions {
+            budget: Some(3),
+            ..EvalOptions::default()
+        };
+        assert_eq!(evaluate_with(&ast, &Env::new(), &enough), Ok(10));
+    }
+    #[test]
+    fn double_star_is_exponentiation() {
+        assert_eq!(run("2 ** 10"), Ok(1024));
+        assert_eq!(run("2 * 10"), Ok(20));
+        assert_eq!(lex("2**3"), lex("2^3"));
+    }
+    // The debug-log events `f` emits on this thread.
+    fn captured_log(f: impl FnOnce()) -> Vec<String> {
+        CAPTURED_LOG.with(|log| *log.borrow_mut() = Some(Vec::new()));
+        f();
+        CAPTURED_LOG.with(|log| log.borrow_mut().take().unwrap_or_default())
+    }
+    #[test]
+    fn debug_log_reports_each_token_node_and_step() {
+        let events = captured_log(|| {
+            run("1+2*3").unwrap();
+        });
+        let count = |prefix: &str| events.iter().filter(|e| e.starts_with(prefix)).count();
+        assert_eq!(count("lexed "), 5);
+        assert_eq!(count("built "), 5);
+        assert_eq!(count("evaluated "), 2);
+        assert_eq!(events.len(), 12);
+    }
+    fn tree(input: &str) -> AST {
+        parse(&lex(input).unwrap()).unwrap()
+    }
+    fn env_of(bindings: &[(&str, Value)]) -> Env {
+        bindings
+            .iter()
+            .map(|(name, value)| (Symbol::intern(name), value.clone()))
+            .collect()
+    }
+    #[test]
+    fn partial_eval_collapses_a_fully_bound_expression() {
+        let env = env_of(&[("x", Value::Int(4))]);
+        assert_eq!(partial_eval(&tree("2 + 3 + x"), &env), AST::num(9));
+    }
+    #[test]
+    fn partial_eval_folds_what_is_bound_and_keeps_the_rest() {
+        let env = env_of(&[("x", Value::Int(4))]);
+        let ast = partial_eval(&tree("x * 2 + y"), &env);
+        assert_eq!(ast.to_string(), "8 + y");
+    }
+    #[test]
+    fn partial_eval_leaves_a_symbolic_expression_alone() {
+        let ast = tree("x * y");
+        assert_eq!(partial_eval(&ast, &Env::new()), ast);
+    }
+    #[test]
+    fn fold_constant_leaves_rand_unfolded() {
+        for input in ["rand(6)", "rand(1, 6) * 2"] {
+            assert_eq!(fold_constant(&tree(input)), Ok(tree(input)));
+        }
+    }
+    #[test]
+    fn normalize_input_strips_one_line_ending() {
+        assert_eq!(normalize_input("1+2\n"), "1+2");
+        assert_eq!(normalize_input("1+2\r\n"), "1+2");
+        assert_eq!(normalize_input("1+2"), "1+2");
+        assert_eq!(normalize_input(""), "");
+        assert_eq!(normalize_input("1+2\n\n"), "1+2\n");
+    }
+    #[test]
+    fn line_endings_do_not_reach_the_lexer() {
+        assert_eq!(run(normalize_input("6*7\r\n")), Ok(42));
+    }
+    #[test]
+    fn parse_and_parse_pratt_agree() {
+        let battery = [
+            "1+2*3",
+            "2^3^2",
+            "(1+2)*3",
+            "10-3-2",
+            "x*(y+2)/z",
+            "[1+2]*{3-1}",
+            "-2^2",
+            "|3-7|*2",
+            "max(1, 2+3, 4)",
+            "2**3**2-1",
+            "1 < 2",
+            "1+",
+            "",
+            "(1+2",
+            "1)",
+            "1 2",
+            "()",
+        ];
+        for input in battery {
+            let tokens = lex(input).unwrap();
+            assert_eq!(parse(&tokens), parse_pratt(&tokens), "{}", input);
+        }
+    }
+    #[test]
+    fn builders_make_the_tree_the_parser_would() {
+        let built = AST::add(
+            AST::num(1),
+            AST::mul(AST::var("x"), AST::pow(AST::num(3), AST::num(2))),
+        );
+        assert_eq!(built, tree("1+x*3^2"));
+        let env = env_of(&[("x", Value::Int(2))]);
+        assert_eq!(evaluate_with(&built, &env, &EvalOptions::default()), Ok(19));
+        let call = AST::call("max", vec![AST::neg(AST::num(4)), AST::abs(AST::num(-3))]);
+        assert_eq!(evaluate(&call), Ok(3));
+    }
+    #[test]
+    fn division_by_zero_points_at_the_operator() {
+        let error = run("1 + 8 / (4 - 4) * 2").unwrap_err();
+        assert_eq!(error, CalcError::DivisionByZero(Span { start: 6, end: 7 }));
+        assert_eq!(error.to_string(), "division by zero at position 6");
+        assert_eq!(
+            run("7 % 0"),
+            Err(CalcError::DivisionByZero(Span { start: 2, end: 3 }))
+        );
+    }
+    #[test]
+    fn small_step_reduces_one_operation_at_a_time() {
+        let mut ast = tree("(1+2)*3");
+        let mut shown = vec![ast.to_string()];
+        while let Some(next) = small_step(&ast, &Env::new()).unwrap() {
+            shown.push(next.to_string());
+            ast = next;
+        }
+        assert_eq!(shown, ["(1 + 2) * 3", "3 * 3", "9"]);
+    }
+    #[test]
+    fn small_step_reports_errors_when_it_reaches_them() {
+        let ast = small_step(&tree("1/0 + 2"), &Env::new());
+        assert!(matches!(ast, Err(CalcError::DivisionByZero(_))));
+    }
+    #[test]
+    fn bars_take_the_absolute_value() {
+        assert_eq!(run("|-5|"), Ok(5));
+        assert_eq!(run("|3 - 7|"), Ok(4));
+        assert_eq!(run("||2-5| - 10|"), Ok(7));
+        assert_eq!(run("2 * |1 - 4|"), Ok(6));
+    }
+    #[test]
+    fn unclosed_bars_are_an_error() {
+        assert_eq!(run("|1 + 2"), Err(CalcError::UnbalancedBars));
+    }
+    // Feeds each line to a fresh REPL, returning what each one produced.
+    fn session(lines: &[&str]) -> (Repl, Vec<ReplOutput>) {
+        let mut repl = Repl::default();
+        let outputs = lines.iter().map(|line| repl.feed_line(line)).collect();
+        (repl, outputs)
+    }
+    fn shown(text: &str) -> ReplOutput {
+        ReplOutput::Result(text.to_string())
+    }
+    #[test]
+    fn each_result_is_bound_to_its_number() {
+        let (repl, outputs) = session(&["2 + 3", "10", "_1 * _2", "_3 - ans"]);
+        assert_eq!(outputs, [shown("5"), shown("10"), shown("50"), shown("0")]);
+        assert_eq!(repl.env.get(&Symbol::intern("_3")), Some(&Value::Int(50)));
+        assert_eq!(repl.env.get(&Symbol::intern("_4")), Some(&Value::Int(0)));
+    }
+    #[test]
+    fn result_names_cannot_be_assigned() {
+        let (_, outputs) = session(&["_1 = 4"]);
+        assert_eq!(
+            outputs,
+            [ReplOutput::Error(CalcError::ReservedName("_1".to_string()))]
+        );
+    }
+    #[test]
+    fn mod_is_euclidean_where_percent_truncates() {
+        assert_eq!(run("-7 % 3"), Ok(-1));
+        assert_eq!(run("mod(-7, 3)"), Ok(2));
+        assert_eq!(run("7 % -3"), Ok(1));
+        assert_eq!(run("mod(7, -3)"), Ok(1));
+        assert_eq!(run("mod(-7, -3)"), Ok(2));
+    }
+    #[test]
+    fn mod_rejects_zero_and_overflow() {
+        assert!(matches!(
+            run("mod(1, 0)"),
+            Err(CalcError::DivisionByZero(_))
+        ));
+        assert_eq!(
+            call_builtin("mod", &[i64::MIN, -1]),
+            Err(CalcError::Overflow)
+        );
+    }
+    #[test]
+    fn lex_all_reports_every_bad_character() {
+        let (tokens, errors) = lex_all("1 @ 2 $ 3");
+        assert_eq!(
+            errors,
+            [
+                CalcError::UnknownCharacter('@', Span::at(2)),
+                CalcError::UnknownCharacter('$', Span::at(6)),
+            ]
+        );
+        assert_eq!(
+            tokens,
+            [
+                Token::Number(1, 10),
+                Token::Number(2, 10),
+                Token::Number(3, 10)
+            ]
+        );
+        assert_eq!(lex("1 @ 2 $ 3"), Err(errors[0].clone()));
+    }
+    #[test]
+    fn input_over_the_limit_is_rejected_before_lexing() {
+        assert_eq!(run_limited("1+2+3", 5), Ok(6));
+        assert_eq!(
+            run_limited("1+2+3+4", 5),
+            Err(CalcError::InputTooLong {
+                length: 7,
+                limit: 5
+            })
+        );
+        assert!(matches!(
+            run_limited("@@@@@@", 5),
+            Err(CalcError::InputTooLong { .. })
+        ));
+    }
+    #[test]
+    fn the_repl_applies_its_own_limit() {
+        let mut repl = Repl {
+            max_input_len: 3,
+            ..Repl::default()
+        };
+        assert_eq!(repl.feed_line("1+2"), shown("3"));
+        assert!(matches!(
+            repl.feed_line("1 + 2"),
+            ReplOutput::Error(CalcError::InputTooLong { .. })
+        ));
+        assert_eq!(
+            eval_args(&["1+2".to_string(), "10*10".to_string()], 3)[1],
+            Err(CalcError::InputTooLong {
+                length: 5,
+                limit: 3
+            })
+        );
+    }
+    #[test]
+    fn sign_mode_marks_positives_only() {
+        let (_, outputs) = session(&[":sign on", "2 + 3", "2 - 3", "0", "1.5", "1 < 2"]);
+        assert_eq!(
+            outputs[1..],
+            [
+                shown("+5"),
+                shown("-1"),
+                shown("0"),
+                shown("+1.5"),
+                shown("+1")
+            ]
+        );
+        let (_, outputs) = session(&["2 + 3"]);
+        assert_eq!(outputs, [shown("5")]);
+    }
+    #[test]
+    fn an_identifier_interns_to_one_symbol() {
+        assert_eq!(Symbol::intern("radius"), Symbol::intern("radius"));
+        assert_ne!(Symbol::intern("radius"), Symbol::intern("diameter"));
+        assert_eq!(Symbol::intern("radius").name(), "radius");
+        assert!(Symbol::intern("radius").is("radius"));
+        let tokens = lex("radius * radius").unwrap();
+        assert_eq!(tokens[0], tokens[2]);
+        assert_eq!(tokens[0], Token::Identifier(Symbol::intern("radius")));
+    }
+    #[test]
+    fn symbols_resolve_the_same_on_every_thread() {
+        let symbol = Symbol::intern("shared_name");
+        let from_thread = std::thread::spawn(|| Symbol::intern("shared_name"))
+            .join()
+            .unwrap();
+        assert_eq!(symbol, from_thread);
+    }
+    #[test]
+    fn format_expr_normalizes_spacing() {
+        assert_eq!(format_expr("1+2*3").unwrap(), "1 + 2 * 3");
+        assert_eq!(format_expr("  2^  3 -x ").unwrap(), "2 ^ 3 - x");
+        assert_eq!(format_expr("max( 1,2 )").unwrap(), "max(1, 2)");
+    }
+    #[test]
+    fn format_expr_keeps_parentheses_precedence_needs() {
+        assert_eq!(format_expr("(1+2)*3").unwrap(), "(1 + 2) * 3");
+        assert_eq!(format_expr("2^(1+1)").unwrap(), "2 ^ (1 + 1)");
+        assert_eq!(format_expr("10-(3-2)").unwrap(), "10 - (3 - 2)");
+    }
+    #[test]
+    fn format_reads_with_the_session_options() {
+        let (_, outputs) = session(&[
+            ":decimal comma",
+            ":format max(1;2,5)",
+            ":roman on",
+            ":format XII+1",
+            ":assoc sub right",
+            ":format 10-3-2",
+        ]);
+        assert_eq!(outputs[1], ReplOutput::Command("max(1, 2.5)".to_string()));
+        assert_eq!(outputs[3], ReplOutput::Command("12 + 1".to_string()));
+        assert_eq!(outputs[5], ReplOutput::Command("10 - (3 - 2)".to_string()));
+    }
+    #[test]
+    fn parse_prefix_returns_the_tokens_after_the_expression() {
+        let tokens = lex("1 + 2 * 3 ) , 4").unwrap();
+        let (ast, rest) = parse_prefix(&tokens).unwrap();
+        assert_eq!(evaluate(&ast), Ok(7));
+        assert_eq!(
+            rest,
+            [Token::RightParen, Token::Comma, Token::Number(4, 10)]
+        );
+        let tokens = lex("2 x").unwrap();
+        let (ast, rest) = parse_prefix(&tokens).unwrap();
+        assert_eq!(ast, AST::num(2));
+        assert_eq!(rest, [Token::Identifier(Symbol::intern("x"))]);
+    }
+    #[test]
+    fn parse_prefix_needs_a_complete_expression() {
+        assert!(parse_prefix(&lex("1 +").unwrap()).is_err());
+    }
+    #[test]
+    fn if_picks_a_branch_by_its_condition() {
+        assert_eq!(run("if(1 < 2, 10, 20)"), Ok(10));
+        assert_eq!(run("if(0, 10, 20)"), Ok(20));
+        assert_eq!(run("if(2 - 2, 10, 20 + 1)"), Ok(21));
+    }
+    #[test]
+    fn if_never_evaluates_the_other_branch() {
+        assert_eq!(run("if(1, 5, 1/0)"), Ok(5));
+        assert_eq!(run("if(0, undefined_name, 6)"), Ok(6));
+        assert!(matches!(
+            run("if(0, 5, 1/0)"),
+            Err(CalcError::DivisionByZero(_))
+        ));
+    }
+    #[test]
+    fn negative_hex_is_signed_or_a_bit_pattern() {
+        assert_eq!(format_radix(-1, 16, NegativeFormat::Signed), "-0x1");
+        assert_eq!(
+            format_radix(-1, 16, NegativeFormat::Bits),
+            "0xffffffffffffffff"
+        );
+        assert_eq!(format_radix(255, 16, NegativeFormat::Bits), "0xff");
+        let (_, outputs) = session(&[":base 16", "0 - 1", ":neg bits", "0 - 1", "-2 * 1"]);
+        assert_eq!(outputs[1], shown("-0x1"));
+        assert_eq!(outputs[3], shown("0xffffffffffffffff"));
+        assert_eq!(outputs[4], shown("0xfffffffffffffffe"));
+    }
+    #[test]
+    fn a_seed_fixes_the_rand_sequence() {
+        let draws = [":seed 42", "rand(1, 100)", "rand(1, 100)", "rand(1, 100)"];
+        let (_, first) = session(&draws);
+        let (_, second) = session(&draws);
+        assert_eq!(first, second);
+        for output in &first[1..] {
+            let ReplOutput::Result(text) = output else {
+                panic!("{:?}", output);
+            };
+            assert!((1..=100).contains(&text.parse::<i64>().unwrap()));
+        }
+    }
+    #[test]
+    fn rand_rejects_an_empty_range() {
+        assert_eq!(
+            Rng::seeded(1).range(5, 4),
+            Err(CalcError::EmptyRange { lo: 5, hi: 4 })
+        );
+        assert_eq!(Rng::seeded(1).range(7, 7), Ok(7));
+    }
+    #[test]
+    fn gcd_and_lcm_handle_zero_and_signs() {
+        assert_eq!(run("gcd(0, 0)"), Ok(0));
+        assert_eq!(run("gcd(0, 5)"), Ok(5));
+        assert_eq!(run("lcm(0, 5)"), Ok(0));
+        assert_eq!(run("gcd(-12, 18)"), Ok(6));
+        assert_eq!(run("lcm(-4, 6)"), Ok(12));
+    }
+    #[test]
+    fn lcm_divides_before_multiplying() {
+        let max = i64::MAX;
+        assert_eq!(call_builtin("lcm", &[max, max]), Ok(max));
+        assert_eq!(call_builtin("lcm", &[max - 1, 2]), Ok(max - 1));
+        assert_eq!(call_builtin("lcm", &[max, 2]), Err(CalcError::Overflow));
+        assert_eq!(
+            call_builtin("gcd", &[i64::MIN, 0]),
+            Err(CalcError::Overflow)
+        );
+    }
+    #[test]
+    fn decimal_comma_reads_commas_as_points() {
+        let options = LexOptions {
+            decimal_comma: true,
+            ..LexOptions::default()
+        };
+        let tokens = lex_spanned_with("3,5 + 1,5", &options).unwrap();
+        assert_eq!(evaluate(&parse_spanned(&tokens).unwrap()), Ok(5));
+        let tokens = lex_spanned_with("gcd(4; 6)", &options).unwrap();
+        assert_eq!(evaluate(&parse_spanned(&tokens).unwrap()), Ok(2));
+        let (_, outputs) = session(&[":decimal comma", "1,25 * 2"]);
+        assert_eq!(outputs[1], shown("2,5"));
+    }
+    #[test]
+    fn by_default_a_comma_separates_arguments() {
+        assert_eq!(run("gcd(4,6)"), Ok(2));
+        assert_eq!(
+            lex("3,5").unwrap(),
+            [Token::Number(3, 10), Token::Comma, Token::Number(5, 10)]
+        );
+    }
+    #[test]
+    fn evaluate_wide_survives_an_intermediate_past_i64() {
+        let ast = tree("(10000000000 * 10000000000) / 10000000000");
+        assert_eq!(evaluate(&ast), Ok(10_000_000_000));
+        assert_eq!(evaluate_wide(&ast), Ok(10_000_000_000));
+        assert_eq!(narrow(evaluate_wide(&ast).unwrap()), Ok(10_000_000_000));
+    }
+    #[test]
+    fn evaluate_wide_still_checks_the_final_result() {
+        let ast = tree("10000000000 * 10000000000");
+        assert_eq!(
+            narrow(evaluate_wide(&ast).unwrap()),
+            Err(CalcError::Overflow)
+        );
+        assert_eq!(evaluate_wide(&tree("2 ^ 127")), Err(CalcError::Overflow));
+    }
+    #[test]
+    fn folds_keep_their_behavior_on_the_fold_trait() {
+        let env = Env::new();
+        let folded = |input: &str| partial_eval(&tree(input), &env).to_string();
+        assert_eq!(folded("2 + 3 + x"), "5 + x");
+        assert_eq!(folded("-(2) * x"), "-2 * x");
+        assert_eq!(folded("if(1, x, 1/0)"), "x");
+        assert_eq!(folded("if(y, 1+1, 2)"), "if(y, 2, 2)");
+        assert_eq!(folded("1/0 + x"), "1 / 0 + x");
+        assert_eq!(folded("gcd(4, 6) * |0-3|"), "6");
+        for input in ["1 + 2 * 3", "2^10 - 1", "|3 - 7| * 2", "max(1, 5, 3)"] {
+            let ast = tree(input);
+            assert_eq!(
+                fold_constant(&ast),
+                Ok(AST::num(evaluate(&ast).unwrap())),
+                "{}",
+                input
+            );
+        }
+    }
+    #[test]
+    fn rand_survives_partial_evaluation() {
+        let env = env_of(&[("x", Value::Int(4))]);
+        let folded = |input: &str| partial_eval(&tree(input), &env).to_string();
+        assert_eq!(folded("rand(1,6) + x"), "rand(1, 6) + 4");
+        assert_eq!(folded("rand(2 * 3) * (x - 1)"), "rand(6) * 3");
+        assert_eq!(folded("rand(1,6) + x"), folded("rand(1,6) + x"));
+    }
+    // Yields characters one at a time, like a reader that never buffers a line.
+    struct Trickle(std::vec::IntoIter<char>);
+    impl Iterator for Trickle {
+        type Item = char;
+        fn next(&mut self) -> Option<char> {
+            self.0.next()
+        }
+    }
+    #[test]
+    fn lex_chars_reads_from_any_char_iterator() {
+        let chars: Vec<char> = "12 + x*3.5".chars().collect();
+        assert_eq!(lex_chars(Trickle(chars.into_iter())), lex("12 + x*3.5"));
+        assert_eq!(
+            lex_chars("1 @".chars()),
+            Err(CalcError::UnknownCharacter('@', Span::at(2)))
+        );
+    }
+    #[test]
+    fn transcript_records_lines_while_on() {
+        let (repl, _) = session(&["1", ":transcript on", "1+2", "1/0"]);
+        assert_eq!(
+            repl.transcript_text(),
+            "in> :transcript on\ntranscript on\nin> 1+2\nout> 3\n\
+             in> 1/0\nerr> division by zero at position 1\n"
+        );
+    }
+    #[test]
+    fn transcript_uses_the_configured_prompts() {
+        let mut repl = Repl {
+            prompts: Prompts::from_lookup(|key| match key {
+                "BASIC_ASTS_PROMPT" => Some("> ".to_string()),
+                "BASIC_ASTS_OUT_PROMPT" => Some(String::new()),
+                _ => None,
+            }),
+            ..Repl::default()
+        };
+        repl.feed_line(":transcript on");
+        repl.feed_line("6*7");
+        let expected = "> :transcript on\ntranscript on\n> 6*7\n42\n";
+        assert_eq!(repl.transcript_text(), expected);
+        let path = std::env::temp_dir().join("basic_asts_transcript_test.txt");
+        let command = format!(":transcript save {}", path.display());
+        assert!(matches!(repl.feed_line(&command), ReplOutput::Command(_)));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), expected);
+        std::fs::remove_file(path).unwrap();
+    }
+    #[test]
+    fn lex_all_errors_come_out_in_position_order() {
+        let (_, errors) = lex_all("1 ) @ 99999999999999999999 (] `x");
+        assert_eq!(
+            errors,
+            [
+                CalcError::UnknownCharacter('@', Span::at(4)),
+                CalcError::LiteralOverflow(Span { start: 6, end: 26 }),
+                CalcError::MismatchedBracket {
+                    expected: ')',
+                    found: ']',
+                    span: Span::at(28),
+                },
+                CalcError::UnterminatedIdentifier(Span { start: 30, end: 32 }),
+            ]
+        );
+    }
+    #[test]
+    fn lex_all_reports_each_spot_once() {
+        let (_, errors) = lex_all("#1 # 0x");
+        let starts: Vec<usize> = errors
+            .iter()
+            .filter_map(|e| e.span())
+            .map(|s| s.start)
+            .collect();
+        assert_eq!(starts, [0, 3, 6]);
+    }
+    fn evaluate_opts(input: &str, options: EvalOptions) -> Result<Value, CalcError> {
+        evaluate_value(&tree(input), &Env::new(), &options, &Rng::seeded(1))
+    }
+    #[test]
+    fn comparisons_are_numbers_by_default() {
+        assert_eq!(run("(1 < 2) + 3"), Ok(4));
+        assert_eq!(run("2 <= 1"), Ok(0));
+    }
+    #[test]
+    fn strict_mode_keeps_booleans_out_of_arithmetic() {
+        let strict = EvalOptions {
+            strict_bools: true,
+            ..EvalOptions::default()
+        };
+        assert_eq!(
+            evaluate_opts("(1 < 2) + 3", strict),
+            Err(CalcError::BooleanOperand("+".to_string()))
+        );
+        assert_eq!(evaluate_opts("int(1 < 2) + 3", strict), Ok(Value::Int(4)));
+        assert_eq!(
+            evaluate_opts("(1 < 2) == (3 < 4)", strict),
+            Ok(Value::Bool(true))
+        );
+        assert_eq!(evaluate_opts("if(1 < 2, 5, 6)", strict), Ok(Value::Int(5)));
+    }
+    #[test]
+    fn kept_groups_round_trip_only_where_written() {
+        assert_eq!(format_expr("(1+2)").unwrap(), "(1 + 2)");
+        assert_eq!(format_expr("1+2").unwrap(), "1 + 2");
+        assert_eq!(format_expr("1+(2*3)").unwrap(), "1 + (2 * 3)");
+        assert_eq!(tree("(1+2)"), AST::add(AST::num(1), AST::num(2)));
+    }
+    #[test]
+    fn kept_groups_evaluate_like_their_contents() {
+        let options = ParseOptions {
+            keep_groups: true,
+            ..ParseOptions::default()
+        };
+        let grouped = parse_spanned_with(&lex_spanned("(1+2)*-(3)").unwrap(), &options).unwrap();
+        assert_eq!(evaluate(&grouped), Ok(-9));
+        assert_eq!(partial_eval(&grouped, &Env::new()), AST::num(-9));
+    }
+    #[test]
+    fn explain_precedence_adds_the_implied_parentheses() {
+        assert_eq!(explain_precedence("1 + 2 * 3").unwrap(), "1 + (2 * 3)");
+        assert_eq!(explain_precedence("1 - 2 - 3").unwrap(), "(1 - 2) - 3");
+        assert_eq!(explain_precedence("2 ^ 3 ^ 2").unwrap(), "2 ^ (3 ^ 2)");
+        assert_eq!(explain_precedence("-2^2").unwrap(), "-(2 ^ 2)");
+        assert_eq!(explain_precedence("7").unwrap(), "7");
+    }
+    #[test]
+    fn prompts_fall_back_to_their_defaults() {
+        let prompts = Prompts::from_lookup(|_| None);
+        assert_eq!(prompts.input, "in> ");
+        assert_eq!(prompts.continuation, "...> ");
+        assert_eq!(prompts.output, "out> ");
+        assert_eq!(prompts.error, "err> ");
+    }
+    #[test]
+    fn prompt_overrides_may_be_blank() {
+        let prompts = Prompts::from_lookup(|key| match key {
+            "BASIC_ASTS_PROMPT" => Some(String::new()),
+            "BASIC_ASTS_ERR_PROMPT" => Some("! ".to_string()),
+            _ => None,
+        });
+        assert_eq!(prompts.input, "");
+        assert_eq!(prompts.output, "out> ");
+        assert_eq!(prompts.error, "! ");
+    }
+    #[test]
+    fn bool_words_show_comparisons_as_true_and_false() {
+        let (_, outputs) = session(&["2 > 1", ":bool words", "2 > 1", "0 < -1", "ans + 2"]);
+        assert_eq!(outputs[0], shown("1"));
+        assert_eq!(outputs[2..], [shown("true"), shown("false"), shown("2")]);
+    }
+    #[test]
+    fn bool_words_never_take_a_sign() {
+        let (_, outputs) = session(&[":bool words", ":sign on", "2 > 1", "2 + 1"]);
+        assert_eq!(outputs[2..], [shown("true"), shown("+3")]);
+    }
+    #[test]
+    fn two_character_operators_lex_as_one_token() {
+        assert_eq!(
+            lex("1<=2").unwrap(),
+            [
+                Token::Number(1, 10),
+                Token::Operator('≤'),
+                Token::Number(2, 10)
+            ]
+        );
+        assert_eq!(lex_spanned("a==b").unwrap()[1].1, Span { start: 1, end: 3 });
+    }
+    #[test]
+    fn a_space_splits_a_two_character_operator() {
+        assert_eq!(
+            lex("1< =2").unwrap(),
+            [
+                Token::Number(1, 10),
+                Token::Operator('<'),
+                Token::Assign,
+                Token::Number(2, 10)
+            ]
+        );
+    }
+    #[test]
+    fn modular_add_and_multiply_wrap_around() {
+        let mod_7 = EvalOptions {
+            modulus: Some(7),
+            ..EvalOptions::default()
+        };
+        let mod_p = EvalOptions {
+            modulus: Some(1_000_000_007),
+            ..EvalOptions::default()
+        };
+        assert_eq!(evaluate_opts("5 + 4", mod_7), Ok(Value::Int(2)));
+        assert_eq!(evaluate_opts("5 * 4", mod_7), Ok(Value::Int(6)));
+        assert_eq!(evaluate_opts("2 - 5", mod_7), Ok(Value::Int(4)));
+        assert_eq!(evaluate_opts("9", mod_7), Ok(Value::Int(2)));
+        assert_eq!(evaluate_opts("2 ^ 100", mod_p), Ok(Value::Int(976_371_285)));
+    }
+    #[test]
+    fn modular_division_multiplies_by_the_inverse() {
+        let mod_7 = EvalOptions {
+            modulus: Some(7),
+            ..EvalOptions::default()
+        };
+        assert_eq!(evaluate_opts("3 / 2", mod_7), Ok(Value::Int(5)));
+        assert_eq!(evaluate_opts("(3 / 2) * 2", mod_7), Ok(Value::Int(3)));
+        assert_eq!(
+            evaluate_opts("1 / 7", mod_7),
+            Err(CalcError::NoInverse {
+                value: 0,
+                modulus: 7
+            })
+        );
+    }
+    #[test]
+    fn modular_exponents_are_plain_integers() {
+        let mod_7 = EvalOptions {
+            modulus: Some(7),
+            ..EvalOptions::default()
+        };
+        let mod_p = EvalOptions {
+            modulus: Some(1_000_000_007),
+            ..EvalOptions::default()
+        };
+        assert_eq!(evaluate_opts("2 ^ 10", mod_7), Ok(Value::Int(2)));
+        assert_eq!(evaluate_opts("3 ^ 7", mod_7), Ok(Value::Int(3)));
+        assert_eq!(evaluate_opts("2 ^ 1000000007", mod_p), Ok(Value::Int(2)));
+        assert_eq!(evaluate_opts("2 ^ (5 + 5)", mod_7), Ok(Value::Int(2)));
+    }
+    #[test]
+    fn a_negative_modular_exponent_inverts_the_base() {
+        let mod_7 = EvalOptions {
+            modulus: Some(7),
+            ..EvalOptions::default()
+        };
+        assert_eq!(evaluate_opts("2 ^ -1", mod_7), Ok(Value::Int(4)));
+        assert_eq!(evaluate_opts("3 ^ -2", mod_7), Ok(Value::Int(4)));
+        assert_eq!(
+            evaluate_opts("7 ^ -1", mod_7),
+            Err(CalcError::NoInverse {
+                value: 0,
+                modulus: 7
+            })
+        );
+    }
+    #[test]
+    fn modular_remainder_uses_the_integer_operands() {
+        let mod_7 = EvalOptions {
+            modulus: Some(7),
+            ..EvalOptions::default()
+        };
+        assert_eq!(evaluate_opts("(5 + 5) % 4", mod_7), Ok(Value::Int(2)));
+        assert_eq!(evaluate_opts("-9 % 4", mod_7), Ok(Value::Int(6)));
+    }
+    #[test]
+    fn modulus_must_be_prime() {
+        assert!(is_prime(2) && is_prime(7) && is_prime(9_223_372_036_854_775_783));
+        assert!(!is_prime(1) && !is_prime(9) && !is_prime(3_215_031_751));
+        let (_, outputs) = session(&[":modulus 8", ":modulus 7", "6 + 3"]);
+        assert!(matches!(outputs[0], ReplOutput::Error(_)));
+        assert_eq!(outputs[2], shown("2"));
+    }
+    #[test]
+    fn eq_ignore_spans_compares_shape_only() {
+        let parsed = parse_spanned(&lex_spanned("f(1 + 2 * x, -|y|)").unwrap()).unwrap();
+        let built = AST::call(
+            "f",
+            vec![
+                AST::add(AST::num(1), AST::mul(AST::num(2), AST::var("x"))),
+                AST::neg(AST::abs(AST::var("y"))),
+            ],
+        );
+        assert_ne!(parsed, built);
+        assert!(eq_ignore_spans(&parsed, &built));
+        assert!(!eq_ignore_spans(
+            &AST::add(AST::num(1), AST::num(2)),
+            &AST::sub(AST::num(1), AST::num(2))
+        ));
+    }
+    #[test]
+    fn is_complete_tells_balanced_input_from_unfinished() {
+        assert!(is_complete("1 + 2"));
+        assert!(is_complete("(1 + 2) * 3"));
+        assert!(is_complete("1 + 2)"));
+        assert!(is_complete(""));
+        assert!(!is_complete("(1 + 2 *"));
+        assert!(!is_complete("(1 + 2"));
+        assert!(!is_complete("3 +"));
+        assert!(!is_complete("gcd(4,"));
+    }
+    #[test]
+    fn unfinished_lines_wait_for_the_rest() {
+        let (_, outputs) = session(&["(1 + 2", ":base 16", "* 5)"]);
+        assert_eq!(
+            outputs,
+            [
+                ReplOutput::Incomplete,
+                ReplOutput::Command("output base 16".to_string()),
+                shown("0xb")
+            ]
+        );
+    }
+    #[test]
+    fn width_8_overflows_where_width_16_does_not() {
+        let width_8 = EvalOptions {
+            width: Some(8),
+            ..EvalOptions::default()
+        };
+        let width_16 = EvalOptions {
+            width: Some(16),
+            ..EvalOptions::default()
+        };
+        let width_32 = EvalOptions {
+            width: Some(32),
+            ..EvalOptions::default()
+        };
+        assert_eq!(
+            evaluate_opts("200 + 100", width_8),
+            Err(CalcError::ExceedsWidth {
+                value: 300,
+                width: 8
+            })
+        );
+        assert_eq!(evaluate_opts("200 + 100", width_16), Ok(Value::Int(300)));
+        assert_eq!(evaluate_opts("-100 - 28", width_8), Ok(Value::Int(-128)));
+        assert_eq!(
+            evaluate_opts("2147483647 + 1", width_32),
+            Err(CalcError::ExceedsWidth {
+                value: 2_147_483_648,
+                width: 32
+            })
+        );
+    }
+    #[test]
+    fn width_64_is_the_full_range() {
+        let (_, outputs) = session(&[":width 8", "100 * 2", ":width 64", "100 * 2", ":width 12"]);
+        assert!(matches!(outputs[1], ReplOutput::Error(_)));
+        assert_eq!(outputs[3], shown("200"));
+        assert!(matches!(outputs[4], ReplOutput::Error(_)));
+    }
+    #[test]
+    fn run_many_keeps_one_record_per_input() {
+        let records = run_many(&["1+2", "1/0", "x", "2*3"], DEFAULT_MAX_INPUT_LEN);
+        assert_eq!(records[0], ("1+2".to_string(), Ok(3)));
+        assert_eq!(
+            records[1],
+            (
+                "1/0".to_string(),
+                Err(CalcError::DivisionByZero(Span::at(1)))
+            )
+        );
+        assert_eq!(
+            records[2],
+            (
+                "x".to_string(),
+                Err(CalcError::UndefinedVariable("x".to_string()))
+            )
+        );
+        assert_eq!(records[3], ("2*3".to_string(), Ok(6)));
+    }
+    #[test]
+    fn a_trailing_operator_is_its_own_error() {
+        assert_eq!(run("3 +"), Err(CalcError::TrailingOperator('+')));
+        assert_eq!(run("3 * -"), Err(CalcError::TrailingOperator('-')));
+        assert_eq!(
+            run("3 <=").unwrap_err().to_string(),
+            "incomplete expression: ends with operator '<='"
+        );
+        assert_eq!(run("(3"), Err(CalcError::UnbalancedParens));
+        assert_eq!(run(""), Err(CalcError::UnexpectedEnd));
+    }
+    #[test]
+    fn powers_use_the_whole_i64_range() {
+        assert_eq!(run("2 ^ 62"), Ok(4_611_686_018_427_387_904));
+        assert_eq!(run("3 ^ 39"), Ok(4_052_555_153_018_976_267));
+        assert_eq!(run("(-2) ^ 63"), Ok(i64::MIN));
+        assert_eq!(run("1 ^ 5000000000"), Ok(1));
+        assert_eq!(run("(-1) ^ 5000000001"), Ok(-1));
+        assert_eq!(run("0 ^ 0"), Ok(1));
+    }
+    #[test]
+    fn powers_past_i64_overflow() {
+        assert_eq!(run("2 ^ 63"), Err(CalcError::Overflow));
+        assert_eq!(run("10 ^ 19"), Err(CalcError::Overflow));
+        assert_eq!(run("2 ^ 4294967296"), Err(CalcError::Overflow));
+    }
+    #[test]
+    fn negative_exponents_leave_the_integers() {
+        assert_eq!(run("2 ^ -1"), Err(CalcError::NotAnInteger(0.5)));
+        for base in -7i64..8 {
+            for exponent in 0u32..24 {
+                assert_eq!(
+                    checked_power(base, exponent as u64),
+                    base.checked_pow(exponent)
+                );
+            }
+        }
+    }
+    #[test]
+    fn explain_shows_every_stage() {
+        assert_eq!(
+            explain("1+2*3").unwrap(),
+            "tokens: 1 + 2 * 3\n\
+             tree:\n  Binary +\n    Number 1\n    Binary *\n      Number 2\n      Number 3\n\
+             rpn: 1 2 3 * +\n\
+             result: 7\n"
+        );
+        assert!(explain("1/0")
+            .unwrap()
+            .ends_with("error: division by zero at position 1\n"));
+        assert!(explain("1+").is_err());
+    }
+    #[test]
+    fn each_overflow_policy_handles_the_same_sum() {
+        let checked = EvalOptions {
+            overflow: OverflowPolicy::Checked,
+            ..EvalOptions::default()
+        };
+        let wrapping = EvalOptions {
+            overflow: OverflowPolicy::Wrapping,
+            ..EvalOptions::default()
+        };
+        let saturating = EvalOptions {
+            overflow: OverflowPolicy::Saturating,
+            ..EvalOptions::default()
+        };
+        let sum = "9223372036854775807 + 1";
+        assert_eq!(
+            evaluate_opts(sum, checked).map(|value| value.to_string()),
+            Ok("9223372036854775808".to_string())
+        );
+        assert_eq!(evaluate_opts(sum, wrapping), Ok(Value::Int(i64::MIN)));
+        assert_eq!(evaluate_opts(sum, saturating), Ok(Value::Int(i64::MAX)));
+    }
+    #[test]
+    fn policies_cover_powers_and_narrow_widths() {
+        let wrapping = EvalOptions {
+            overflow: OverflowPolicy::Wrapping,
+            ..EvalOptions::default()
+        };
+        let saturating = EvalOptions {
+            overflow: OverflowPolicy::Saturating,
+            ..EvalOptions::default()
+        };
+        let wrapping_8 = EvalOptions {
+            width: Some(8),
+            overflow: OverflowPolicy::Wrapping,
+            ..EvalOptions::default()
+        };
+        let saturating_8 = EvalOptions {
+            width: Some(8),
+            overflow: OverflowPolicy::Saturating,
+            ..EvalOptions::default()
+        };
+        assert_eq!(evaluate_opts("2 ^ 64", wrapping), Ok(Value::Int(0)));
+        assert_eq!(
+            evaluate_opts("2 ^ 70", saturating),
+            Ok(Value::Int(i64::MAX))
+        );
+        assert_eq!(
+            evaluate_opts("(-2) ^ 71", saturating),
+            Ok(Value::Int(i64::MIN))
+        );
+        assert_eq!(evaluate_opts("100 + 100", wrapping_8), Ok(Value::Int(-56)));
+        assert_eq!(
+            evaluate_opts("100 + 100", saturating_8),
+            Ok(Value::Int(127))
+        );
+    }
+    #[test]
+    fn magnitude_suffixes_scale_literals() {
+        assert_eq!(run("3k"), Ok(3000));
+        assert_eq!(run("3K + 1"), Ok(3001));
+        assert_eq!(run("2M"), Ok(2_000_000));
+        assert_eq!(run("1.5k"), Ok(1500));
+        assert_eq!(run("12G"), Ok(12_000_000_000));
+    }
+    #[test]
+    fn a_suffix_that_overflows_is_a_literal_error() {
+        assert_eq!(
+            run("9999999999G"),
+            Err(CalcError::LiteralOverflow(Span { start: 0, end: 11 }))
+        );
+    }
+    #[test]
+    fn sampling_accepts_a_correct_simplification() {
+        let original = tree("2 + 3 + x * 1");
+        assert!(probably_equivalent(
+            &original,
+            &simplify(&original),
+            "x",
+            50
+        ));
+        assert!(probably_equivalent(&tree("x * 2"), &tree("x + x"), "x", 50));
+    }
+    #[test]
+    fn sampling_catches_a_wrong_simplification() {
+        assert!(!probably_equivalent(
+            &tree("x * 2"),
+            &tree("x * x"),
+            "x",
+            50
+        ));
+        assert!(!probably_equivalent(
+            &tree("(x + 1) ^ 2"),
+            &tree("x ^ 2 + 1"),
+            "x",
+            50
+        ));
+    }
+    #[test]
+    fn float_and_int_convert_between_the_number_kinds() {
+        let plain = EvalOptions::default();
+        assert_eq!(evaluate_opts("float(3)", plain), Ok(Value::Float(3.0)));
+        assert_eq!(evaluate_opts("float(7) / 2", plain), Ok(Value::Float(3.5)));
+        assert_eq!(evaluate_opts("int(3.9)", plain), Ok(Value::Int(3)));
+        assert_eq!(evaluate_opts("int(0 - 3.9)", plain), Ok(Value::Int(-3)));
+    }
+    #[test]
+    fn int_rejects_what_no_integer_holds() {
+        let plain = EvalOptions::default();
+        assert_eq!(
+            evaluate_opts("int(1/0.0)", plain),
+            Err(CalcError::NotFinite(f64::INFINITY))
+        );
+        assert_eq!(
+            evaluate_opts("int(10000000000.0 * 10000000000.0)", plain),
+            Err(CalcError::Overflow)
+        );
+    }
+    #[test]
+    fn rich_json_adds_precedence_and_associativity() {
+        let rich = to_json_rich(&tree("2 * 3"));
+        assert!(
+            rich.contains(r#""op":"*","precedence":3,"associativity":"left""#),
+            "{}",
+            rich
+        );
+        assert!(to_json_rich(&tree("2 ^ 3")).contains(r#""associativity":"right""#));
+        assert!(!to_json(&tree("2 * 3")).contains("precedence"));
+    }
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+    #[test]
+    fn the_expression_variable_wins_over_arguments() {
+        assert_eq!(
+            input_source(Some("1+2".to_string()), &strings(&["3"])),
+            InputSource::Expressions(strings(&["1+2"]))
+        );
+        assert_eq!(
+            input_source(Some(" ".to_string()), &strings(&["3"])),
+            InputSource::Expressions(strings(&["3"]))
+        );
+    }
+    fn usage_error(args: &[&str]) -> String {
+        match input_source(None, &strings(args)) {
+            InputSource::Usage(message) => message,
+            source => panic!("{:?} for {:?}", source, args),
+        }
+    }
+    #[test]
+    fn unrecognised_arguments_are_a_usage_error() {
+        assert_eq!(usage_error(&["--foo"]), "unknown option --foo");
+        assert_eq!(usage_error(&["-1", "--bogus"]), "unknown option --bogus");
+        assert_eq!(
+            usage_error(&["--explain"]),
+            "--explain takes exactly one expression"
+        );
+        assert_eq!(
+            usage_error(&["--explain", "a", "b"]),
+            "--explain takes exactly one expression"
+        );
+        assert_eq!(
+            usage_error(&["--csv", "x"]),
+            "--csv reads stdin and takes no arguments"
+        );
+    }
+    #[test]
+    fn without_input_the_repl_runs() {
+        assert_eq!(input_source(None, &[]), InputSource::Repl);
+        assert_eq!(input_source(Some(String::new()), &[]), InputSource::Repl);
+    }
+    #[test]
+    fn calls_are_checked_against_each_arity_kind() {
+        let message = |input: &str| run(input).unwrap_err().to_string();
+        assert_eq!(message("abs(1, 2)"), "abs expects 1 argument, got 2");
+        assert_eq!(message("mod(7)"), "mod expects 2 arguments, got 1");
+        assert_eq!(message("max()"), "max expects at least 1 argument, got 0");
+        assert_eq!(
+            message("rand(1, 2, 3)"),
+            "rand expects 1 to 2 arguments, got 3"
+        );
+        assert_eq!(run("abs(-3)"), Ok(3));
+        assert_eq!(run("max(3, 9, 4)"), Ok(9));
+        assert_eq!(run("rand(4, 4)"), Ok(4));
+        assert_eq!(
+            run("nope(1)"),
+            Err(CalcError::UnknownFunction("nope".to_string()))
+        );
+    }
+    fn with_units(input: &str) -> Result<String, CalcError> {
+        evaluate_units(input, &Env::new()).map(|quantity| quantity.to_string())
+    }
+    #[test]
+    fn units_add_when_they_match() {
+        assert_eq!(with_units("5m + 2m").unwrap(), "7 m");
+        assert_eq!(with_units("3km + 2km").unwrap(), "5 km");
+        assert_eq!(with_units("1 + 2").unwrap(), "3");
+    }
+    #[test]
+    fn a_unit_is_not_a_magnitude_suffix() {
+        assert_eq!(with_units("5kg").unwrap(), "5 kg");
+        assert_eq!(with_units("2k * 3m").unwrap(), "6000 m");
+        assert_eq!(
+            with_units("3km - 500m").unwrap_err().to_string(),
+            "incompatible units: km and m"
+        );
+        assert_eq!(
+            lex("5kg").unwrap(),
+            [
+                Token::Number(5, 10),
+                Token::Identifier(Symbol::intern("kg"))
+            ]
+        );
+        assert_eq!(run("2k"), Ok(2000));
+    }
+    #[test]
+    fn units_that_differ_do_not_add() {
+        assert_eq!(
+            with_units("5m + 2s").unwrap_err().to_string(),
+            "incompatible units: m and s"
+        );
+    }
+    #[test]
+    fn products_and_quotients_combine_units() {
+        assert_eq!(with_units("5m * 2s").unwrap(), "10 m*s");
+        assert_eq!(with_units("10m / 2s").unwrap(), "5 m/s");
+        assert_eq!(with_units("2m * 3m").unwrap(), "6 m^2");
+        assert_eq!(with_units("6m / 3m").unwrap(), "2");
+    }
+    #[test]
+    fn reset_keeps_the_seed_and_config_shows_it() {
+        let (_, seeded) = session(&[":seed 42", "rand(1, 100)", "rand(1, 100)"]);
+        let (mut repl, _) = session(&[":seed 42", "rand(1, 100)", ":reset"]);
+        assert!(repl.run_command("config").unwrap().contains("seed 42\n"));
+        assert_eq!(repl.feed_line("rand(1, 100)"), seeded[2]);
+        repl.feed_line(":seed off");
+        assert!(repl.run_command("config").unwrap().contains("seed off\n"));
+    }
+    #[test]
+    fn config_reflects_changes_and_reset_restores_defaults() {
+        let mut repl = Repl::default();
+        let fresh = repl.run_command("config").unwrap();
+        assert!(fresh.contains("base 10\n") && fresh.contains("width 64\n"));
+        repl.feed_line(":base 16");
+        repl.feed_line(":overflow wrapping");
+        repl.feed_line("x = 3");
+        let changed = repl.run_command("config").unwrap();
+        assert!(changed.contains("base 16\n") && changed.contains("overflow wrapping\n"));
+        repl.feed_line(":reset");
+        assert_eq!(repl.run_command("config").unwrap(), fresh);
+        assert_eq!(repl.feed_line("x"), shown("3"));
+    }
+    #[test]
+    fn a_leading_plus_is_accepted_and_ignored() {
+        assert_eq!(run("+5"), Ok(5));
+        assert_eq!(run("3 + +4"), Ok(7));
+        assert_eq!(run("+(2*3)"), Ok(6));
+        assert_eq!(run("-+2"), Ok(-2));
+        assert_eq!(run("+"), Err(CalcError::TrailingOperator('+')));
+    }
+    #[test]
+    fn swap_exchanges_two_variables() {
+        let (mut repl, _) = session(&["a = 1", "b = 2"]);
+        assert_eq!(
+            repl.feed_line(":swap a b"),
+            ReplOutput::Command("a = 2, b = 1".to_string())
+        );
+        assert_eq!(repl.env[&Symbol::intern("a")], Value::Int(2));
+        assert_eq!(repl.env[&Symbol::intern("b")], Value::Int(1));
+        assert_eq!(
+            repl.feed_line(":swap a c"),
+            ReplOutput::Error(CalcError::UndefinedVariable("c".to_string()))
+        );
+        assert_eq!(repl.env[&Symbol::intern("a")], Value::Int(2));
+    }
+    #[test]
+    fn the_vm_agrees_with_the_tree_walker() {
+        let env = env_of(&[("x", Value::Int(7))]);
+        let inputs = [
+            "1 + 2 * 3",
+            "-(x - 10) ^ 2",
+            "|3 - x|",
+            "if(x > 5, 1, 1/0)",
+            "if(0, 1/0, 4)",
+            "gcd(x * 6, 4) + max(1, x)",
+            "float(x) / 2 * 4",
+            "x / 0",
+            "y + 1",
+            "2 ^ 63",
+        ];
+        for input in inputs {
+            let ast = tree(input);
+            assert_eq!(
+                run_vm(&compile(&ast), &env),
+                evaluate_with(&ast, &env, &EvalOptions::default()),
+                "{}",
+                input
+            );
+        }
+    }
+    // Run with `--ignored --nocapture` to compare the two.
+    #[test]
+    #[ignore]
+    fn benchmark_the_vm_against_the_tree_walker() {
+        let env = env_of(&[("x", Value::Int(7))]);
+        let ast = tree("(x + 1) * (x - 1) + x * x * 3 - 12 / (x + 1)");
+        let timing = time_evaluations(&ast, &env, 200_000).unwrap();
+        println!("{}", timing);
+    }
+    #[test]
+    fn backticks_quote_an_identifier() {
+        let tokens = lex("`my var` + 1").unwrap();
+        assert_eq!(tokens[0], Token::Identifier(Symbol::intern("my var")));
+        assert_eq!(tree("`a b` + x").to_string(), "`a b` + x");
+        let (_, outputs) = session(&["`my var` = 4", "`my var` * 2", "`if` = 1"]);
+        assert_eq!(outputs, [shown("4"), shown("8"), shown("1")]);
+    }
+    #[test]
+    fn an_unterminated_backtick_is_a_lex_error() {
+        let error = lex("1 + `oops").unwrap_err();
+        assert_eq!(
+            error,
+            CalcError::UnterminatedIdentifier(Span { start: 4, end: 9 })
+        );
+        assert_eq!(
+            error.to_string(),
+            "unterminated quoted identifier at position 4"
+        );
+    }
+    #[test]
+    fn subtraction_chains_can_associate_right() {
+        let (_, outputs) = session(&[
+            "10 - 3 - 2",
+            ":assoc sub right",
+            "10 - 3 - 2",
+            "64 / 8 / 2",
+            "10 - 3 + 2",
+            "2 * 3 - 1 - 1",
+            ":assoc sub left",
+            "10 - 3 - 2",
+        ]);
+        assert_eq!(outputs[0], shown("5"));
+        assert_eq!(outputs[2], shown("9"));
+        assert_eq!(outputs[3], shown("16"));
+        assert_eq!(outputs[4], shown("9"));
+        assert_eq!(outputs[5], shown("6"));
+        assert_eq!(outputs[7], shown("5"));
+    }
+    #[test]
+    fn assoc_takes_only_left_or_right() {
+        let (mut repl, _) = session(&[":assoc sub right"]);
+        assert!(repl.config_text().contains("assoc sub right\n"));
+        assert!(matches!(
+            repl.feed_line(":assoc sub up"),
+            ReplOutput::Error(_)
+        ));
+    }
+    #[test]
+    fn reset_all_returns_to_a_fresh_session() {
+        let (mut repl, _) = session(&[":transcript on", ":base 16", "x = 3", "x + 1"]);
+        repl.max_input_len = 10;
+        assert!(!repl.transcript.is_empty());
+        repl.feed_line(":reset all");
+        assert!(repl.env.is_empty());
+        assert!(repl.transcript.is_empty());
+        assert!(!repl.recording);
+        assert_eq!(repl.results, 0);
+        assert_eq!(repl.base, 10);
+        assert_eq!(repl.max_input_len, 10);
+        assert_eq!(
+            repl.feed_line("ans"),
+            ReplOutput::Error(CalcError::UndefinedVariable("ans".to_string()))
+        );
+        assert!(matches!(repl.feed_line("_1"), ReplOutput::Error(_)));
+        assert!(matches!(repl.feed_line(":undo"), ReplOutput::Error(_)));
+    }
+    #[test]
+    fn batch_results_as_csv_rows() {
+        let records = run_many(
+            &["1 + 2", "1 / 0", "max(1, 2)", "2 +"],
+            DEFAULT_MAX_INPUT_LEN,
+        );
+        assert_eq!(
+            to_csv(&records),
+            "input,result,error\n\
+             1 + 2,3,\n\
+             1 / 0,,division by zero at position 2\n\
+             \"max(1, 2)\",2,\n\
+             2 +,,incomplete expression: ends with operator '+'\n"
+        );
+    }
+    #[test]
+    fn csv_fields_are_escaped() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a\"b"), "\"a\"\"b\"");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(input_source(None, &["--csv".to_string()]), InputSource::Csv);
+    }
+    #[test]
+    fn simplify_folds_a_negated_literal() {
+        assert_eq!(simplify(&tree("-5")), AST::num(-5));
+        assert_eq!(simplify(&tree("-5")).to_string(), "-5");
+        assert_eq!(simplify(&tree("-2.5")), AST::Float(-2.5));
+        assert!(eq_ignore_spans(
+            &simplify(&tree("3 - -4")),
+            &AST::sub(AST::num(3), AST::num(-4))
+        ));
+    }
+    #[test]
+    fn simplify_cancels_double_negation() {
+        assert_eq!(simplify(&tree("--5")), AST::num(5));
+        assert_eq!(simplify(&tree("---5")), AST::num(-5));
+        assert_eq!(simplify(&tree("--x")), tree("x"));
+    }
+    #[test]
+    fn a_folded_base_keeps_its_parentheses() {
+        let folded = simplify(&tree("(-2) ^ 2"));
+        assert_eq!(folded.to_string(), "(-2) ^ 2");
+        assert_eq!(evaluate(&folded), Ok(4));
+    }
+    fn parse_unmixed(input: &str) -> Result<AST, CalcError> {
+        let options = ParseOptions {
+            reject_mixed: true,
+            ..ParseOptions::default()
+        };
+        parse_spanned_with(&lex_spanned(input).unwrap(), &options)
+    }
+    #[test]
+    fn strict_mixing_rejects_arithmetic_beside_a_comparison() {
+        let error = parse_unmixed("1 + 2 < 3").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "ambiguous mix of '<' and '+' at position 6; add parentheses"
+        );
+        assert!(parse_unmixed("3 >= x * 2").is_err());
+        assert!(parse_spanned(&lex_spanned("1 + 2 < 3").unwrap()).is_ok());
+    }
+    #[test]
+    fn strict_mixing_accepts_parenthesized_operands() {
+        let grouped = parse_unmixed("(1 + 2) < 3").unwrap();
+        assert_eq!(evaluate(&grouped), Ok(0));
+        assert!(parse_unmixed("1 < 2").is_ok());
+        assert!(parse_unmixed("-x < abs(y - 1)").is_ok());
+    }
+    #[test]
+    fn cost_counts_operations_and_weights_powers() {
+        let cost = |input| estimate_cost(&tree(input));
+        assert_eq!(cost("7"), 0);
+        assert_eq!(cost("1 + 2 * 3 - x"), 3);
+        assert_eq!(cost("2 ^ 10 + -x"), POWER_COST + 2);
+        assert_eq!(cost("max(1 + 1, 2)"), 2);
+        assert_eq!(cost("if(x, 1, 2 ^ 3)"), POWER_COST + 1);
+    }
+    #[test]
+    fn superscript_digits_are_an_exponent() {
+        assert_eq!(run("2²"), Ok(4));
+        assert_eq!(run("2¹⁰"), Ok(1024));
+        assert_eq!(run("3³ + 1"), Ok(28));
+        assert_eq!(run("10⁰"), Ok(1));
+        assert_eq!(run("2⁵⁶⁷⁸⁹"), Err(CalcError::Overflow));
+        assert_eq!(lex_spanned("2²").unwrap()[1].1, Span::at(1));
+    }
+    #[test]
+    fn a_superscript_must_follow_a_number() {
+        assert!(matches!(
+            run("x²"),
+            Err(CalcError::UnknownCharacter('²', _))
+        ));
+        assert!(matches!(
+            run("2 ²"),
+            Err(CalcError::UnknownCharacter('²', _))
+        ));
+    }
+    #[test]
+    fn a_scripted_session_gives_structured_outputs() {
+        let (mut repl, outputs) = session(&[
+            "x = 2", "", "(x +", "3) * 2", ":base 16", "x * 8", "1/0", ":nope",
+        ]);
+        assert_eq!(
+            outputs,
+            [
+                shown("2"),
+                ReplOutput::Empty,
+                ReplOutput::Incomplete,
+                shown("10"),
+                ReplOutput::Command("output base 16".to_string()),
+                shown("0x10"),
+                ReplOutput::Error(CalcError::DivisionByZero(Span::at(1))),
+                ReplOutput::Error(CalcError::UnknownCommand("nope".to_string())),
+            ]
+        );
+        assert_eq!(repl.feed_line("2 *"), ReplOutput::Incomplete);
+        assert!(matches!(
+            repl.finish(),
+            Some(ReplOutput::Error(CalcError::TrailingOperator('*')))
+        ));
+        assert_eq!(repl.finish(), None);
+    }
+    #[test]
+    fn outputs_render_through_the_prompts() {
+        let prompts = Prompts::from_lookup(|_| None);
+        assert_eq!(prompts.render(&shown("3")).unwrap(), "out> 3");
+        assert_eq!(prompts.render(&ReplOutput::Empty), None);
+    }
+    #[test]
+    fn json_bindings_fill_the_environment() {
+        assert_eq!(eval_with_json("x * 2 + y", r#"{"x": 3, "y": 1}"#), Ok(7));
+        assert_eq!(eval_with_json("x * 2", r#" { "x" : 1.5 } "#), Ok(3));
+        assert_eq!(
+            eval_with_json("`a b` + 1", r#"{"a b": -4, "q\"": true}"#),
+            Ok(-3)
+        );
+        assert_eq!(eval_with_json("1", "{}"), Ok(1));
+        assert_eq!(
+            eval_with_json("x + z", r#"{"x": 3}"#),
+            Err(CalcError::UndefinedVariable("z".to_string()))
+        );
+    }
+    #[test]
+    fn malformed_json_is_an_error() {
+        let message = |json| eval_with_json("x", json).unwrap_err().to_string();
+        assert_eq!(
+            message(r#"{"x": 3"#),
+            "invalid JSON: expected ',' or '}' at byte 7"
+        );
+        assert_eq!(
+            message(r#"{"x": "3"}"#),
+            "invalid JSON: expected a number or boolean at byte 6"
+        );
+        for json in [r#"{"x": 1} x"#, "[1]", "{x: 1}", ""] {
+            assert!(eval_with_json("x", json).is_err(), "{}", json);
+        }
+    }
+    #[test]
+    fn a_path_reaches_a_leaf() {
+        use PathStep::*;
+        let ast = tree("1 + 2 * 3");
+        assert_eq!(get_at(&ast, &[Right, Left]), Ok(&AST::num(2)));
+        assert_eq!(get_at(&ast, &[]), Ok(&ast));
+        let call = tree("max(1, -x)");
+        assert_eq!(get_at(&call, &[Arg(1), Operand]).unwrap().to_string(), "x");
+    }
+    #[test]
+    fn replace_at_builds_a_new_tree() {
+        use PathStep::*;
+        let ast = tree("1 + 2 * 3");
+        let replaced = replace_at(&ast, &[Right, Left], AST::num(10)).unwrap();
+        assert_eq!(replaced.to_string(), "1 + 10 * 3");
+        assert_eq!(ast.to_string(), "1 + 2 * 3");
+    }
+    #[test]
+    fn a_path_past_the_tree_is_an_error() {
+        use PathStep::*;
+        let ast = tree("1 + 2 * 3");
+        assert_eq!(
+            get_at(&ast, &[Right, Left, Left]),
+            Err(CalcError::InvalidPath(2))
+        );
+        assert_eq!(
+            replace_at(&ast, &[Operand], AST::num(0)),
+            Err(CalcError::InvalidPath(0))
+        );
+        assert!(get_at(&tree("max(1, -x)"), &[Arg(2)]).is_err());
+    }
+    #[test]
+    fn an_arrow_binds_the_result_on_its_right() {
+        let (repl, outputs) = session(&["3 + 4 => total", "total * 2", "x = 1", "x >= 1"]);
+        assert_eq!(outputs, [shown("7"), shown("14"), shown("1"), shown("1")]);
+        assert_eq!(repl.env[&Symbol::intern("total")], Value::Int(7));
+        assert_eq!(lex("a=>b").unwrap()[1], Token::Arrow);
+    }
+    #[test]
+    fn an_arrow_needs_an_assignable_name() {
+        let (_, outputs) = session(&["1 => ans", "1 => 2"]);
+        assert_eq!(
+            outputs,
+            [
+                ReplOutput::Error(CalcError::ReservedName("ans".to_string())),
+                ReplOutput::Error(CalcError::UnexpectedToken(Token::Arrow)),
+            ]
+        );
+        assert!(!is_complete("1 + 2 =>"));
+    }
+    #[test]
+    fn result_kind_reads_the_shape_of_the_tree() {
+        let kind = |input| result_kind(&tree(input));
+        assert_eq!(kind("1 + 2 * x"), ResultKind::Int);
+        assert_eq!(kind("int(2.5)"), ResultKind::Int);
+        assert_eq!(kind("1 + 2 < 3"), ResultKind::Bool);
+        assert_eq!(kind("if(x, 1 < 2, 3 == 3)"), ResultKind::Bool);
+        assert_eq!(kind("(1 + 2.5) * 3"), ResultKind::Float);
+        assert_eq!(kind("float(3) - 1"), ResultKind::Float);
+        assert_eq!(kind("if(x, 1, 2.0)"), ResultKind::Float);
+        assert_eq!(kind("-|2.5|"), ResultKind::Float);
+    }
+    #[test]
+    fn literals_keep_their_radix_when_printed() {
+        assert_eq!(tree("0xFF + 1").to_string(), "0xff + 1");
+        assert_eq!(tree("0b101 - 3").to_string(), "0b101 - 3");
+        assert_eq!(simplify(&tree("-0x10")).to_string(), "-0x10");
+        assert_eq!(format_expr("0x1F*2").unwrap(), "0x1f * 2");
+    }
+    #[test]
+    fn the_radix_does_not_change_the_value() {
+        assert_eq!(run("0xFF + 1"), Ok(256));
+        assert_eq!(run("0b101 * 0o17"), Ok(75));
+        assert_eq!(run("-0x10 == -16"), Ok(1));
+        assert_eq!(run("0x10k"), Ok(16000));
+    }
+    #[test]
+    fn an_evaluator_runs_against_each_environment() {
+        let f = into_evaluator(tree("x * x + 1"));
+        let ys: Vec<i64> = (-2..=2)
+            .map(|x| f(&env_of(&[("x", Value::Int(x))])).unwrap())
+            .collect();
+        assert_eq!(ys, [5, 2, 1, 2, 5]);
+        assert_eq!(
+            f(&Env::new()),
+            Err(CalcError::UndefinedVariable("x".to_string()))
+        );
+    }
+    // `main` prints `to_json` of the parsed tree for this source.
+    #[test]
+    fn json_ast_prints_the_tree_without_evaluating() {
+        assert_eq!(
+            input_source(None, &strings(&["--json-ast", "1 + 2 * 3"])),
+            InputSource::JsonAst("1 + 2 * 3".to_string())
+        );
+        assert_eq!(
+            to_json(&tree("1 + 2 * 3")),
+            r#"{"type":"BinaryOp","op":"+","left":{"type":"Number","value":1},"#.to_string()
+                + r#""right":{"type":"BinaryOp","op":"*","left":{"type":"Number","value":2},"#
+                + r#""right":{"type":"Number","value":3}}}"#
+        );
+        assert_eq!(
+            to_json(&tree("x / 0")),
+            r#"{"type":"BinaryOp","op":"/","left":{"type":"Variable","name":"x"},"#.to_string()
+                + r#""right":{"type":"Number","value":0}}"#
+        );
+    }
+    #[test]
+    fn pct_and_change_return_floats() {
+        let plain = EvalOptions::default();
+        assert_eq!(evaluate_opts("pct(25, 200)", plain), Ok(Value::Float(12.5)));
+        assert_eq!(
+            evaluate_opts("change(50, 75)", plain),
+            Ok(Value::Float(50.0))
+        );
+        assert_eq!(
+            evaluate_opts("change(80, 60)", plain),
+            Ok(Value::Float(-25.0))
+        );
+        assert_eq!(result_kind(&tree("pct(1, 2)")), ResultKind::Float);
+    }
+    #[test]
+    fn pct_and_change_reject_a_zero_denominator() {
+        let plain = EvalOptions::default();
+        assert!(matches!(
+            evaluate_opts("pct(1, 0)", plain),
+            Err(CalcError::DivisionByZero(_))
+        ));
+        assert!(matches!(
+            evaluate_opts("change(0, 5)", plain),
+            Err(CalcError::DivisionByZero(_))
+        ));
+        assert!(matches!(
+            evaluate_opts("pct(1)", plain),
+            Err(CalcError::WrongArgCount { .. })
+        ));
+    }
+    #[test]
+    fn precision_sets_the_decimal_places_shown() {
+        let (_, outputs) = session(&[
+            "2 / 3.0",
+            ":precision 2",
+            "2 / 3.0",
+            ":precision 6",
+            "2 / 3.0",
+            "1.5",
+            "ans * 2",
+        ]);
+        assert_eq!(outputs[0], shown("0.6666666666666666"));
+        assert_eq!(outputs[2], shown("0.67"));
+        assert_eq!(outputs[4], shown("0.666667"));
+        assert_eq!(outputs[5], shown("1.500000"));
+        assert_eq!(outputs[6], shown("3.000000"));
+    }
+    #[test]
+    fn precision_can_trim_trailing_zeros() {
+        let (mut repl, outputs) =
+            session(&[":precision 6 trim", "1.5", "2.0", ":precision off", "1.5"]);
+        assert_eq!(
+            outputs,
+            [
+                ReplOutput::Command("float precision 6 trim".to_string()),
+                shown("1.5"),
+                shown("2"),
+                ReplOutput::Command("float precision off".to_string()),
+                shown("1.5"),
+            ]
+        );
+        for line in [":precision 99", ":precision 2 x"] {
+            assert!(matches!(repl.feed_line(line), ReplOutput::Error(_)));
+        }
+    }
+    #[test]
+    fn strict_literals_reject_a_leading_zero() {
+        let strict = LexOptions {
+            reject_leading_zero: true,
+            ..LexOptions::default()
+        };
+        assert_eq!(
+            lex_spanned_with("1 + 010", &strict),
+            Err(CalcError::LeadingZero(Span { start: 4, end: 7 }))
+        );
+        assert_eq!(
+            lex_spanned_with("010", &strict).unwrap_err().to_string(),
+            "leading zero at position 0; write 0o for octal"
+        );
+        assert!(lex_spanned_with("007.5", &strict).is_err());
+        assert!(lex_spanned_with("0 + 0.5 + 0o10 + 100", &strict).is_ok());
+    }
+    #[test]
+    fn by_default_a_leading_zero_is_decimal() {
+        assert_eq!(run("010"), Ok(10));
+    }
+    #[test]
+    fn undo_steps_back_through_assignments() {
+        let x = Symbol::intern("x");
+        let (mut repl, _) = session(&["x = 1", "x = 2", "3 + 4"]);
+        assert_eq!(
+            repl.feed_line(":undo"),
+            ReplOutput::Command("x = 1".to_string())
+        );
+        assert_eq!(repl.env.get(&x), Some(&Value::Int(1)));
+        assert_eq!(
+            repl.feed_line(":undo"),
+            ReplOutput::Command("removed x".to_string())
+        );
+        assert_eq!(repl.env.get(&x), None);
+        assert_eq!(
+            repl.feed_line(":undo"),
+            ReplOutput::Error(CalcError::NothingToUndo)
+        );
+    }
+    #[test]
+    fn undo_reverts_a_swap_as_one_step() {
+        let (mut repl, _) = session(&["a = 1", "b = 2", ":swap a b"]);
+        assert_eq!(
+            repl.feed_line(":undo"),
+            ReplOutput::Command("a = 1, b = 2".to_string())
+        );
+        assert_eq!(repl.env[&Symbol::intern("a")], Value::Int(1));
+    }
+    fn with_double() -> FunctionRegistry {
+        let mut functions = FunctionRegistry::default();
+        functions.register("double", |args| match args {
+            [x] => x.checked_mul(2).ok_or(CalcError::Overflow),
+            _ => Err(CalcError::WrongArgCount {
+                name: "double".to_string(),
+                expected: Arity::Exact(1),
+                found: args.len(),
+            }),
+        });
+        functions
+    }
+    #[test]
+    fn a_registered_function_can_be_called() {
+        let functions = with_double();
+        let eval = |input| evaluate_with_functions(&tree(input), &Env::new(), &functions);
+        assert_eq!(eval("double(21)"), Ok(42));
+        assert_eq!(eval("double(gcd(12, 18)) + abs(-1)"), Ok(13));
+        assert!(matches!(
+            eval("double(1, 2)"),
+            Err(CalcError::WrongArgCount { .. })
+        ));
+        assert!(matches!(
+            eval("gcd(4)"),
+            Err(CalcError::WrongArgCount { .. })
+        ));
+    }
+    #[test]
+    fn an_unregistered_function_is_unknown() {
+        let triple = evaluate_with_functions(&tree("triple(1)"), &Env::new(), &with_double());
+        assert_eq!(
+            triple,
+            Err(CalcError::UnknownFunction("triple".to_string()))
+        );
+        let gcd =
+            evaluate_with_functions(&tree("gcd(4, 6)"), &Env::new(), &FunctionRegistry::empty());
+        assert_eq!(gcd, Err(CalcError::UnknownFunction("gcd".to_string())));
+        assert_eq!(
+            run("double(2)"),
+            Err(CalcError::UnknownFunction("double".to_string()))
+        );
+    }
+    #[test]
+    fn a_top_level_tuple_evaluates_each_element() {
+        assert_eq!(evaluate_tuple("1+1, 2*3, 4"), Ok(vec![2, 6, 4]));
+        assert_eq!(evaluate_tuple("max(1, 5), 2"), Ok(vec![5, 2]));
+        assert_eq!(evaluate_tuple("7"), Ok(vec![7]));
+    }
+    #[test]
+    fn the_repl_prints_a_tuple_in_parentheses() {
+        let (mut repl, outputs) = session(&["1+1, 2*3, 4", "x = 3", "x, x * 2", "ans"]);
+        assert_eq!(
+            outputs,
+            [shown("(2, 6, 4)"), shown("3"), shown("(3, 6)"), shown("3")]
+        );
+        assert_eq!(repl.feed_line("1, "), ReplOutput::Incomplete);
+    }
+    fn float_warnings(input: &str, enabled: bool) -> Vec<String> {
+        let options = EvalOptions {
+            float_warnings: enabled,
+            ..EvalOptions::default()
+        };
+        evaluate_with_warnings(&tree(input), &Env::new(), &options, &Rng::seeded(1))
+            .unwrap()
+            .1
+    }
+    #[test]
+    fn nearly_equal_large_floats_warn_when_subtracted() {
+        let warnings = float_warnings("100000000.1 - 100000000.0", true);
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].contains("cancels"), "{}", warnings[0]);
+        assert_eq!(
+            float_warnings("100000000.1 - 100000000.0 * 1.0", true).len(),
+            1
+        );
+        assert!(float_warnings("0.0 / 0.0", true)[0].contains("NaN"));
+        assert!(float_warnings("10.0 ^ 400.0", true)[0].contains("inf"));
+    }
+    #[test]
+    fn ordinary_float_arithmetic_does_not_warn() {
+        assert!(float_warnings("2.5 - 1.0 + 3.0 * 4.0", true).is_empty());
+        assert!(float_warnings("5.0 - 5.0", true).is_empty());
+        assert!(float_warnings("0.0 / 0.0", false).is_empty());
+    }
+    #[test]
+    fn the_repl_keeps_only_the_last_lines_warnings() {
+        let (mut repl, _) = session(&[":warn on", "100000000.1 - 100000000.0"]);
+        assert_eq!(repl.warnings.len(), 1);
+        repl.feed_line("1.5 - 1.0");
+        assert!(repl.warnings.is_empty());
+    }
+    #[test]
+    fn solve_finds_the_unknown_in_a_linear_equation() {
+        assert_eq!(solve_equation("2*x + 3 = 11", "x"), Ok(Value::Int(4)));
+        assert_eq!(
+            solve_equation("(y - 1) / 4 = 3 * y", "y"),
+            Ok(Value::Float(-1.0 / 11.0))
+        );
+        assert_eq!(
+            solve_equation("10 - x = 2 * (x + 1)", "x"),
+            Ok(Value::Float(8.0 / 3.0))
+        );
+        let (_, outputs) = session(&["a = 2", "solve(a*x + 3 = 11, x)", "s = solve(x / 2 = 5, x)"]);
+        assert_eq!(outputs[1..], [shown("4"), shown("10")]);
+    }
+    #[test]
+    fn solve_rejects_a_nonlinear_equation() {
+        let error = solve_equation("x * x = 4", "x").unwrap_err();
+        assert!(matches!(error, CalcError::NotLinear { .. }));
+        assert_eq!(error.to_string(), "x * x is not linear in x");
+        let (_, outputs) = session(&["solve(2^x = 8, x)"]);
+        assert!(matches!(
+            outputs[0],
+            ReplOutput::Error(CalcError::NotLinear { .. })
+        ));
+    }
+    #[test]
+    fn solve_needs_one_solution_of_an_equation() {
+        assert_eq!(
+            solve_equation("x + 1 = x", "x"),
+            Err(CalcError::NoUniqueSolution("x".to_string()))
+        );
+        assert_eq!(solve_equation("2 * x", "x"), Err(CalcError::NotAnEquation));
+    }
+    fn fingerprint_of(input: &str) -> u64 {
+        fingerprint(&parse_spanned(&lex_spanned(input).unwrap()).unwrap())
+    }
+    #[test]
+    fn equal_trees_have_equal_fingerprints() {
+        assert_eq!(
+            fingerprint_of("1 + 2 * max(x, 3.5)"),
+            fingerprint_of("1+2*max(x,3.5)")
+        );
+        // Pinned, so a change to the hashing shows up here.
+        assert_eq!(fingerprint_of("1"), 0x4787_dbab_a9f3_52d4);
+    }
+    #[test]
+    fn different_trees_have_different_fingerprints() {
+        assert_ne!(fingerprint_of("1 + 2"), fingerprint_of("2 + 1"));
+        assert_ne!(fingerprint_of("1 + 2"), fingerprint_of("1 - 2"));
+        assert_ne!(fingerprint_of("x"), fingerprint_of("y"));
+        assert_ne!(fingerprint_of("max(1, 2)"), fingerprint_of("min(1, 2)"));
+    }
+    #[test]
+    fn inexact_division_promotes_to_a_rational() {
+        let rational = EvalOptions {
+            rationals: true,
+            ..EvalOptions::default()
+        };
+        assert_eq!(evaluate_opts("1/3", rational), Ok(Value::Rational(1, 3)));
+        assert_eq!(
+            evaluate_opts("1 + 1/3", rational),
+            Ok(Value::Rational(4, 3))
+        );
+        assert_eq!(evaluate_opts("-6/4", rational), Ok(Value::Rational(-3, 2)));
+        assert_eq!(
+            evaluate_opts("(2/3) ^ 2", rational),
+            Ok(Value::Rational(4, 9))
+        );
+        assert_eq!(
+            evaluate_opts("(2/3) ^ -1", rational),
+            Ok(Value::Rational(3, 2))
+        );
+        assert_eq!(
+            evaluate_opts("1/2 + 0.25", rational),
+            Ok(Value::Float(0.75))
+        );
+        assert!(matches!(
+            evaluate_opts("(1/3) / 0", rational),
+            Err(CalcError::DivisionByZero(_))
+        ));
+    }
+    #[test]
+    fn exact_division_stays_an_integer() {
+        let rational = EvalOptions {
+            rationals: true,
+            ..EvalOptions::default()
+        };
+        assert_eq!(evaluate_opts("4/2", rational), Ok(Value::Int(2)));
+        assert_eq!(evaluate_opts("1/3 + 2/3", rational), Ok(Value::Int(1)));
+        assert_eq!(evaluate_opts("1/3 < 1/2", rational), Ok(Value::Int(1)));
+        assert_eq!(evaluate_opts("int(7/2)", rational), Ok(Value::Int(3)));
+        assert_eq!(evaluate(&tree("1/3")), Ok(0));
+        let (_, outputs) = session(&[":rational on", "1 + 1/3", "4/2"]);
+        assert_eq!(outputs[1..], [shown("4/3"), shown("2")]);
+    }
+    #[test]
+    fn the_cursor_looks_two_characters_ahead() {
+        let mut cursor = CharCursor::new("<=x".chars());
+        assert_eq!(cursor.peek2(), Some('='));
+        assert_eq!(cursor.peek(), Some('<'));
+        assert_eq!(cursor.pos(), 0);
+        cursor.bump();
+        assert_eq!(cursor.peek2(), Some('x'));
+        cursor.bump();
+        assert_eq!(cursor.peek2(), None);
+        let mut one = CharCursor::new("a".chars());
+        assert_eq!(one.peek2(), None);
+        assert_eq!(one.peek(), Some('a'));
+    }
+    #[test]
+    fn eat_consumes_only_the_expected_character() {
+        let mut cursor = CharCursor::new("<=x".chars());
+        assert!(!cursor.eat('='));
+        assert_eq!(cursor.pos(), 0);
+        assert!(cursor.eat('<'));
+        assert!(cursor.eat('='));
+        assert_eq!(cursor.pos(), 2);
+        assert_eq!(cursor.bump(), Some('x'));
+        assert!(!cursor.eat('x'));
+        assert_eq!(cursor.bump(), None);
+        assert_eq!(cursor.pos(), 3);
+    }
+    fn lex_unknown(input: &str, unknown: UnknownChars) -> Result<Vec<Token>, CalcError> {
+        let options = LexOptions {
+            unknown,
+            ..LexOptions::default()
+        };
+        let tokens = lex_spanned_with(input, &options)?;
+        Ok(tokens.into_iter().map(|(token, _)| token).collect())
+    }
+    #[test]
+    fn a_stray_character_fails_by_default() {
+        let stray = Err(CalcError::UnknownCharacter('@', Span::at(2)));
+        assert_eq!(lex_unknown("2 @ 3", UnknownChars::Fail), stray);
+        assert_eq!(lex("2 @ 3"), stray);
+    }
+    #[test]
+    fn a_stray_character_can_be_skipped() {
+        assert_eq!(
+            lex_unknown("2@3", UnknownChars::Skip),
+            Ok(vec![Token::Number(2, 10), Token::Number(3, 10)])
+        );
+    }
+    #[test]
+    fn a_stray_character_can_stand_for_an_operator() {
+        let tokens = lex_unknown("2 @ 3", UnknownChars::Replace('*')).unwrap();
+        assert_eq!(evaluate(&parse(&tokens).unwrap()), Ok(6));
+        assert_eq!(
+            lex_unknown("2 @ 3", UnknownChars::Replace('$')),
+            Err(CalcError::UnknownCharacter('@', Span::at(2)))
+        );
+    }
+    #[test]
+    fn numbers_are_spelled_out_in_words() {
+        assert_eq!(number_words(0), "zero");
+        assert_eq!(number_words(42), "forty-two");
+        assert_eq!(number_words(-7), "negative seven");
+        assert_eq!(number_words(100), "one hundred");
+        assert_eq!(number_words(-1042), "negative one thousand forty-two");
+        assert_eq!(number_words(1_000_000), "one million");
+        assert_eq!(number_words(2_000_000_013), "two billion thirteen");
+        let min = number_words(i64::MIN);
+        assert!(min.starts_with("negative nine quintillion two hundred twenty-three"));
+        assert!(min.ends_with("seven hundred seventy-five thousand eight hundred eight"));
+    }
+    #[test]
+    fn words_mode_spells_integer_results_only() {
+        let (_, outputs) = session(&[":words on", "6 * 7", "1.5"]);
+        assert_eq!(outputs[1..], [shown("forty-two"), shown("1.5")]);
+    }
+    fn lex_roman(input: &str, roman: RomanNumerals) -> Result<Vec<Token>, CalcError> {
+        let options = LexOptions {
+            roman,
+            ..LexOptions::default()
+        };
+        let tokens = lex_spanned_with(input, &options)?;
+        Ok(tokens.into_iter().map(|(token, _)| token).collect())
+    }
+    #[test]
+    fn roman_numerals_lex_as_numbers() {
+        let tokens = lex_roman("IV + VI", RomanNumerals::Strict).unwrap();
+        assert_eq!(evaluate(&parse(&tokens).unwrap()), Ok(10));
+        assert_eq!(
+            lex_roman("MCMXCIV", RomanNumerals::Strict),
+            Ok(vec![Token::Number(1994, 10)])
+        );
+        assert_eq!(
+            lex_roman("IV", RomanNumerals::Off),
+            Ok(vec![Token::Identifier(Symbol::intern("IV"))])
+        );
+        assert_eq!(
+            lex_roman("IVy", RomanNumerals::Strict),
+            Ok(vec![Token::Identifier(Symbol::intern("IVy"))])
+        );
+    }
+    #[test]
+    fn strict_mode_rejects_an_invalid_numeral() {
+        assert_eq!(
+            lex_roman("IIII", RomanNumerals::Strict),
+            Err(CalcError::InvalidNumeral {
+                numeral: "IIII".to_string(),
+                span: Span { start: 0, end: 4 },
+            })
+        );
+        assert!(matches!(
+            lex_roman("1 + VV", RomanNumerals::Strict),
+            Err(CalcError::InvalidNumeral { .. })
+        ));
+        assert_eq!(
+            lex_roman("IIII", RomanNumerals::Lenient),
+            Ok(vec![Token::Number(4, 10)])
+        );
+    }
+    #[test]
+    fn a_malformed_numeral_is_invalid_in_either_mode() {
+        for mode in [RomanNumerals::Lenient, RomanNumerals::Strict] {
+            assert_eq!(
+                lex_roman("VX", mode),
+                Err(CalcError::InvalidNumeral {
+                    numeral: "VX".to_string(),
+                    span: Span { start: 0, end: 2 },
+                })
+            );
+            assert!(matches!(
+                lex_roman("2 * IC", mode),
+                Err(CalcError::InvalidNumeral { .. })
+            ));
+        }
+        assert_eq!(parse_roman("XCIX"), Some(99));
+        assert_eq!(
+            lex_roman("IIII", RomanNumerals::Strict)
+                .unwrap_err()
+                .to_string(),
+            "invalid roman numeral: IIII"
+        );
+    }
+    #[test]
+    fn roman_mode_prints_positive_results_as_numerals() {
+        assert_eq!(to_roman(3999).as_deref(), Some("MMMCMXCIX"));
+        assert_eq!(to_roman(0), None);
+        let (_, outputs) = session(&[":roman strict", "IV + VI", "I - V"]);
+        assert_eq!(outputs[1..], [shown("X"), shown("-4")]);
+    }
+    #[test]
+    fn free_variables_collects_each_name_once() {
+        assert_eq!(
+            free_variables(&tree("x * y + x - z")),
+            strings(&["x", "y", "z"]).into_iter().collect()
+        );
+        assert!(free_variables(&tree("max(1, 2)")).is_empty());
+        assert_eq!(free_variables(&tree("abs(-a)")).len(), 1);
+    }
+    #[test]
+    fn check_defined_reports_every_missing_variable() {
+        let ast = tree("a * x + b - y");
+        let env = env_of(&[("a", Value::Int(1)), ("b", Value::Int(2))]);
+        assert_eq!(check_defined(&ast, &env), Err(strings(&["x", "y"])));
+        let env = env_of(&[
+            ("a", Value::Int(1)),
+            ("b", Value::Int(2)),
+            ("x", Value::Int(3)),
+            ("y", Value::Int(4)),
+        ]);
+        assert_eq!(check_defined(&ast, &env), Ok(()));
+    }
+    #[test]
+    fn a_series_evaluates_once_per_value() {
+        assert_eq!(
+            evaluate_series(&tree("x*x"), "x", &[0, 1, 2, 3]),
+            [Ok(0), Ok(1), Ok(4), Ok(9)]
+        );
+        let results = evaluate_series(&tree("6 / x"), "x", &[2, 0, 3]);
+        assert_eq!(results[0], Ok(3));
+        assert!(matches!(results[1], Err(CalcError::DivisionByZero(_))));
+        assert_eq!(results[2], Ok(2));
+    }
+    #[test]
+    fn eval_lines_pulls_lines_only_as_needed() {
+        let pulled = std::cell::Cell::new(0);
+        let lines = ["1 + 1\n", "", "2 * 3\r\n", "1 / 0"].iter().map(|line| {
+            pulled.set(pulled.get() + 1);
+            line.to_string()
+        });
+        let mut results = eval_lines(lines);
+        assert_eq!(pulled.get(), 0);
+        assert_eq!(results.next(), Some(Ok(2)));
+        assert_eq!(pulled.get(), 1);
+        assert_eq!(results.next(), Some(Ok(6)));
+        assert_eq!(pulled.get(), 3);
+        assert!(matches!(
+            results.next(),
+            Some(Err(CalcError::DivisionByZero(_)))
+        ));
+        assert_eq!(results.next(), None);
+    }
+    #[test]
+    fn timing_runs_both_evaluators_the_given_number_of_times() {
+        let ast = tree("(1 + 2) * 3 ^ 2 - max(4, 5)");
+        let timing = time_evaluations(&ast, &Env::new(), 1000).unwrap();
+        assert_eq!(timing.iterations, 1000);
+        assert!(timing.tree > std::time::Duration::ZERO);
+        assert!(timing.vm > std::time::Duration::ZERO);
+        assert!(
+            timing.to_string().ends_with("per evaluation (1000 runs)"),
+            "{}",
+            timing
+        );
+    }
+    #[test]
+    fn timing_reports_an_evaluation_error() {
+        assert!(matches!(
+            time_evaluations(&tree("1 / 0"), &Env::new(), 10),
+            Err(CalcError::DivisionByZero(_))
+        ));
+        let (_, outputs) = session(&[":bench 1 + 1", ":bench y"]);
+        assert!(matches!(&outputs[0], ReplOutput::Command(text) if text.starts_with("tree ")));
+        assert!(matches!(outputs[1], ReplOutput::Error(_)));
+    }
+    #[test]
+    fn negative_and_fractional_exponents_give_floats() {
+        let plain = EvalOptions::default();
+        assert_eq!(evaluate_opts("2 ^ -1", plain), Ok(Value::Float(0.5)));
+        assert_eq!(evaluate_opts("2 ^ -3", plain), Ok(Value::Float(0.125)));
+        assert_eq!(evaluate_opts("4 ^ 0.5", plain), Ok(Value::Float(2.0)));
+        assert_eq!(evaluate_opts("2 ^ 10", plain), Ok(Value::Int(1024)));
+    }
+    #[test]
+    fn zero_to_a_negative_power_divides_by_zero() {
+        let plain = EvalOptions::default();
+        assert!(matches!(
+            evaluate_opts("0 ^ -1", plain),
+            Err(CalcError::DivisionByZero(_))
+        ));
+    }
+    #[test]
+    fn explain_parse_narrates_each_decision() {
+        assert_eq!(
+            explain_parse("1 + 2 * 3").unwrap(),
+            strings(&[
+                "operand 1",
+                "push +",
+                "operand 2",
+                "keep +: * binds tighter",
+                "push *",
+                "operand 3",
+                "pop *: built 2 * 3",
+                "pop +: built 1 + 2 * 3",
+            ])
+        );
+        let steps = explain_parse("(1 - 2) - max(3, 4)").unwrap();
+        assert!(
+            steps.contains(&"matched ( with )".to_string()),
+            "{:?}",
+            steps
+        );
+        let (_, outputs) = session(&[":explain-parse 1*2"]);
+        assert!(matches!(
+            &outputs[0],
+            ReplOutput::Command(text) if text.starts_with("operand 1\npush *")
+        ));
+    }
+    #[test]
+    fn explain_parse_follows_the_session_options() {
+        let (_, outputs) = session(&[":assoc sub right", ":explain-parse 10-3-2"]);
+        assert!(matches!(
+            &outputs[1],
+            ReplOutput::Command(text) if text.ends_with("pop -: built 10 - (3 - 2)")
+        ));
+        let (_, outputs) = session(&[":decimal comma", ":explain-parse max(1;2)"]);
+        assert!(matches!(&outputs[1], ReplOutput::Command(_)));
+    }
+    fn exactly(input: &str) -> String {
+        evaluate_exact(&tree(input)).unwrap().to_string()
+    }
+    #[test]
+    fn exact_evaluation_escalates_past_i64() {
+        assert_eq!(evaluate_exact(&tree("1 + 2")), Ok(Integer::Small(3)));
+        assert_eq!(exactly("2^63"), "9223372036854775808");
+        assert_eq!(exactly("2^64 - 1"), "18446744073709551615");
+        let big = evaluate_exact(&tree("2 ^ 100")).unwrap();
+        assert!(matches!(big, Integer::Big(_)));
+        assert_eq!(big.to_string(), "1267650600228229401496703205376");
+        assert_eq!(exactly("|-(2^70)|"), "1180591620717411303424");
+        assert_eq!(exactly("-(10^30) / 7"), "-142857142857142857142857142857");
+        assert_eq!(exactly("-(10^30) % 7"), "-1");
+        assert_eq!(exactly("(10^30 + 7) % 10^15"), "7");
+    }
+    #[test]
+    fn exact_results_shrink_back_when_they_fit() {
+        assert_eq!(
+            evaluate_exact(&tree("2 ^ 100 / 2 ^ 98")),
+            Ok(Integer::Small(4))
+        );
+        assert_eq!(
+            evaluate_exact(&tree("-(2^63)")),
+            Ok(Integer::Small(i64::MIN))
+        );
+        assert_eq!(
+            evaluate_exact(&tree("(3^50) * (3^50) == 3^100")),
+            Ok(Integer::Small(1))
+        );
+        assert_eq!(
+            evaluate_exact(&tree("(-1) ^ (2^70 + 1)")),
+            Ok(Integer::Small(-1))
+        );
+    }
+    #[test]
+    fn exact_evaluation_still_has_limits() {
+        assert_eq!(
+            evaluate_exact(&tree("2 ^ 100000")),
+            Err(CalcError::Overflow)
+        );
+        assert!(matches!(
+            evaluate_exact(&tree("2^70 / 0")),
+            Err(CalcError::DivisionByZero(_))
+        ));
+    }
+    #[test]
+    fn checked_overflow_promotes_past_i64() {
+        let plain = EvalOptions::default();
+        let wrapping = EvalOptions {
+            overflow: OverflowPolicy::Wrapping,
+            ..EvalOptions::default()
+        };
+        let width_32 = EvalOptions {
+            width: Some(32),
+            ..EvalOptions::default()
+        };
+        let big = |input| evaluate_opts(input, plain).map(|value| value.to_string());
+        assert_eq!(big("2 ^ 64"), Ok("18446744073709551616".to_string()));
+        assert_eq!(big("-(2 ^ 63) - 1"), Ok("-9223372036854775809".to_string()));
+        assert_eq!(evaluate_opts("2 ^ 64 / 2 ^ 60", plain), Ok(Value::Int(16)));
+        assert_eq!(
+            evaluate_opts("2 ^ 64 > 2 ^ 63 - 1", plain),
+            Ok(Value::Int(1))
+        );
+        assert_eq!(
+            evaluate_opts("2 ^ 64 * 0.5", plain),
+            Ok(Value::Float(9.223372036854776e18))
+        );
+        assert_eq!(evaluate_opts("2 ^ 64", wrapping), Ok(Value::Int(0)));
+        assert_eq!(evaluate_opts("2 ^ 64", width_32), Err(CalcError::Overflow));
+        let (_, outputs) = session(&["2 ^ 64", "ans - 1", ":base 16", "2 ^ 64"]);
+        assert_eq!(outputs[0], shown("18446744073709551616"));
+        assert_eq!(outputs[1], shown("18446744073709551615"));
+        assert_eq!(outputs[3], shown("0x10000000000000000"));
+    }
+    #[test]
+    fn big_exponents_keep_their_value() {
+        let plain = EvalOptions::default();
+        let mod_7 = EvalOptions {
+            modulus: Some(7),
+            ..EvalOptions::default()
+        };
+        let exponent = BigInt::from_i64(1 << 62).mul(&BigInt::from_i64(-256));
+        assert_eq!(
+            integer_binary(
+                '^',
+                Integer::Small(2),
+                Integer::Big(exponent.clone()),
+                Span::default()
+            ),
+            Err(CalcError::NegativeExponent(Integer::Big(exponent)))
+        );
+        assert_eq!(
+            evaluate_wide(&tree("2 ^ -(2 ^ 70)"))
+                .unwrap_err()
+                .to_string(),
+            "negative exponent: -1180591620717411303424"
+        );
+        assert_eq!(evaluate_opts("2 ^ -(2 ^ 70)", plain), Ok(Value::Float(0.0)));
+        assert_eq!(evaluate_opts("3 ^ (2 ^ 64)", mod_7), Ok(Value::Int(4)));
+        assert_eq!(evaluate_opts("3 ^ -(2 ^ 64)", mod_7), Ok(Value::Int(2)));
+    }
+    #[test]
+    fn sum_and_prod_fold_their_arguments() {
+        let plain = EvalOptions::default();
+        assert_eq!(evaluate_opts("sum(1, 2, 3)", plain), Ok(Value::Int(6)));
+        assert_eq!(evaluate_opts("prod(2, 3, 4)", plain), Ok(Value::Int(24)));
+        assert_eq!(evaluate_opts("sum(1, 0.5)", plain), Ok(Value::Float(1.5)));
+        assert_eq!(
+            evaluate_opts("prod(4294967296, 4294967296)", plain).map(|value| value.to_string()),
+            Ok("18446744073709551616".to_string())
+        );
+        assert_eq!(run("sum(4) + prod(5)"), Ok(9));
+    }
+    #[test]
+    fn empty_sum_and_prod_are_the_identities() {
+        let plain = EvalOptions::default();
+        assert_eq!(evaluate_opts("sum()", plain), Ok(Value::Int(0)));
+        assert_eq!(evaluate_opts("prod()", plain), Ok(Value::Int(1)));
+        let code = compile(&tree("sum(1, 2) * prod()"));
+        assert_eq!(run_vm(&code, &Env::new()), Ok(3));
+    }
+    #[test]
+    fn lenient_mode_reads_an_undefined_variable_as_zero() {
+        let options = EvalOptions {
+            undefined_as_zero: true,
+            ..EvalOptions::default()
+        };
+        let (value, warnings) =
+            evaluate_with_warnings(&tree("x + 5"), &Env::new(), &options, &Rng::seeded(1)).unwrap();
+        assert_eq!(value, Value::Int(5));
+        assert_eq!(warnings, strings(&["undefined variable 'x' treated as 0"]));
+        let (repl, outputs) = session(&[":undefined zero", "y * 2 + 1"]);
+        assert_eq!(outputs[1], shown("1"));
+        assert_eq!(
+            repl.warnings,
+            strings(&["undefined variable 'y' treated as 0"])
+        );
+    }
+    #[test]
+    fn by_default_an_undefined_variable_is_an_error() {
+        let undefined = Err(CalcError::UndefinedVariable("x".to_string()));
+        assert_eq!(evaluate(&tree("x + 5")), undefined);
+        assert_eq!(run_vm(&compile(&tree("x + 5")), &Env::new()), undefined);
+        let (_, outputs) = session(&[":undefined zero", ":undefined error", "y"]);
+        assert_eq!(
+            outputs[2],
+            ReplOutput::Error(CalcError::UndefinedVariable("y".to_string()))
+        );
+    }
+    #[test]
+    fn each_argument_is_evaluated_on_its_own_line() {
+        let args = strings(&["1+2", "3*4", "5-1"]);
+        assert_eq!(
+            input_source(None, &args),
+            InputSource::Expressions(args.clone())
+        );
+        assert_eq!(
+            eval_args(&args, DEFAULT_MAX_INPUT_LEN),
+            [
+                Ok("3".to_string()),
+                Ok("12".to_string()),
+                Ok("4".to_string())
+            ]
+        );
+    }
+    #[test]
+    fn a_failing_argument_does_not_stop_the_rest() {
+        let results = eval_args(&strings(&["x = 2", "1/0", "x * 3"]), DEFAULT_MAX_INPUT_LEN);
+        assert!(results[1].is_err());
+        assert_eq!(results[2], Ok("6".to_string()));
+    }
+    #[test]
+    fn misplaced_expression_arguments_are_a_usage_error() {
+        assert_eq!(
+            usage_error(&["1+2", "--csv"]),
+            "--csv can't follow an expression argument"
+        );
+        assert_eq!(
+            usage_error(&["1+2", "--explain", "3"]),
+            "--explain can't follow an expression argument"
+        );
+        assert_eq!(
+            usage_error(&["--json-ast", "1+2", "3*4"]),
+            "--json-ast takes exactly one expression"
+        );
+    }
+    #[test]
+    fn int_scientific_rounds_the_mantissa() {
+        assert_eq!(int_scientific(2_000_000_000, None, false), "2.0e9");
+        assert_eq!(
+            int_scientific(-2_147_483_648, None, false),
+            "-2.147483648e9"
+        );
+        assert_eq!(int_scientific(2_049_999_999, Some(2), false), "2.05e9");
+        assert_eq!(int_scientific(9_999_999, Some(1), false), "1.0e7");
+        assert_eq!(int_scientific(2_000_000_000, Some(2), true), "2e9");
+        assert_eq!(int_scientific(1_250_000, Some(0), false), "1e6");
+        assert_eq!(int_scientific(i64::MAX, Some(2), false), "9.22e18");
+    }
+    #[test]
+    fn intsci_switches_at_its_threshold() {
+        let (mut repl, outputs) = session(&[
+            ":intsci 1e9",
+            "2000000000",
+            "1000000000",
+            "999999999",
+            ":intsci off",
+            "2000000000",
+        ]);
+        assert_eq!(outputs[1], shown("2.0e9"));
+        assert_eq!(outputs[2], shown("1.0e9"));
+        assert_eq!(outputs[3], shown("999999999"));
+        assert_eq!(outputs[5], shown("2000000000"));
+        repl.feed_line(":intsci 1e9");
+        assert!(repl.config_text().contains("intsci 1000000000"));
+        assert!(matches!(
+            repl.feed_line(":intsci 0.5"),
+            ReplOutput::Error(_)
+        ));
+    }
+    #[test]
+    fn an_identity_rule_rewrites_to_a_fixpoint() {
+        let identity = Rule::parse("?a + 0", "?a").unwrap();
+        let rules = [identity];
+        assert_eq!(
+            apply_rules(tree("(x + 0) + 0 * y + 0"), &rules).to_string(),
+            "x + 0 * y"
+        );
+        assert!(eq_ignore_spans(
+            &apply_rules(tree("(x + 0) + 0"), &rules),
+            &tree("x")
+        ));
+        assert_eq!(apply_rules(tree("f(x + 0)"), &rules).to_string(), "f(x)");
+    }
+    #[test]
+    fn a_commutativity_rule_moves_constants_first() {
+        let commute = Rule::parse("?a * ?#n", "?#n * ?a").unwrap();
+        assert_eq!(
+            apply_rules(tree("x * 2 + (y * 3) * 4"), &[commute]).to_string(),
+            "2 * x + 4 * (3 * y)"
+        );
+        let same = Rule::parse("?a - ?a", "0").unwrap();
+        assert_eq!(
+            apply_rules(tree("(x*2) - (x*2) + (x - y)"), &[same]).to_string(),
+            "0 + (x - y)"
+        );
+    }
+    #[test]
+    fn rules_stop_at_the_iteration_cap() {
+        let swap = Rule::parse("?a + ?b", "?b + ?a").unwrap();
+        let rewritten = apply_rules(tree("x + y"), &[swap]);
+        assert!(matches!(rewritten.to_string().as_str(), "x + y" | "y + x"));
+        assert_eq!(
+            Rule::parse("?a", "?b").unwrap_err(),
+            CalcError::UnboundWildcard("?b".to_string())
+        );
+    }
+    #[test]
+    fn latin1_bytes_decode_before_lexing() {
+        assert_eq!(run_bytes(b"6 \xd7 7", Encoding::Latin1), Ok(42));
+        assert_eq!(run_bytes(b"84 \xf7 2", Encoding::Latin1), Ok(42));
+        assert_eq!(run_bytes("6 × 7".as_bytes(), Encoding::Utf8), Ok(42));
+        assert_eq!(run_bytes(b"1+2", Encoding::Ascii), Ok(3));
+    }
+    #[test]
+    fn bytes_invalid_for_the_encoding_are_an_error() {
+        assert_eq!(
+            run_bytes(b"6 \xd7 7", Encoding::Utf8),
+            Err(CalcError::InvalidEncoding {
+                encoding: Encoding::Utf8,
+                offset: 2,
+            })
+        );
+        assert_eq!(
+            run_bytes(b"6 \xd7 7", Encoding::Ascii)
+                .unwrap_err()
+                .to_string(),
+            "byte 2 is not valid ASCII"
+        );
+    }
 }
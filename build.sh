@@ -36,7 +36,7 @@ declare -A commands=(
     [hs]='ghc -O2 ast.hs -o ast_hs'
     [java]='javac ast.java'
     [ml]='ocamlopt -O3 ast.ml -o ast_ml'
-    [rs]='rustc -C opt-level=3 ast.rs -o ast_rs'
+    [rs]='rustc -C opt-level=3 ast.rs -o ast_rs && rustc --cfg feature=\"no_std\" --crate-type lib --emit=metadata ast.rs -o ast_rs_core.rmeta'
     [zig]='zig build-exe -O ReleaseFast ast.zig -femit-bin=ast_zig'
 )
 